@@ -0,0 +1,260 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::{BufReader, Read, Seek},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use osmpbfreader::{OsmId, OsmObj};
+use rstar::{
+    primitives::{GeomWithData, Rectangle},
+    RTree, AABB,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{memory, pbf};
+
+/// A `boundary=administrative` relation containing the query point.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AdminMatch {
+    pub osm_id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_level: Option<u8>,
+    /// Values of the configured index tags present on the relation.
+    pub tags: BTreeMap<String, String>,
+}
+
+/// An administrative boundary relation assembled into closed rings.
+#[derive(Clone, Serialize, Deserialize)]
+struct Boundary {
+    relation: AdminMatch,
+    /// Outer and inner rings alike, as `[lat, lon]` points; containment uses
+    /// the even-odd rule so holes fall out naturally.
+    rings: Vec<Vec<[f64; 2]>>,
+}
+
+impl Boundary {
+    fn contains(&self, point: [f64; 2]) -> bool {
+        self.rings
+            .iter()
+            .filter(|ring| ring_contains(ring, point))
+            .count()
+            % 2
+            == 1
+    }
+}
+
+/// Ray casting along the latitude axis.
+fn ring_contains(ring: &[[f64; 2]], [lat, lon]: [f64; 2]) -> bool {
+    let mut inside = false;
+    for edge in ring.windows(2) {
+        let ([lat1, lon1], [lat2, lon2]) = (edge[0], edge[1]);
+        if (lat1 > lat) != (lat2 > lat) && lon < lon1 + (lat - lat1) * (lon2 - lon1) / (lat2 - lat1)
+        {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// Joins member ways end to end into closed rings, by shared node ids.
+/// Members that never close up are dropped.
+fn assemble_rings(mut open: Vec<Vec<(i64, [f64; 2])>>) -> Vec<Vec<[f64; 2]>> {
+    let mut rings = Vec::new();
+    while let Some(mut ring) = open.pop() {
+        while let (Some(&(first, _)), Some(&(last, _))) = (ring.first(), ring.last()) {
+            if ring.len() >= 4 && first == last {
+                rings.push(ring.into_iter().map(|(_, point)| point).collect());
+                break;
+            }
+            let starts_at = |way: &Vec<(i64, [f64; 2])>, id| way.first().map(|n| n.0) == Some(id);
+            let Some(next) = open
+                .iter()
+                .position(|way| starts_at(way, last) || way.last().map(|n| n.0) == Some(last))
+            else {
+                break;
+            };
+            let mut next = open.swap_remove(next);
+            if !starts_at(&next, last) {
+                next.reverse();
+            }
+            ring.extend(next.into_iter().skip(1));
+        }
+    }
+    rings
+}
+
+/// The boundary relations of a pbf along with the ways and nodes they use.
+fn boundary_objs(reader: impl Read + Seek) -> Result<BTreeMap<OsmId, OsmObj>, String> {
+    osmpbfreader::OsmPbfReader::new(reader)
+        .get_objs_and_deps(|obj| {
+            obj.is_relation() && obj.tags().contains("boundary", "administrative")
+        })
+        .map_err(|e| e.to_string())
+}
+
+/// [`boundary_objs`] of a gzipped pbf. Collecting dependencies seeks back
+/// through the file, which a decompressor can't, so it is inflated to a
+/// temporary file first, as the whole of a large one wouldn't fit in memory.
+fn inflated_boundary_objs(mut reader: impl Read) -> Result<BTreeMap<OsmId, OsmObj>, String> {
+    static INFLATED: AtomicU64 = AtomicU64::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "fast-pbf-server-{}-{}.boundaries.pbf",
+        std::process::id(),
+        INFLATED.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut inflate = || -> std::io::Result<std::fs::File> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        std::io::copy(&mut reader, &mut file)?;
+        file.rewind()?;
+        Ok(file)
+    };
+    let objs = inflate()
+        .map_err(|e| format!("cannot inflate to {}: {}", path.display(), e))
+        .and_then(|file| boundary_objs(BufReader::new(file)));
+    let _ = std::fs::remove_file(&path);
+    objs
+}
+
+/// Boundary polygons with an R-tree over their bounding boxes as a prefilter.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct AdminIndex {
+    boundaries: Vec<Boundary>,
+    tree: RTree<GeomWithData<Rectangle<[f64; 2]>, usize>>,
+}
+
+impl AdminIndex {
+    /// Rough heap footprint, see [`memory`].
+    pub fn heap_bytes(&self) -> usize {
+        let boundaries: usize = self
+            .boundaries
+            .iter()
+            .map(|boundary| {
+                let relation = &boundary.relation;
+                memory::tags(&relation.tags)
+                    + relation.name.as_ref().map_or(0, String::capacity)
+                    + memory::vec(&boundary.rings)
+                    + boundary.rings.iter().map(memory::vec).sum::<usize>()
+            })
+            .sum();
+        memory::vec(&self.boundaries) + boundaries + memory::rtree(&self.tree)
+    }
+
+    /// Adds the `boundary=administrative` relations of `path`, skipping ones
+    /// already indexed from an earlier file.
+    pub fn build(&mut self, path: &str, index_tags: &[String]) {
+        let start = std::time::Instant::now();
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                tracing::warn!("cannot read boundaries from {}: {}", path, e);
+                return;
+            }
+        };
+        let objs = match pbf::Input::new(file) {
+            Ok(pbf::Input::Plain(reader)) => boundary_objs(reader),
+            Ok(pbf::Input::Gzip(reader)) => inflated_boundary_objs(reader),
+            Err(e) => Err(e.to_string()),
+        };
+        let objs = match objs {
+            Ok(objs) => objs,
+            Err(e) => {
+                tracing::warn!("cannot read boundaries from {}: {}", path, e);
+                return;
+            }
+        };
+
+        let mut seen: HashSet<i64> = self
+            .boundaries
+            .iter()
+            .map(|boundary| boundary.relation.osm_id)
+            .collect();
+        let mut boundaries = std::mem::take(&mut self.boundaries);
+        for obj in objs.values() {
+            let OsmObj::Relation(relation) = obj else {
+                continue;
+            };
+            if !relation.tags.contains("boundary", "administrative") || !seen.insert(relation.id.0)
+            {
+                continue;
+            }
+            let members = relation
+                .refs
+                .iter()
+                .filter(|member| matches!(member.role.as_str(), "outer" | "inner" | ""))
+                .filter_map(|member| match member.member {
+                    OsmId::Way(id) => objs.get(&OsmId::Way(id))?.way(),
+                    _ => None,
+                })
+                .map(|way| {
+                    way.nodes
+                        .iter()
+                        .filter_map(|id| {
+                            let node = objs.get(&OsmId::Node(*id))?.node()?;
+                            Some((id.0, [node.lat(), node.lon()]))
+                        })
+                        .collect()
+                })
+                .collect();
+            let rings = assemble_rings(members);
+            if rings.is_empty() {
+                continue;
+            }
+            boundaries.push(Boundary {
+                relation: AdminMatch {
+                    osm_id: relation.id.0,
+                    name: relation.tags.get("name").map(|v| v.to_string()),
+                    admin_level: relation
+                        .tags
+                        .get("admin_level")
+                        .and_then(|v| v.parse().ok()),
+                    tags: index_tags
+                        .iter()
+                        .filter_map(|key| {
+                            let value = relation.tags.get(key.as_str())?;
+                            Some((key.clone(), value.to_string()))
+                        })
+                        .collect(),
+                },
+                rings,
+            });
+        }
+
+        let envelopes = boundaries
+            .iter()
+            .enumerate()
+            .map(|(i, boundary)| {
+                let envelope = AABB::from_points(boundary.rings.iter().flatten());
+                GeomWithData::new(Rectangle::from_aabb(envelope), i)
+            })
+            .collect();
+        self.boundaries = boundaries;
+        self.tree = RTree::bulk_load(envelopes);
+        tracing::info!(
+            "Loaded {} admin boundaries from {} in {}ms",
+            self.boundaries.len(),
+            path,
+            start.elapsed().as_millis()
+        );
+    }
+
+    /// Boundaries containing the point, from the lowest admin level
+    /// (country) to the highest.
+    pub fn locate(&self, lat: f64, lon: f64) -> Vec<AdminMatch> {
+        let point = [lat, lon];
+        let mut found: Vec<AdminMatch> = self
+            .tree
+            .locate_all_at_point(&point)
+            .map(|candidate| &self.boundaries[candidate.data])
+            .filter(|boundary| boundary.contains(point))
+            .map(|boundary| boundary.relation.clone())
+            .collect();
+        found.sort_by_key(|found| (found.admin_level.unwrap_or(u8::MAX), found.osm_id));
+        found
+    }
+}
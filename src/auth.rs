@@ -0,0 +1,80 @@
+use std::sync::Arc;
+
+use poem::{
+    http::{header, StatusCode},
+    web::Json,
+    Endpoint, IntoResponse, Middleware, Request, Response, Result,
+};
+
+/// Shared secret clients must present; `None` disables authentication.
+#[derive(Clone, Default)]
+pub struct ApiKey(Option<Arc<str>>);
+
+impl ApiKey {
+    pub fn new(key: Option<String>) -> Self {
+        ApiKey(key.map(Arc::from))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Always true when no key is configured.
+    pub fn accepts(&self, presented: Option<&str>) -> bool {
+        match (&self.0, presented) {
+            (None, _) => true,
+            (Some(key), Some(presented)) => constant_time_eq(key.as_bytes(), presented.as_bytes()),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Checks the `Authorization: Bearer <key>` header.
+    pub fn accepts_request(&self, req: &Request) -> bool {
+        let bearer = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        self.accepts(bearer)
+    }
+}
+
+/// Doesn't bail out on the first differing byte, so response timing doesn't
+/// leak how much of the key a guess got right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl<E: Endpoint> Middleware<E> for ApiKey {
+    type Output = ApiKeyEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        ApiKeyEndpoint {
+            inner: ep,
+            key: self.clone(),
+        }
+    }
+}
+
+/// Answers 401 unless the request carries the configured key.
+pub struct ApiKeyEndpoint<E> {
+    inner: E,
+    key: ApiKey,
+}
+
+#[poem::async_trait]
+impl<E: Endpoint> Endpoint for ApiKeyEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        if !self.key.accepts_request(&req) {
+            return Ok(
+                Json(serde_json::json!({ "success": false, "error": "Unauthorized" }))
+                    .with_status(StatusCode::UNAUTHORIZED)
+                    .with_header(header::WWW_AUTHENTICATE, "Bearer")
+                    .into_response(),
+            );
+        }
+        self.inner.call(req).await.map(IntoResponse::into_response)
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    time::UNIX_EPOCH,
+};
+
+use flate2::{bufread::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+
+use crate::geo::GeoIndex;
+
+const MAGIC: [u8; 8] = *b"FPBFIDX\0";
+/// Bump whenever the serialized layout of `GeoIndex` changes, or the
+/// segments it is built from do.
+const FORMAT_VERSION: u32 = 16;
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum CacheCompression {
+    None,
+    Gzip,
+}
+
+/// Written uncompressed after `MAGIC` and `FORMAT_VERSION`, in front of the
+/// index, so incompatible caches are rejected before we try to decode them.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    crate_version: String,
+    sources: Vec<SourceInfo>,
+}
+
+/// Identifies a PBF a cache was built from, so edits to it invalidate the cache.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct SourceInfo {
+    path: String,
+    size: u64,
+    modified_unix_nanos: u128,
+}
+
+impl SourceInfo {
+    /// A downloaded pbf is identified by its URL alone; rebuild with
+    /// `--force-rebuild` to pick up a newer remote file.
+    fn of(pbf: &str) -> std::io::Result<SourceInfo> {
+        if crate::fetch::is_url(pbf) {
+            return Ok(SourceInfo {
+                path: pbf.to_string(),
+                size: 0,
+                modified_unix_nanos: 0,
+            });
+        }
+        let metadata = std::fs::metadata(pbf)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(SourceInfo {
+            path: std::fs::canonicalize(pbf)?.to_string_lossy().into_owned(),
+            size: metadata.len(),
+            modified_unix_nanos: modified.as_nanos(),
+        })
+    }
+}
+
+fn mismatch(reason: String) -> bincode::Error {
+    Box::new(bincode::ErrorKind::Custom(reason))
+}
+
+impl Header {
+    fn current(pbfs: &[String]) -> bincode::Result<Header> {
+        Ok(Header {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            sources: pbfs
+                .iter()
+                .map(|pbf| SourceInfo::of(pbf))
+                .collect::<std::io::Result<_>>()?,
+        })
+    }
+
+    fn check(&self, pbfs: &[String]) -> bincode::Result<()> {
+        let current = Header::current(pbfs)?;
+        let paths = |sources: &[SourceInfo]| -> Vec<String> {
+            sources.iter().map(|source| source.path.clone()).collect()
+        };
+        let reason = if self.crate_version != current.crate_version {
+            format!(
+                "cache written by version {} but this is {}",
+                self.crate_version, current.crate_version
+            )
+        } else if paths(&self.sources) != paths(&current.sources) {
+            format!(
+                "cache was built from {:?} but {:?} were given",
+                paths(&self.sources),
+                paths(&current.sources)
+            )
+        } else if let Some(changed) = self
+            .sources
+            .iter()
+            .zip(&current.sources)
+            .find(|(cached, current)| cached != current)
+        {
+            format!(
+                "cache was built from {} which has changed since",
+                changed.0.path
+            )
+        } else {
+            return Ok(());
+        };
+        Err(mismatch(reason))
+    }
+}
+
+/// Reads a cached index built from `pbfs`, transparently decompressing gzip
+/// payloads. Fails if the cache is incompatible or any of `pbfs` changed since.
+pub fn load(file: File, pbfs: &[String]) -> bincode::Result<GeoIndex> {
+    let mut reader = BufReader::new(file);
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(mismatch("not an index cache file".to_string()));
+    }
+    let format_version: u32 = bincode::deserialize_from(&mut reader)?;
+    if format_version != FORMAT_VERSION {
+        return Err(mismatch(format!(
+            "cache format version {} but this build expects {}",
+            format_version, FORMAT_VERSION
+        )));
+    }
+    let header: Header = bincode::deserialize_from(&mut reader)?;
+    header.check(pbfs)?;
+
+    let compressed = reader.fill_buf()?.starts_with(&GZIP_MAGIC);
+    let reader: Box<dyn Read> = if compressed {
+        Box::new(GzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    };
+    bincode::deserialize_from(reader)
+}
+
+pub fn save(
+    path: &str,
+    pbfs: &[String],
+    geo: &GeoIndex,
+    compression: CacheCompression,
+) -> bincode::Result<()> {
+    let header = Header::current(pbfs)?;
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&MAGIC)?;
+    bincode::serialize_into(&mut writer, &FORMAT_VERSION)?;
+    bincode::serialize_into(&mut writer, &header)?;
+    match compression {
+        CacheCompression::None => {
+            bincode::serialize_into(&mut writer, geo)?;
+            writer.flush()?;
+        }
+        CacheCompression::Gzip => {
+            let mut encoder = GzEncoder::new(writer, Compression::fast());
+            bincode::serialize_into(&mut encoder, geo)?;
+            encoder.finish()?.flush()?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,96 @@
+use std::io::Write;
+
+use flate2::{write::GzEncoder, Compression};
+use poem::{
+    http::{header, HeaderValue},
+    Body, Endpoint, IntoResponse, Middleware, Request, Response, Result,
+};
+
+/// Gzips responses to clients that accept it, once they reach `min_bytes`;
+/// smaller ones would gain less than the encoding costs.
+#[derive(Clone, Copy, Debug)]
+pub struct Gzip {
+    min_bytes: usize,
+}
+
+impl Gzip {
+    pub fn new(min_bytes: usize) -> Self {
+        Gzip { min_bytes }
+    }
+}
+
+/// Whether `Accept-Encoding` lists gzip (or `*`) without `q=0`.
+fn accepts_gzip(req: &Request) -> bool {
+    let Some(accept) = req
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    accept.split(',').any(|coding| {
+        let mut params = coding.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        let refused = params.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+    })
+}
+
+fn gzip(body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+impl<E: Endpoint> Middleware<E> for Gzip {
+    type Output = GzipEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        GzipEndpoint {
+            inner: ep,
+            gzip: *self,
+        }
+    }
+}
+
+pub struct GzipEndpoint<E> {
+    inner: E,
+    gzip: Gzip,
+}
+
+#[poem::async_trait]
+impl<E: Endpoint> Endpoint for GzipEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        let accepts = accepts_gzip(&req);
+        let mut resp = self.inner.call(req).await?.into_response();
+        resp.headers_mut()
+            .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        if !accepts || resp.headers().contains_key(header::CONTENT_ENCODING) {
+            return Ok(resp);
+        }
+
+        let body = resp.take_body().into_vec().await?;
+        if body.len() < self.gzip.min_bytes {
+            resp.set_body(body);
+            return Ok(resp);
+        }
+        // Encoding into memory can't fail in practice; if it does, the body
+        // is simply sent as it is.
+        let Ok(compressed) = gzip(&body) else {
+            resp.set_body(body);
+            return Ok(resp);
+        };
+        resp.headers_mut().remove(header::CONTENT_LENGTH);
+        resp.headers_mut()
+            .insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        resp.set_body(Body::from(compressed));
+        Ok(resp)
+    }
+}
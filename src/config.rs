@@ -0,0 +1,88 @@
+use std::ffi::OsString;
+
+use clap::{parser::ValueSource, ArgAction, ArgMatches, Command, Parser};
+use toml_edit::{Document, Value};
+
+/// Parses `T` from the command line, filling in options missing there from
+/// the TOML file named by `--config`. Keys are the option names with
+/// underscores (`max_results = 500`), so the precedence is: command
+/// line, then config file, then environment, then defaults.
+pub fn parse<T: Parser>() -> T {
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    // First pass only to find `--config` and what the command line already
+    // sets; required options may well come from the file.
+    let matches = T::command().ignore_errors(true).get_matches_from(&argv);
+    let Some(path) = matches.get_one::<String>("config") else {
+        return T::parse_from(argv);
+    };
+
+    let from_file = match file_args(&T::command(), &matches, path) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("error: cannot use config {}: {}", path, e);
+            std::process::exit(2);
+        }
+    };
+    // Right after the binary name, so they land before any subcommand.
+    let mut args = vec![argv[0].clone()];
+    args.extend(from_file);
+    args.extend(argv.into_iter().skip(1));
+    T::parse_from(args)
+}
+
+/// Turns the config file into `--key=value` arguments, skipping options the
+/// command line already sets.
+fn file_args(command: &Command, matches: &ArgMatches, path: &str) -> Result<Vec<OsString>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let document: Document = text
+        .parse()
+        .map_err(|e: toml_edit::TomlError| e.to_string())?;
+
+    let mut args = Vec::new();
+    for (key, item) in document.iter() {
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == key && arg.get_long().is_some() && key != "config")
+            .ok_or_else(|| format!("unknown option `{}`", key))?;
+        if matches.value_source(key) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let long = arg.get_long().unwrap_or(key);
+        let value = item
+            .as_value()
+            .ok_or_else(|| format!("`{}` must be a value, not a table", key))?;
+
+        if matches!(arg.get_action(), ArgAction::SetTrue) {
+            match value.as_bool() {
+                Some(true) => args.push(format!("--{}", long).into()),
+                Some(false) => {}
+                None => return Err(format!("`{}` must be true or false", key)),
+            }
+            continue;
+        }
+        let value = match value {
+            Value::Array(values) => values
+                .iter()
+                .map(|value| scalar(key, value))
+                .collect::<Result<Vec<_>, _>>()?
+                .join(","),
+            value => scalar(key, value)?,
+        };
+        args.push(format!("--{}={}", long, value).into());
+    }
+    Ok(args)
+}
+
+fn scalar(key: &str, value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.value().clone()),
+        Value::Integer(i) => Ok(i.value().to_string()),
+        Value::Float(f) => Ok(f.value().to_string()),
+        Value::Boolean(b) => Ok(b.value().to_string()),
+        other => Err(format!(
+            "unsupported {} value for `{}`",
+            other.type_name(),
+            key
+        )),
+    }
+}
@@ -0,0 +1,137 @@
+use serde::{de, Deserializer};
+
+/// Which coordinate a value is, deciding the hemisphere letters it may carry.
+#[derive(Clone, Copy, Debug)]
+pub enum Axis {
+    Latitude,
+    Longitude,
+}
+
+impl Axis {
+    fn name(self) -> &'static str {
+        match self {
+            Axis::Latitude => "latitude",
+            Axis::Longitude => "longitude",
+        }
+    }
+
+    /// `Some(true)` for the hemisphere that makes the value negative.
+    fn is_negative(self, hemisphere: char) -> Option<bool> {
+        match (self, hemisphere) {
+            (Axis::Latitude, 'N') | (Axis::Longitude, 'E') => Some(false),
+            (Axis::Latitude, 'S') | (Axis::Longitude, 'W') => Some(true),
+            _ => None,
+        }
+    }
+}
+
+/// Marks between degrees, minutes and seconds; which one is used doesn't
+/// matter, only the order of the numbers does.
+const SEPARATORS: [char; 10] = ['°', 'º', '˚', '\'', '′', '’', '"', '″', '”', ':'];
+
+/// Reads decimal degrees (`40.7484`) or degrees with minutes and optionally
+/// seconds, e.g. `40°44'54.36"N`, `N 40 44 54.36`, `-73:59:8.36` or
+/// `40° 44.906′ N`. A hemisphere letter may lead or trail instead of a sign;
+/// S and W are negative. Only the last number may have decimals, and
+/// minutes and seconds must be below 60.
+pub fn parse(text: &str, axis: Axis) -> Result<f64, String> {
+    let fail = |why: &str| format!("cannot read {:?} as a {}: {}", text, axis.name(), why);
+    let mut rest = text.trim();
+    if let Ok(degrees) = rest.parse::<f64>() {
+        return Ok(degrees);
+    }
+
+    let mut hemisphere = None;
+    let is_letter = |c: &char| "NSEWnsew".contains(*c);
+    if let Some(first) = rest.chars().next().filter(is_letter) {
+        hemisphere = Some(first.to_ascii_uppercase());
+        rest = &rest[first.len_utf8()..];
+    } else if let Some(last) = rest.chars().next_back().filter(is_letter) {
+        hemisphere = Some(last.to_ascii_uppercase());
+        rest = &rest[..rest.len() - last.len_utf8()];
+    }
+    let mut rest = rest.trim();
+    let mut negative = false;
+    if let Some(unsigned) = rest.strip_prefix('-') {
+        negative = true;
+        rest = unsigned;
+    } else if let Some(unsigned) = rest.strip_prefix('+') {
+        rest = unsigned;
+    }
+    if let Some(hemisphere) = hemisphere {
+        if negative {
+            return Err(fail("give either a sign or a hemisphere, not both"));
+        }
+        negative = axis
+            .is_negative(hemisphere)
+            .ok_or_else(|| fail(&format!("hemisphere {} doesn't apply", hemisphere)))?;
+    }
+
+    let parts: Vec<&str> = rest
+        .split(|c: char| c.is_whitespace() || SEPARATORS.contains(&c))
+        .filter(|part| !part.is_empty())
+        .collect();
+    if parts.is_empty() || parts.len() > 3 {
+        return Err(fail(
+            "expected decimal degrees or degrees, minutes and seconds like 40°44'54.36\"N",
+        ));
+    }
+    let mut degrees = 0.0;
+    for (i, part) in parts.iter().enumerate() {
+        let value = part
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == '.')
+            .then(|| part.parse::<f64>().ok())
+            .flatten()
+            .ok_or_else(|| fail(&format!("{:?} is not a number", part)))?;
+        if i + 1 < parts.len() && value.fract() != 0.0 {
+            return Err(fail("only the last number may have decimals"));
+        }
+        if i > 0 && value >= 60.0 {
+            return Err(fail("minutes and seconds must be below 60"));
+        }
+        degrees += value / 60f64.powi(i as i32);
+    }
+    Ok(if negative { -degrees } else { degrees })
+}
+
+struct Visitor(Axis);
+
+impl<'de> de::Visitor<'de> for Visitor {
+    type Value = f64;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "a {} in decimal degrees or as degrees, minutes and seconds",
+            self.0.name()
+        )
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<f64, E> {
+        Ok(value)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<f64, E> {
+        Ok(value as f64)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<f64, E> {
+        Ok(value as f64)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<f64, E> {
+        parse(value, self.0).map_err(E::custom)
+    }
+}
+
+/// For `#[serde(deserialize_with)]`: a latitude as a number or a string
+/// [`parse`] accepts.
+pub fn latitude<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    deserializer.deserialize_any(Visitor(Axis::Latitude))
+}
+
+/// Like [`latitude`], for longitudes.
+pub fn longitude<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    deserializer.deserialize_any(Visitor(Axis::Longitude))
+}
@@ -0,0 +1,110 @@
+use std::{collections::BTreeMap, io::Write};
+
+use serde::Serialize;
+
+use crate::geo::{Feature, GeoIndex, OsmType};
+
+/// Output of the `export` subcommand.
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Format {
+    /// One row per feature, with a column for each `--index-tags` key
+    Csv,
+    /// One JSON object per line, with every tag the index keeps
+    Jsonl,
+}
+
+#[derive(Serialize)]
+struct Row<'a> {
+    osm_type: OsmType,
+    osm_id: i64,
+    /// The feature's first located node; absent if none could be resolved.
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    tags: &'a BTreeMap<String, String>,
+    /// `tags` together with the `--store-tags`, if any are stored.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    all_tags: Option<BTreeMap<&'a str, &'a str>>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    wikipedia_langs: &'a BTreeMap<String, String>,
+}
+
+impl<'a> Row<'a> {
+    fn new(feature: &'a Feature, stores_tags: bool) -> Self {
+        Row {
+            osm_type: feature.osm_type,
+            osm_id: feature.osm_id,
+            latitude: feature.location.map(|[lat, _]| lat),
+            longitude: feature.location.map(|[_, lon]| lon),
+            name: feature.name.as_deref(),
+            tags: &feature.tags,
+            all_tags: stores_tags.then(|| {
+                feature
+                    .tags
+                    .iter()
+                    .chain(&feature.extra_tags)
+                    .map(|(k, v)| (k.as_str(), v.as_str()))
+                    .collect()
+            }),
+            wikipedia_langs: &feature.wikipedia_langs,
+        }
+    }
+}
+
+/// Quotes a CSV field if it needs it, doubling any quotes inside.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Writes every indexed feature to `out`, in the order they were indexed.
+pub fn write(geo: &GeoIndex, format: Format, out: &mut impl Write) -> std::io::Result<()> {
+    match format {
+        Format::Csv => {
+            let mut header = vec!["osm_type", "osm_id", "latitude", "longitude", "name"];
+            // `name` already has its column.
+            let tag_keys: Vec<&str> = geo
+                .index_tags()
+                .iter()
+                .map(String::as_str)
+                .filter(|key| !header.contains(key))
+                .collect();
+            header.extend(&tag_keys);
+            let header: Vec<String> = header.into_iter().map(csv_field).collect();
+            writeln!(out, "{}", header.join(","))?;
+            for feature in geo.features() {
+                let mut fields = vec![
+                    feature.osm_type.as_str().to_string(),
+                    feature.osm_id.to_string(),
+                    feature
+                        .location
+                        .map_or_else(String::new, |[lat, _]| lat.to_string()),
+                    feature
+                        .location
+                        .map_or_else(String::new, |[_, lon]| lon.to_string()),
+                    feature.name.as_deref().map(csv_field).unwrap_or_default(),
+                ];
+                fields.extend(tag_keys.iter().map(|&key| {
+                    feature
+                        .tags
+                        .get(key)
+                        .map(|value| csv_field(value))
+                        .unwrap_or_default()
+                }));
+                writeln!(out, "{}", fields.join(","))?;
+            }
+        }
+        Format::Jsonl => {
+            let stores_tags = !geo.stored_tags().is_empty();
+            for feature in geo.features() {
+                serde_json::to_writer(&mut *out, &Row::new(feature, stores_tags))?;
+                writeln!(out)?;
+            }
+        }
+    }
+    out.flush()
+}
@@ -0,0 +1,140 @@
+use std::{
+    io::Write,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use hyper::{body::HttpBody, header::LOCATION, Client, Uri};
+
+const MAX_REDIRECTS: usize = 5;
+
+/// Whether a `--pbf` value names a remote file rather than a local path.
+pub fn is_url(pbf: &str) -> bool {
+    pbf.starts_with("http://") || pbf.starts_with("https://")
+}
+
+/// A pbf on the local filesystem; downloaded ones are deleted on drop.
+pub struct Local {
+    path: String,
+    temporary: bool,
+}
+
+impl Local {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for Local {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Makes `pbf` available locally, downloading it to a temporary file first
+/// if it is a URL.
+pub fn localize(pbf: &str) -> Result<Local, String> {
+    if !is_url(pbf) {
+        return Ok(Local {
+            path: pbf.to_string(),
+            temporary: false,
+        });
+    }
+    static DOWNLOADS: AtomicU64 = AtomicU64::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "fast-pbf-server-{}-{}.pbf",
+        std::process::id(),
+        DOWNLOADS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let local = Local {
+        path: path.to_string_lossy().into_owned(),
+        temporary: true,
+    };
+    download(pbf, path).map_err(|e| format!("cannot download {}: {}", pbf, e))?;
+    Ok(local)
+}
+
+/// Streams `url` into `path`. Runs its own runtime on a separate thread so it
+/// can be called from synchronous code, inside the server's runtime or not.
+fn download(url: &str, path: PathBuf) -> Result<(), String> {
+    if url.starts_with("https://") {
+        return Err(
+            "https is not supported by this build, use http or fetch the file first".into(),
+        );
+    }
+    let mut uri: Uri = url.parse().map_err(|e| format!("invalid URL: {}", e))?;
+    std::thread::scope(|scope| {
+        scope
+            .spawn(move || {
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .map_err(|e| e.to_string())?;
+                runtime.block_on(async {
+                    let start = Instant::now();
+                    let client = Client::new();
+                    let mut response = client.get(uri.clone()).await.map_err(|e| e.to_string())?;
+                    for _ in 0..MAX_REDIRECTS {
+                        if !response.status().is_redirection() {
+                            break;
+                        }
+                        let location = response
+                            .headers()
+                            .get(LOCATION)
+                            .and_then(|location| location.to_str().ok())
+                            .ok_or("redirect without a Location")?;
+                        uri = resolve(&uri, location)?;
+                        if uri.scheme_str() == Some("https") {
+                            return Err(format!(
+                                "redirected to {}, but https is not supported",
+                                uri
+                            ));
+                        }
+                        response = client.get(uri.clone()).await.map_err(|e| e.to_string())?;
+                    }
+                    if !response.status().is_success() {
+                        return Err(format!("server answered {}", response.status()));
+                    }
+
+                    tracing::info!("Downloading {} to {}", uri, path.display());
+                    let mut file = std::io::BufWriter::new(
+                        std::fs::File::create(&path).map_err(|e| e.to_string())?,
+                    );
+                    let mut bytes = 0;
+                    let body = response.body_mut();
+                    while let Some(chunk) = body.data().await {
+                        let chunk = chunk.map_err(|e| e.to_string())?;
+                        bytes += chunk.len();
+                        file.write_all(&chunk).map_err(|e| e.to_string())?;
+                    }
+                    file.flush().map_err(|e| e.to_string())?;
+                    tracing::info!(
+                        "Downloaded {} bytes from {} in {}ms",
+                        bytes,
+                        uri,
+                        start.elapsed().as_millis()
+                    );
+                    Ok(())
+                })
+            })
+            .join()
+            .map_err(|_| "download thread panicked".to_string())?
+    })
+}
+
+/// Resolves a `Location` header, which may be relative, against `base`.
+fn resolve(base: &Uri, location: &str) -> Result<Uri, String> {
+    let uri: Uri = location
+        .parse()
+        .map_err(|e| format!("invalid redirect {}: {}", location, e))?;
+    if uri.scheme().is_some() {
+        return Ok(uri);
+    }
+    let mut parts = uri.into_parts();
+    parts.scheme = base.scheme().cloned();
+    parts.authority = base.authority().cloned();
+    Uri::from_parts(parts).map_err(|e| format!("invalid redirect {}: {}", location, e))
+}
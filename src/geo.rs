@@ -1,101 +1,2269 @@
-use std::{collections::HashMap, time::Instant};
+use std::{
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
+    io::Read,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::Instant,
+};
 
-use osmpbfreader::OsmObj;
+use osmpbfreader::{OsmId, OsmObj};
+use par_map::ParMap;
 use rstar::{
     primitives::{GeomWithData, Line},
-    RTree,
+    PointDistance, AABB,
 };
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+use crate::{
+    admin::{AdminIndex, AdminMatch},
+    memory, osc, pbf,
+    progress::{BuildEvent, BuildObserver, LogProgress},
+    spatial::{self, Backend, Segment},
+};
+
+const EARTH_RADIUS_M: f64 = 6_371_008.8;
+
+/// Great-circle distance in meters between two `[lat, lon]` points.
+pub fn haversine_m(a: [f64; 2], b: [f64; 2]) -> f64 {
+    let (lat1, lon1) = (a[0].to_radians(), a[1].to_radians());
+    let (lat2, lon2) = (b[0].to_radians(), b[1].to_radians());
+    let h = ((lat2 - lat1) / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * h.sqrt().asin()
+}
+
+/// Distance in meters on a plane through the two points' mean latitude.
+/// Cheaper than [`haversine_m`] and within 0.01% of it up to about 100km,
+/// but the error grows with distance and latitude (about 0.1% at 500km).
+pub fn equirect_m(a: [f64; 2], b: [f64; 2]) -> f64 {
+    let mut dlon = b[1] - a[1];
+    dlon -= 360.0 * (dlon / 360.0).round();
+    let x = dlon.to_radians() * ((a[0] + b[0]) / 2.0).to_radians().cos();
+    let y = (b[0] - a[0]).to_radians();
+    EARTH_RADIUS_M * x.hypot(y)
+}
+
+/// How match distances are computed.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Metric {
+    /// Great-circle distance, accurate at any range
+    #[default]
+    Haversine,
+    /// Flat approximation; faster, and close enough at city scales
+    Equirect,
+}
+
+impl Metric {
+    pub fn distance_m(self, a: [f64; 2], b: [f64; 2]) -> f64 {
+        match self {
+            Metric::Haversine => haversine_m(a, b),
+            Metric::Equirect => equirect_m(a, b),
+        }
+    }
+}
+
+/// The point 360° of longitude away, on the far side of the antimeridian
+/// from where the index stores `point`'s neighbours across it.
+fn wrapped([lat, lon]: [f64; 2]) -> [f64; 2] {
+    [lat, if lon >= 0.0 { lon - 360.0 } else { lon + 360.0 }]
+}
+
+/// Meters spanned by one degree of latitude.
+const METERS_PER_DEGREE: f64 = EARTH_RADIUS_M * std::f64::consts::PI / 180.0;
+
+/// How far, in the planar degrees the spatial index ranks lines by, a point
+/// within `meters` of one at `lat` can be. A degree of longitude shrinks
+/// with the cosine of the latitude, so near the poles a point far in
+/// degrees can be near in meters; the cosine is taken at the edge of the
+/// band `meters` reaches, where it is smallest.
+fn planar_reach(lat: f64, meters: f64) -> f64 {
+    let dlat = meters / METERS_PER_DEGREE;
+    let cos = (lat.abs() + dlat).min(90.0).to_radians().cos().max(1e-6);
+    dlat / cos
+}
+
+/// Ordered node < way < relation, for breaking ties between matches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OsmType {
+    Node,
+    Way,
+    Relation,
+}
+
+impl OsmType {
+    /// The name serde uses, e.g. `way`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OsmType::Node => "node",
+            OsmType::Way => "way",
+            OsmType::Relation => "relation",
+        }
+    }
+}
+
+/// An indexed OSM element and the tags we answer queries with.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Feature {
+    pub osm_type: OsmType,
+    pub osm_id: i64,
+    /// Values of the configured index tags present on the element.
+    pub tags: BTreeMap<String, String>,
+    /// Values of the `--store-tags` keys present on the element, apart
+    /// from those already in `tags`.
+    pub extra_tags: BTreeMap<String, String>,
+    pub name: Option<String>,
+    /// `wikipedia:<lang>` tags, keyed by language code.
+    pub wikipedia_langs: BTreeMap<String, String>,
+    /// First node of the geometry that could be resolved, or the point a
+    /// relation is placed at, as `[lat, lon]`.
+    pub location: Option<[f64; 2]>,
+}
+
+/// Tag values a feature must all carry to be matched, e.g.
+/// `tourism=museum`; empty matches every feature. Reads from a JSON object
+/// or from `key=value` pairs separated by commas, the form a query string
+/// can carry.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "TagFilterRepr")]
+pub struct TagFilter(BTreeMap<String, String>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TagFilterRepr {
+    Tags(BTreeMap<String, String>),
+    Text(String),
+}
+
+impl TryFrom<TagFilterRepr> for TagFilter {
+    type Error = String;
+
+    fn try_from(repr: TagFilterRepr) -> Result<Self, String> {
+        let text = match repr {
+            TagFilterRepr::Tags(tags) => return Ok(TagFilter(tags)),
+            TagFilterRepr::Text(text) => text,
+        };
+        text.split(',')
+            .filter(|pair| !pair.trim().is_empty())
+            .map(|pair| match pair.split_once('=') {
+                Some((key, value)) if !key.trim().is_empty() => {
+                    Ok((key.trim().to_string(), value.trim().to_string()))
+                }
+                _ => Err(format!("filter {:?} is not of the form key=value", pair)),
+            })
+            .collect::<Result<_, _>>()
+            .map(TagFilter)
+    }
+}
+
+impl Feature {
+    fn heap_bytes(&self) -> usize {
+        memory::tags(&self.tags)
+            + memory::tags(&self.extra_tags)
+            + memory::tags(&self.wikipedia_langs)
+            + self.name.as_ref().map_or(0, String::capacity)
+    }
+}
+
+impl TagFilter {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(String::as_str)
+    }
+
+    fn matches(&self, feature: &Feature) -> bool {
+        self.0.iter().all(|(key, value)| {
+            feature
+                .tags
+                .get(key)
+                .or_else(|| feature.extra_tags.get(key))
+                == Some(value)
+        })
+    }
+}
+
+/// Why a lookup found nothing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryError {
+    /// Nothing is indexed at all.
+    EmptyIndex,
+    /// Nothing close enough, and the point lies beyond the extent of the
+    /// indexed features.
+    OutsideCoverage,
+    /// Nothing close enough, although the point is within that extent.
+    NothingNearby,
+    /// Nothing within the tolerance of an exact lookup.
+    NothingAtPoint,
+}
+
+impl std::fmt::Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            QueryError::EmptyIndex => "The index is empty",
+            QueryError::OutsideCoverage => "Point is outside the area covered by the index",
+            QueryError::NothingNearby => "No address found",
+            QueryError::NothingAtPoint => "No feature at this point",
+        })
+    }
+}
+
+pub struct Match {
+    pub feature: Feature,
+    pub distance_m: f64,
+    /// Point of the feature's geometry closest to the query, as `[lat, lon]`.
+    pub location: [f64; 2],
+}
+
+/// Nearest first; features at exactly the same distance are ordered by OSM
+/// id and then type, so results don't depend on how the index is laid out.
+pub fn by_distance(a: &Match, b: &Match) -> std::cmp::Ordering {
+    a.distance_m
+        .total_cmp(&b.distance_m)
+        .then(a.feature.osm_id.cmp(&b.feature.osm_id))
+        .then(a.feature.osm_type.cmp(&b.feature.osm_type))
+}
+
+/// A candidate feature's distance bits, OSM id, type and index; see
+/// [`GeoIndex::rank`].
+type Rank = (u64, i64, OsmType, usize);
+
+/// Counts the bytes pulled out of the PBF so progress can be estimated.
+struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// A box in degrees: the extent of the indexed geometry, or the coverage
+/// `--bbox` limits the index to.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Bbox {
+    pub min_lat: f64,
+    pub min_lon: f64,
+    pub max_lat: f64,
+    pub max_lon: f64,
+}
+
+impl Bbox {
+    fn contains(&self, lat: f64, lon: f64) -> bool {
+        (self.min_lat..=self.max_lat).contains(&lat) && (self.min_lon..=self.max_lon).contains(&lon)
+    }
+}
+
+/// In the order `--bbox` takes.
+impl std::fmt::Display for Bbox {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{},{},{},{}",
+            self.min_lat, self.min_lon, self.max_lat, self.max_lon
+        )
+    }
+}
+
+/// Whether a feature through `points` is to be indexed: always without a
+/// coverage box, otherwise if any of them lies in it, so ways crossing its
+/// edge are kept whole.
+fn within(coverage: Option<Bbox>, points: &[[f64; 2]]) -> bool {
+    coverage.is_none_or(|bbox| points.iter().any(|&[lat, lon]| bbox.contains(lat, lon)))
+}
+
+/// Summary of the index contents, computed whenever they change.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct IndexStats {
+    pub features: usize,
+    /// `None` while nothing is indexed.
+    pub bbox: Option<Bbox>,
+    /// How many features carry each of the index tags.
+    pub tag_counts: BTreeMap<String, usize>,
+    /// When the index was built from the pbf files, in seconds since the epoch.
+    pub built_at_unix: u64,
+    /// When change files last modified it, if ever. Always written, as the
+    /// stats are also part of the bincode cache, which can't skip fields.
+    pub updated_at_unix: Option<u64>,
+    /// The pbf files the index was built from.
+    pub sources: Vec<String>,
+    /// The `--bbox` the index was limited to, if any.
+    pub coverage: Option<Bbox>,
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+impl IndexStats {
+    /// Refreshes the counts and extent, keeping the timestamps and sources.
+    fn recount(&mut self, features: &[Feature], index_tags: &[String], lines: &[Segment]) {
+        self.features = features.len();
+        self.tag_counts = index_tags
+            .iter()
+            .map(|key| {
+                let count = features
+                    .iter()
+                    .filter(|feature| feature.tags.contains_key(key))
+                    .count();
+                (key.clone(), count)
+            })
+            .collect();
+        self.bbox = lines
+            .iter()
+            .flat_map(|line| [line.geom().from, line.geom().to])
+            .fold(None, |bbox: Option<Bbox>, [lat, lon]| {
+                Some(match bbox {
+                    None => Bbox {
+                        min_lat: lat,
+                        min_lon: lon,
+                        max_lat: lat,
+                        max_lon: lon,
+                    },
+                    Some(bbox) => Bbox {
+                        min_lat: bbox.min_lat.min(lat),
+                        min_lon: bbox.min_lon.min(lon),
+                        max_lat: bbox.max_lat.max(lat),
+                        max_lon: bbox.max_lon.max(lon),
+                    },
+                })
+            });
+    }
+}
+
+/// Rough memory use of a single-pass build per byte of pbf, mostly the map
+/// of every node's location: a dense node takes about 8 bytes in a pbf and
+/// around 50 in the map.
+const NODE_MAP_BYTES_PER_PBF_BYTE: u64 = 6;
+
+/// Elements of a pbf whose locations a multi-pass build keeps.
+#[derive(Default)]
+struct Needed {
+    nodes: HashSet<i64>,
+    /// Members of indexed relations.
+    ways: HashSet<i64>,
+}
+
+/// Visits every element of a pbf in file order.
+fn scan(path: &str, mut visit: impl FnMut(OsmObj)) {
+    let file = std::fs::File::open(path).unwrap();
+    let input = pbf::Input::new(file).unwrap_or_else(|e| panic!("cannot read {}: {}", path, e));
+    let mut pbf = osmpbfreader::OsmPbfReader::new(input);
+    let blocks = pbf
+        .blobs()
+        .par_map(|blob| blob.and_then(|blob| osmpbfreader::primitive_block_from_blob(&blob)));
+    for block in blocks.flatten() {
+        for obj in osmpbfreader::blocks::iter(&block) {
+            visit(obj);
+        }
+    }
+}
+
+/// What incremental updates need beyond the features themselves.
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Updates {
+    /// Node ids of each feature's way, parallel to `features`.
+    way_nodes: Vec<Vec<i64>>,
+    /// Locations of the nodes those ways reference.
+    nodes: HashMap<i64, [f64; 2]>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct GeoIndex {
-    tree: RTree<GeomWithData<Line<[f32; 2]>, i64>>,
-    ways: HashMap<i64, String>,
+    /// Each segment carries the position of its feature in `features`.
+    index: Backend,
+    features: Vec<Feature>,
+    /// Tag keys that make an element worth indexing.
+    index_tags: Vec<String>,
+    /// Further tag keys kept on indexed features; `*` keeps all of them.
+    stored_tags: Vec<String>,
+    /// Administrative boundary polygons, if they were asked for.
+    admin: Option<AdminIndex>,
+    /// Kept only when the index is to be updated from change files.
+    updates: Option<Updates>,
+    stats: IndexStats,
+    /// Identifies these contents like `revision`, but is stored with them,
+    /// so it survives restarts and is the same on every server loading
+    /// the same cache.
+    build_id: u64,
+    /// Cell size a grid index was asked for, kept so rebuilds use it too.
+    grid_cell_degrees: Option<f64>,
+    /// Changes whenever the contents do, so results looked up in one
+    /// version of the index aren't served from another. Not persisted.
+    #[serde(skip, default = "next_revision")]
+    revision: u64,
+    /// Whether the index was read from a cache rather than built here.
+    #[serde(skip, default = "deserialized")]
+    from_cache: bool,
+    /// [`GeoIndex::heap_bytes`], worked out on first use after each change.
+    #[serde(skip)]
+    heap_bytes: OnceLock<usize>,
+    /// Chosen per run rather than stored with the index.
+    #[serde(skip)]
+    metric: Metric,
+}
+
+/// Only deserialized indexes come from a cache.
+fn deserialized() -> bool {
+    true
+}
+
+/// Unlikely to repeat across builds, however close together.
+fn new_build_id() -> u64 {
+    use std::hash::{BuildHasher, Hash, Hasher};
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    std::time::SystemTime::now().hash(&mut hasher);
+    std::process::id().hash(&mut hasher);
+    hasher.finish()
+}
+
+fn next_revision() -> u64 {
+    static REVISIONS: AtomicU64 = AtomicU64::new(1);
+    REVISIONS.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The feature for a way with these tags, if it carries any index tag.
+fn tagged_feature<'a>(
+    index_tags: &[String],
+    stored_tags: &[String],
+    osm_type: OsmType,
+    osm_id: i64,
+    tags: impl Iterator<Item = (&'a str, &'a str)> + Clone,
+    location: Option<[f64; 2]>,
+) -> Option<Feature> {
+    let get = |key: &str| tags.clone().find(|(k, _)| *k == key).map(|(_, v)| v);
+    let indexed: BTreeMap<String, String> = index_tags
+        .iter()
+        .filter_map(|key| Some((key.clone(), get(key)?.to_string())))
+        .collect();
+    if indexed.is_empty() {
+        return None;
+    }
+    let store_all = stored_tags.iter().any(|key| key == "*");
+    let extra_tags = tags
+        .clone()
+        .filter(|(key, _)| store_all || stored_tags.iter().any(|stored| stored == key))
+        .filter(|(key, _)| !indexed.contains_key(*key))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    Some(Feature {
+        osm_type,
+        osm_id,
+        tags: indexed,
+        extra_tags,
+        name: get("name").map(|v| v.to_string()),
+        wikipedia_langs: tags
+            .clone()
+            .filter_map(|(key, value)| {
+                let lang = key.strip_prefix("wikipedia:")?;
+                Some((lang.to_string(), value.to_string()))
+            })
+            .collect(),
+        location,
+    })
+}
+
+/// Segments of a way through `points`, one between each pair of
+/// consecutive nodes, tagged with its feature position. A lone point becomes
+/// a zero-length segment, and a closed way also gets one at its centroid so
+/// that queries well inside it still match it.
+fn way_segments(feature: usize, points: impl Iterator<Item = [f64; 2]>) -> Vec<Segment> {
+    let points: Vec<[f64; 2]> = points.collect();
+    let Some(&start_point) = points.first() else {
+        return Vec::new();
+    };
+    let mut lines: Vec<Segment> = points
+        .windows(2)
+        .map(|edge| GeomWithData::new(Line::new(edge[0], edge[1]), feature))
+        .collect();
+    if points.len() == 1 {
+        lines.push(point_segment(feature, start_point));
+    } else if points.len() >= 4 && points.last() == Some(&start_point) {
+        lines.extend(centroid(&points).map(|point| point_segment(feature, point)));
+    }
+    lines
+}
+
+fn point_segment(feature: usize, point: [f64; 2]) -> Segment {
+    GeomWithData::new(Line::new(point, point), feature)
+}
+
+/// Area centroid of a closed ring, treating degrees as planar coordinates;
+/// `None` if the ring encloses no area.
+fn centroid(ring: &[[f64; 2]]) -> Option<[f64; 2]> {
+    // Relative to the first point, to keep the cross products precise.
+    let [lat0, lon0] = ring[0];
+    let (mut area, mut lat, mut lon) = (0.0, 0.0, 0.0);
+    for edge in ring.windows(2) {
+        let (lat1, lon1) = (edge[0][0] - lat0, edge[0][1] - lon0);
+        let (lat2, lon2) = (edge[1][0] - lat0, edge[1][1] - lon0);
+        let cross = lat1 * lon2 - lat2 * lon1;
+        area += cross;
+        lat += (lat1 + lat2) * cross;
+        lon += (lon1 + lon2) * cross;
+    }
+    (area.abs() > f64::EPSILON).then(|| [lat0 + lat / (3.0 * area), lon0 + lon / (3.0 * area)])
 }
 
 impl GeoIndex {
-    pub fn new() -> GeoIndex {
+    pub fn new(
+        index_tags: Vec<String>,
+        stored_tags: Vec<String>,
+        kind: spatial::Kind,
+        grid_cell_degrees: Option<f64>,
+        admin_boundaries: bool,
+        updatable: bool,
+    ) -> GeoIndex {
         GeoIndex {
-            tree: RTree::new(),
-            ways: HashMap::new(),
+            index: Backend::new(kind, Vec::new(), grid_cell_degrees),
+            features: Vec::new(),
+            index_tags,
+            stored_tags,
+            admin: admin_boundaries.then(AdminIndex::default),
+            updates: updatable.then(Updates::default),
+            stats: IndexStats::default(),
+            build_id: new_build_id(),
+            grid_cell_degrees,
+            revision: next_revision(),
+            from_cache: false,
+            heap_bytes: OnceLock::new(),
+            metric: Metric::default(),
         }
     }
 
-    pub fn build(&mut self, path: &str) {
+    /// An index of points given in memory rather than read from a pbf, so
+    /// tests can exercise lookups without a fixture file. Each
+    /// `([lat, lon], tags)` becomes a node numbered from 1 in order, indexed
+    /// if it carries one of `index_tags` like nodes read from a pbf.
+    #[cfg(test)]
+    pub fn from_points(
+        index_tags: Vec<String>,
+        kind: spatial::Kind,
+        points: impl IntoIterator<Item = ([f64; 2], BTreeMap<String, String>)>,
+    ) -> GeoIndex {
+        let mut geo = GeoIndex::new(index_tags, Vec::new(), kind, None, false, false);
+        let mut lines = Vec::new();
+        for (id, (point, tags)) in (1..).zip(points) {
+            let tags = tags.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+            if let Some(feature) =
+                tagged_feature(&geo.index_tags, &[], OsmType::Node, id, tags, Some(point))
+            {
+                lines.push(point_segment(geo.features.len(), point));
+                geo.features.push(feature);
+            }
+        }
+        geo.stats.recount(&geo.features, &geo.index_tags, &lines);
+        geo.stats.built_at_unix = unix_now();
+        geo.index = geo.backend(lines);
+        geo
+    }
+
+    pub fn index_tags(&self) -> &[String] {
+        &self.index_tags
+    }
+
+    pub fn stored_tags(&self) -> &[String] {
+        &self.stored_tags
+    }
+
+    /// Spatial structure the segments are stored in.
+    pub fn kind(&self) -> spatial::Kind {
+        self.index.kind()
+    }
+
+    /// Rough heap footprint of the features, the spatial structure, and the
+    /// boundaries and node locations if they are kept; see [`memory`].
+    pub fn heap_bytes(&self) -> usize {
+        *self.heap_bytes.get_or_init(|| self.measure_heap())
+    }
+
+    fn measure_heap(&self) -> usize {
+        let features = memory::vec(&self.features)
+            + self.features.iter().map(Feature::heap_bytes).sum::<usize>();
+        let admin = self.admin.as_ref().map_or(0, AdminIndex::heap_bytes);
+        let updates = self.updates.as_ref().map_or(0, |updates| {
+            memory::vec(&updates.way_nodes)
+                + updates.way_nodes.iter().map(memory::vec).sum::<usize>()
+                + updates.nodes.capacity() * (std::mem::size_of::<(i64, [f64; 2])>() + 1)
+        });
+        features + self.index.heap_bytes() + admin + updates
+    }
+
+    /// Every indexed feature, in the order they were indexed.
+    pub fn features(&self) -> &[Feature] {
+        &self.features
+    }
+
+    /// Whether the index keeps `key` on its features, so it can be filtered
+    /// on.
+    pub fn keeps_tag(&self, key: &str) -> bool {
+        self.index_tags
+            .iter()
+            .chain(&self.stored_tags)
+            .any(|kept| kept == key || kept == "*")
+    }
+
+    /// Cell size a grid index was built with, if one was asked for.
+    pub fn grid_cell_degrees(&self) -> Option<f64> {
+        self.grid_cell_degrees
+    }
+
+    /// An index of the same kind over `lines`.
+    fn backend(&self, lines: Vec<Segment>) -> Backend {
+        Backend::new(self.index.kind(), lines, self.grid_cell_degrees)
+    }
+
+    /// Number of indexed features.
+    pub fn len(&self) -> usize {
+        self.features.len()
+    }
+
+    /// Whether administrative boundaries are indexed.
+    pub fn has_admin_boundaries(&self) -> bool {
+        self.admin.is_some()
+    }
+
+    pub fn stats(&self) -> &IndexStats {
+        &self.stats
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn build_id(&self) -> u64 {
+        self.build_id
+    }
+
+    pub fn is_cached(&self) -> bool {
+        self.from_cache
+    }
+
+    pub fn metric(&self) -> Metric {
+        self.metric
+    }
+
+    pub fn set_metric(&mut self, metric: Metric) {
+        self.metric = metric;
+    }
+
+    /// Limits the features later builds and updates index to those within
+    /// `coverage`, and answers queries outside it without a lookup.
+    pub fn set_coverage(&mut self, coverage: Option<Bbox>) {
+        self.stats.coverage = coverage;
+    }
+
+    pub fn coverage(&self) -> Option<Bbox> {
+        self.stats.coverage
+    }
+
+    /// Whether the point is one the index can answer for at all.
+    fn covers(&self, lat: f64, lon: f64) -> bool {
+        within(self.stats.coverage, &[[lat, lon]])
+    }
+
+    /// Names the sources in the stats when the files built from were local
+    /// copies, e.g. downloads of these URLs.
+    pub fn set_sources(&mut self, sources: &[String]) {
+        self.stats.sources = sources.to_vec();
+    }
+
+    /// Whether the index keeps what [`GeoIndex::apply_osc`] needs.
+    pub fn is_updatable(&self) -> bool {
+        self.updates.is_some()
+    }
+
+    /// Orders the features of candidate matches like [`by_distance`] without
+    /// building the matches. Distances are never negative, so their bits
+    /// sort like them.
+    fn rank(&self, feature: usize, distance_m: f64) -> Rank {
+        let found = &self.features[feature];
+        (distance_m.to_bits(), found.osm_id, found.osm_type, feature)
+    }
+
+    fn to_match(&self, feature: usize, query: [f64; 2], location: [f64; 2]) -> Option<Match> {
+        Some(Match {
+            feature: self.features.get(feature)?.clone(),
+            distance_m: self.metric.distance_m(query, location),
+            location,
+        })
+    }
+
+    /// Indexes every pbf in `paths` into this index. A feature present in
+    /// several (overlapping) extracts is only indexed from the first one.
+    ///
+    /// A single pass over a pbf keeps the location of every node in memory,
+    /// about [`NODE_MAP_BYTES_PER_PBF_BYTE`] times the file size. When that
+    /// estimate exceeds `max_memory_mb`, the file is read in several passes
+    /// instead, keeping only the nodes the indexed features use.
+    pub fn build(&mut self, paths: &[String], max_memory_mb: Option<u64>) {
+        self.build_observed(paths, max_memory_mb, &mut LogProgress::default());
+    }
+
+    /// [`GeoIndex::build`], reporting to `observer` rather than the log.
+    pub fn build_observed(
+        &mut self,
+        paths: &[String],
+        max_memory_mb: Option<u64>,
+        observer: &mut dyn BuildObserver,
+    ) {
+        let start = Instant::now();
+        let mut seen: HashSet<(OsmType, i64)> = self
+            .features
+            .iter()
+            .map(|feature| (feature.osm_type, feature.osm_id))
+            .collect();
+        let mut lines = Vec::new();
+        for path in paths {
+            let size = std::fs::metadata(path).map_or(0, |m| m.len());
+            let estimate = size * NODE_MAP_BYTES_PER_PBF_BYTE;
+            let needed = match max_memory_mb {
+                Some(max) if estimate > max * 1024 * 1024 => {
+                    tracing::info!(
+                        "Reading {} in several passes: a single pass needs about {}MB, more than --max-memory-mb {}",
+                        path,
+                        estimate / (1024 * 1024),
+                        max
+                    );
+                    Some(self.needed(path))
+                }
+                _ => None,
+            };
+            self.ingest(path, needed.as_ref(), &mut seen, &mut lines, observer);
+            if let Some(admin) = &mut self.admin {
+                admin.build(path, &self.index_tags);
+            }
+        }
+        let empty = self.backend(Vec::new());
+        let index = std::mem::replace(&mut self.index, empty);
+        lines.extend(index.into_segments());
+        self.stats.recount(&self.features, &self.index_tags, &lines);
+        self.stats.built_at_unix = unix_now();
+        self.stats.sources.extend(paths.iter().cloned());
+        // Built from all segments at once, after `ingest` has freed its node
+        // locations, which take far more memory. Inserting segments while
+        // reading would only save the R-tree's bulk load holding them twice,
+        // the grid and k-d tree need them all up front, and a bulk-loaded
+        // R-tree answers faster.
+        self.index = self.backend(lines);
+        self.build_id = new_build_id();
+        self.revision = next_revision();
+        self.heap_bytes = OnceLock::new();
+        observer.observe(&BuildEvent::Finished {
+            features: self.features.len(),
+            files: paths.len(),
+            heap_bytes: self.heap_bytes(),
+            elapsed: start.elapsed(),
+        });
+    }
+
+    /// Finds the nodes and ways whose locations the features of `path` need,
+    /// so [`GeoIndex::ingest`] can skip the rest: a pass for the tagged ways
+    /// and multipolygon relations, and one for the ways those relations use.
+    fn needed(&self, path: &str) -> Needed {
+        let start = Instant::now();
+        let tagged = |tags: &osmpbfreader::Tags| {
+            self.index_tags
+                .iter()
+                .any(|key| tags.get(key.as_str()).is_some())
+        };
+        let mut needed = Needed::default();
+        scan(path, |obj| match obj {
+            OsmObj::Way(way) if tagged(&way.tags) => {
+                needed.nodes.extend(way.nodes.iter().map(|node| node.0));
+            }
+            OsmObj::Relation(relation)
+                if relation.tags.contains("type", "multipolygon") && tagged(&relation.tags) =>
+            {
+                for member in &relation.refs {
+                    match member.member {
+                        OsmId::Way(id) => {
+                            needed.ways.insert(id.0);
+                        }
+                        OsmId::Node(id) => {
+                            needed.nodes.insert(id.0);
+                        }
+                        OsmId::Relation(_) => {}
+                    }
+                }
+            }
+            _ => {}
+        });
+        if !needed.ways.is_empty() {
+            scan(path, |obj| {
+                if let OsmObj::Way(way) = obj {
+                    if needed.ways.contains(&way.id.0) {
+                        needed.nodes.extend(way.nodes.iter().map(|node| node.0));
+                    }
+                }
+            });
+        }
+        tracing::info!(
+            "Found {} nodes and {} relation member ways to keep in {} in {}ms",
+            needed.nodes.len(),
+            needed.ways.len(),
+            path,
+            start.elapsed().as_millis()
+        );
+        needed
+    }
+
+    fn ingest(
+        &mut self,
+        path: &str,
+        needed: Option<&Needed>,
+        seen: &mut HashSet<(OsmType, i64)>,
+        lines: &mut Vec<Segment>,
+        observer: &mut dyn BuildObserver,
+    ) {
         let start = Instant::now();
-        let mut pbf = osmpbfreader::OsmPbfReader::new(std::fs::File::open(path).unwrap());
-        println!("Loaded pbf in {}ms", start.elapsed().as_millis());
+        let file = std::fs::File::open(path).unwrap();
+        let total_bytes = file.metadata().map(|m| m.len()).unwrap_or(0);
+        let read_bytes = Arc::new(AtomicU64::new(0));
+        // Counted before decompression, so progress is measured against the
+        // file size as stored.
+        let input = pbf::Input::new(CountingReader {
+            inner: file,
+            count: read_bytes.clone(),
+        })
+        .unwrap_or_else(|e| panic!("cannot read {}: {}", path, e));
+        let mut pbf = osmpbfreader::OsmPbfReader::new(input);
+        observer.observe(&BuildEvent::FileOpened {
+            path,
+            elapsed: start.elapsed(),
+        });
 
         let mut nodes = HashMap::new();
+        // Sum and count of the located nodes of every way, for placing the
+        // relations built from them.
+        let mut way_points: HashMap<i64, ([f64; 2], usize)> = HashMap::new();
         let mut nodes_count = 0;
         let mut ways_count = 0;
+        let mut relations_count = 0;
         let mut lines_count = 0;
+        let mut duplicates_count = 0;
+        let mut outside_count = 0;
 
-        let tree = &mut self.tree;
-        let ways = &mut self.ways;
-
-        for obj in pbf.iter() {
-            match obj {
-                Ok(OsmObj::Node(node)) => {
-                    nodes.insert(node.id.0, [node.lat() as f32, node.lon() as f32]);
-                    nodes_count += 1;
-                    if nodes_count % 10000 == 0 {
-                        println!(
-                            "Loaded {} ways {} nodes in {}ms",
-                            ways_count,
-                            nodes_count,
-                            start.elapsed().as_millis()
-                        );
-                    }
-                }
-                Ok(OsmObj::Way(way)) => {
-                    ways_count += 1;
-                    if ways_count % 10000 == 0 {
-                        println!(
-                            "Loaded {} ways {} nodes in {}ms",
-                            ways_count,
-                            nodes_count,
-                            start.elapsed().as_millis()
-                        );
+        let coverage = self.stats.coverage;
+        let features = &mut self.features;
+        let index_tags = &self.index_tags;
+        let stored_tags = &self.stored_tags;
+        let updates = &mut self.updates;
+
+        // Blocks are decoded on a thread pool but yielded in file order, so
+        // nodes still arrive before the ways referencing them and the
+        // resulting index (and its cache) is reproducible.
+        let blocks = pbf
+            .blobs()
+            .par_map(|blob| blob.and_then(|blob| osmpbfreader::primitive_block_from_blob(&blob)));
+        for (blocks_before, block) in blocks.flatten().enumerate() {
+            for obj in osmpbfreader::blocks::iter(&block) {
+                match obj {
+                    OsmObj::Node(node) => {
+                        let point = [node.lat(), node.lon()];
+                        if needed.is_none_or(|needed| needed.nodes.contains(&node.id.0)) {
+                            nodes.insert(node.id.0, point);
+                        }
+                        nodes_count += 1;
+                        let tags = node.tags.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+                        let Some(found) = tagged_feature(
+                            index_tags,
+                            stored_tags,
+                            OsmType::Node,
+                            node.id.0,
+                            tags,
+                            Some(point),
+                        ) else {
+                            continue;
+                        };
+                        if !within(coverage, &[point]) {
+                            outside_count += 1;
+                            continue;
+                        }
+                        if !seen.insert((OsmType::Node, node.id.0)) {
+                            duplicates_count += 1;
+                            continue;
+                        }
+                        lines.push(point_segment(features.len(), point));
+                        lines_count += 1;
+                        features.push(found);
+                        observer.observe(&BuildEvent::FeatureIndexed {
+                            osm_type: OsmType::Node,
+                            osm_id: node.id.0,
+                        });
+                        if let Some(updates) = updates.as_mut() {
+                            updates.way_nodes.push(vec![node.id.0]);
+                            updates.nodes.insert(node.id.0, point);
+                        }
                     }
-                    let name = if let Some(name) = way.tags.get("wikipedia") {
-                        name.to_string()
-                    } else {
-                        continue;
-                    };
-                    ways.insert(way.id.0, name);
-
-                    let mut start_point = None;
-                    for node in &way.nodes {
-                        if let Some(node_point) = nodes.get(&(node.0)) {
-                            if let Some(start_point) = &start_point {
-                                let line = Line::new(*start_point, *node_point);
-                                lines_count += 1;
-                                tree.insert(GeomWithData::new(line, way.id.0));
-                            } else {
-                                start_point = Some(*node_point);
+                    OsmObj::Way(way) => {
+                        ways_count += 1;
+                        let mut location = None;
+                        let mut sum = ([0.0, 0.0], 0);
+                        // A closed way's repeated first node isn't counted twice.
+                        let open = match (way.nodes.first(), way.nodes.last()) {
+                            (Some(first), Some(last)) if way.nodes.len() > 1 && first == last => {
+                                &way.nodes[..way.nodes.len() - 1]
+                            }
+                            _ => &way.nodes[..],
+                        };
+                        for point in open.iter().filter_map(|node| nodes.get(&node.0)) {
+                            location.get_or_insert(*point);
+                            sum.0[0] += point[0];
+                            sum.0[1] += point[1];
+                            sum.1 += 1;
+                        }
+                        if sum.1 > 0 && needed.is_none_or(|needed| needed.ways.contains(&way.id.0))
+                        {
+                            way_points.insert(way.id.0, sum);
+                        }
+                        let tags = way.tags.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+                        let Some(found) = tagged_feature(
+                            index_tags,
+                            stored_tags,
+                            OsmType::Way,
+                            way.id.0,
+                            tags,
+                            location,
+                        ) else {
+                            continue;
+                        };
+                        let points: Vec<[f64; 2]> = way
+                            .nodes
+                            .iter()
+                            .filter_map(|node| nodes.get(&node.0).copied())
+                            .collect();
+                        if !within(coverage, &points) {
+                            outside_count += 1;
+                            continue;
+                        }
+                        if !seen.insert((OsmType::Way, way.id.0)) {
+                            duplicates_count += 1;
+                            continue;
+                        }
+                        let feature = features.len();
+                        features.push(found);
+                        observer.observe(&BuildEvent::FeatureIndexed {
+                            osm_type: OsmType::Way,
+                            osm_id: way.id.0,
+                        });
+
+                        let way_lines = way_segments(feature, points.into_iter());
+                        lines_count += way_lines.len();
+                        lines.extend(way_lines);
+                        if let Some(updates) = updates.as_mut() {
+                            updates
+                                .way_nodes
+                                .push(way.nodes.iter().map(|node| node.0).collect());
+                            for node in &way.nodes {
+                                if let Some(point) = nodes.get(&node.0) {
+                                    updates.nodes.insert(node.0, *point);
+                                }
                             }
                         }
                     }
+                    // Multipolygons (lakes, parks, ...) are placed at the mean
+                    // of their members' nodes. Boundaries are left to /admin.
+                    OsmObj::Relation(relation) => {
+                        relations_count += 1;
+                        if !relation.tags.contains("type", "multipolygon") {
+                            continue;
+                        }
+                        let (mut sum, mut count) = ([0.0, 0.0], 0);
+                        for member in &relation.refs {
+                            let (point, n) = match member.member {
+                                OsmId::Way(id) => match way_points.get(&id.0) {
+                                    Some(&(point, n)) => (point, n),
+                                    None => continue,
+                                },
+                                OsmId::Node(id) => match nodes.get(&id.0) {
+                                    Some(&point) => (point, 1),
+                                    None => continue,
+                                },
+                                OsmId::Relation(_) => continue,
+                            };
+                            sum[0] += point[0];
+                            sum[1] += point[1];
+                            count += n;
+                        }
+                        if count == 0 {
+                            continue;
+                        }
+                        let point = [sum[0] / count as f64, sum[1] / count as f64];
+                        let tags = relation.tags.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+                        let Some(found) = tagged_feature(
+                            index_tags,
+                            stored_tags,
+                            OsmType::Relation,
+                            relation.id.0,
+                            tags,
+                            Some(point),
+                        ) else {
+                            continue;
+                        };
+                        if !within(coverage, &[point]) {
+                            outside_count += 1;
+                            continue;
+                        }
+                        if !seen.insert((OsmType::Relation, relation.id.0)) {
+                            duplicates_count += 1;
+                            continue;
+                        }
+                        lines.push(point_segment(features.len(), point));
+                        lines_count += 1;
+                        features.push(found);
+                        observer.observe(&BuildEvent::FeatureIndexed {
+                            osm_type: OsmType::Relation,
+                            osm_id: relation.id.0,
+                        });
+                        if let Some(updates) = updates.as_mut() {
+                            updates.way_nodes.push(Vec::new());
+                        }
+                    }
                 }
-                _ => {}
             }
+            observer.observe(&BuildEvent::BlockProcessed {
+                blocks: blocks_before + 1,
+                nodes: nodes_count,
+                ways: ways_count,
+                relations: relations_count,
+                features: features.len(),
+                read_bytes: read_bytes.load(Ordering::Relaxed),
+                total_bytes,
+                elapsed: start.elapsed(),
+            });
         }
-        drop(nodes);
-        drop(pbf);
-        println!(
-            "Loaded {} ways {} lines in {}ms",
-            ways_count,
-            lines_count,
+        observer.observe(&BuildEvent::FileFinished {
+            path,
+            nodes: nodes_count,
+            located: nodes.len(),
+            ways: ways_count,
+            relations: relations_count,
+            lines: lines_count,
+            duplicates: duplicates_count,
+            outside: outside_count,
+            elapsed: start.elapsed(),
+        });
+    }
+
+    /// Applies an OsmChange file (optionally gzipped) to the index. Ways and
+    /// tagged nodes are created, replaced or removed as the file says, and
+    /// ways whose nodes moved get their geometry recomputed; the spatial
+    /// structure is rebuilt from the resulting segments. A way none of whose
+    /// nodes has a known location is left out. Relations and administrative
+    /// boundaries are left as they are.
+    pub fn apply_osc(&mut self, path: &str) -> Result<(), String> {
+        let start = Instant::now();
+        let Some(mut updates) = self.updates.take() else {
+            return Err("the index keeps no node locations to update".to_string());
+        };
+        let result = self.apply_changes(path, &mut updates);
+        self.updates = Some(updates);
+        let (added, removed) = result?;
+        tracing::info!(
+            "Applied {}: {} features added or updated, {} removed, in {}ms",
+            path,
+            added,
+            removed,
             start.elapsed().as_millis()
         );
+        Ok(())
+    }
+
+    fn apply_changes(
+        &mut self,
+        path: &str,
+        updates: &mut Updates,
+    ) -> Result<(usize, usize), String> {
+        let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+        let mut text = String::new();
+        pbf::Input::new(file)
+            .and_then(|mut input| input.read_to_string(&mut text))
+            .map_err(|e| e.to_string())?;
+        let changes = osc::parse(&text)?;
+
+        // Later changes to the same element win.
+        let mut nodes = BTreeMap::new();
+        let mut ways = BTreeMap::new();
+        for change in changes {
+            match (change.action, change.element) {
+                (osc::Action::Delete, osc::Element::Node { id, .. }) => {
+                    nodes.insert(id, None);
+                }
+                (_, osc::Element::Node { id, location, tags }) => {
+                    nodes.insert(id, location.map(|location| (location, tags)));
+                }
+                (osc::Action::Delete, osc::Element::Way { id, .. }) => {
+                    ways.insert(id, None);
+                }
+                (_, osc::Element::Way { id, nodes, tags }) => {
+                    ways.insert(id, Some((nodes, tags)));
+                }
+                (_, osc::Element::Relation) => {}
+            }
+        }
+
+        // Only the locations of nodes that kept ways use are tracked, like
+        // `ingest` does: those tracked already, and those of the indexed
+        // ways of this file. Tagged nodes are added once they are indexed.
+        let referenced: HashSet<i64> = ways
+            .values()
+            .flatten()
+            .filter(|(_, tags)| {
+                let tagged = |key: &String| tags.iter().any(|(k, _)| k == key);
+                self.index_tags.iter().any(tagged)
+            })
+            .flat_map(|(way_nodes, _)| way_nodes.iter().copied())
+            .collect();
+        let mut moved = HashSet::new();
+        for (&id, node) in &nodes {
+            match node {
+                None => {
+                    if updates.nodes.remove(&id).is_some() {
+                        moved.insert(id);
+                    }
+                }
+                Some((location, _)) => {
+                    if updates.nodes.contains_key(&id) || referenced.contains(&id) {
+                        updates.nodes.insert(id, *location);
+                        moved.insert(id);
+                    }
+                }
+            }
+        }
+
+        // Keep untouched features, and recompute the ways whose nodes moved.
+        // Changed nodes and ways are dropped, to be added back below if
+        // they are still tagged.
+        let old_features = std::mem::take(&mut self.features);
+        let old_way_nodes = std::mem::take(&mut updates.way_nodes);
+        let mut position = vec![None; old_features.len()];
+        let mut removed = HashSet::new();
+        let mut recomputed = Vec::new();
+        for (i, (feature, way_nodes)) in old_features.into_iter().zip(old_way_nodes).enumerate() {
+            let changed = match feature.osm_type {
+                OsmType::Node => nodes.contains_key(&feature.osm_id),
+                OsmType::Way => ways.contains_key(&feature.osm_id),
+                OsmType::Relation => false,
+            };
+            if changed {
+                removed.insert((feature.osm_type, feature.osm_id));
+                continue;
+            }
+            if way_nodes.iter().any(|node| moved.contains(node)) {
+                recomputed.push((feature, way_nodes));
+                continue;
+            }
+            position[i] = Some(self.features.len());
+            self.features.push(feature);
+            updates.way_nodes.push(way_nodes);
+        }
+
+        let empty = self.backend(Vec::new());
+        let index = std::mem::replace(&mut self.index, empty);
+        let mut lines: Vec<Segment> = index
+            .into_segments()
+            .into_iter()
+            .filter_map(|line| {
+                let feature = position[line.data]?;
+                Some(GeomWithData::new(*line.geom(), feature))
+            })
+            .collect();
+
+        let located = |way_nodes: &[i64]| -> Vec<[f64; 2]> {
+            way_nodes
+                .iter()
+                .filter_map(|node| updates.nodes.get(node).copied())
+                .collect()
+        };
+        let mut additions = Vec::new();
+        for (mut feature, way_nodes) in recomputed {
+            let points = located(&way_nodes);
+            feature.location = points.first().copied();
+            additions.push((feature, way_nodes, points));
+        }
+        for (id, node) in nodes {
+            let Some((location, tags)) = node else {
+                continue;
+            };
+            let tags = tags.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+            if let Some(feature) = tagged_feature(
+                &self.index_tags,
+                &self.stored_tags,
+                OsmType::Node,
+                id,
+                tags,
+                Some(location),
+            ) {
+                additions.push((feature, vec![id], vec![location]));
+            }
+        }
+        for (id, way) in ways {
+            let Some((way_nodes, tags)) = way else {
+                continue;
+            };
+            // A way none of whose nodes were ever kept can't be placed.
+            let points = located(&way_nodes);
+            if points.is_empty() {
+                continue;
+            }
+            let tags = tags.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+            if let Some(feature) = tagged_feature(
+                &self.index_tags,
+                &self.stored_tags,
+                OsmType::Way,
+                id,
+                tags,
+                points.first().copied(),
+            ) {
+                additions.push((feature, way_nodes, points));
+            }
+        }
+        let coverage = self.stats.coverage;
+        additions.retain(|(_, _, points)| within(coverage, points));
+        let added = additions.len();
+        for (feature, way_nodes, points) in additions {
+            removed.remove(&(feature.osm_type, feature.osm_id));
+            if feature.osm_type == OsmType::Node {
+                updates.nodes.insert(feature.osm_id, points[0]);
+            }
+            lines.extend(way_segments(self.features.len(), points.into_iter()));
+            self.features.push(feature);
+            updates.way_nodes.push(way_nodes);
+        }
+        self.stats.recount(&self.features, &self.index_tags, &lines);
+        self.stats.updated_at_unix = Some(unix_now());
+        self.index = self.backend(lines);
+        self.build_id = new_build_id();
+        self.revision = next_revision();
+        self.heap_bytes = OnceLock::new();
+        Ok((added, removed.len()))
+    }
+
+    /// The closest feature matching `filter`, if it is within
+    /// `max_distance_m`. Features across the antimeridian are found by
+    /// searching again from the query shifted by 360° when the seam is
+    /// nearer than the best match.
+    pub fn find(
+        &self,
+        lat: f64,
+        lon: f64,
+        max_distance_m: f64,
+        filter: &TagFilter,
+    ) -> Result<Match, QueryError> {
+        if !self.covers(lat, lon) {
+            return Err(self.miss(lat, lon));
+        }
+        let query = [lat, lon];
+        let (mut found, reach) = self.nearest_match(query, query, filter);
+        if 180.0 - lon.abs() < reach {
+            let (across, _) = self.nearest_match(query, wrapped(query), filter);
+            found = found.into_iter().chain(across).min_by(by_distance);
+        }
+        found
+            .filter(|found| found.distance_m <= max_distance_m)
+            .ok_or_else(|| self.miss(lat, lon))
+    }
+
+    /// Why a lookup at the point came back empty. With a coverage box, it
+    /// rather than the extent of the features tells outside from nearby.
+    pub fn miss(&self, lat: f64, lon: f64) -> QueryError {
+        match self.stats.coverage.or(self.stats.bbox) {
+            _ if self.features.is_empty() => QueryError::EmptyIndex,
+            Some(bbox) if bbox.contains(lat, lon) => QueryError::NothingNearby,
+            _ => QueryError::OutsideCoverage,
+        }
+    }
+
+    /// The match for the line nearest to `query`, seen from `frame`, the
+    /// first by [`by_distance`] if several are exactly as near, along with
+    /// the planar distance in degrees within which a nearer one would have
+    /// to lie (infinite if the index is empty). Lines come in planar order,
+    /// which isn't the order in meters away from the equator, so the walk
+    /// goes on until no line left can be nearer.
+    fn nearest_match(
+        &self,
+        query: [f64; 2],
+        frame: [f64; 2],
+        filter: &TagFilter,
+    ) -> (Option<Match>, f64) {
+        let mut best: Option<(Rank, [f64; 2])> = None;
+        let mut reach = f64::INFINITY;
+        for line in self.nearest_lines(frame, filter) {
+            if line.geom().distance_2(&frame).sqrt() > reach {
+                break;
+            }
+            let location = line.geom().nearest_point(&frame);
+            let distance_m = self.metric.distance_m(query, location);
+            let rank = self.rank(line.data, distance_m);
+            if best.is_none_or(|(best, _)| rank < best) {
+                best = Some((rank, location));
+                reach = planar_reach(frame[0], distance_m);
+            }
+        }
+        let found = best.and_then(|(rank, location)| self.to_match(rank.3, query, location));
+        (found, reach)
+    }
+
+    /// The indexed feature with this OSM identity, located at its first node.
+    /// A linear scan, meant for debugging rather than hot paths.
+    pub fn find_by_osm_id(&self, osm_type: OsmType, osm_id: i64) -> Option<Match> {
+        let feature = self
+            .features
+            .iter()
+            .find(|feature| feature.osm_type == osm_type && feature.osm_id == osm_id)?;
+        let location = feature.location?;
+        Some(Match {
+            feature: feature.clone(),
+            distance_m: 0.0,
+            location,
+        })
+    }
+
+    /// Administrative boundaries containing the point, ordered by
+    /// `admin_level` from country down; empty unless they were indexed.
+    pub fn locate_admin(&self, lat: f64, lon: f64) -> Vec<AdminMatch> {
+        self.admin
+            .as_ref()
+            .map(|admin| admin.locate(lat, lon))
+            .unwrap_or_default()
+    }
+
+    /// Lines nearest to `frame` first, skipping those of features that don't
+    /// match `filter`. A filter that few features match makes this walk much
+    /// of the index.
+    fn nearest_lines<'a>(
+        &'a self,
+        frame: [f64; 2],
+        filter: &'a TagFilter,
+    ) -> impl Iterator<Item = &'a Segment> + 'a {
+        self.index
+            .nearest(frame)
+            .filter(move |line| filter.is_empty() || filter.matches(&self.features[line.data]))
+    }
+
+    /// Up to `k` distinct ways matching `filter`, sorted by ascending
+    /// distance, including ways across the antimeridian like
+    /// [`GeoIndex::find`].
+    pub fn find_k_nearest(&self, lat: f64, lon: f64, k: usize, filter: &TagFilter) -> Vec<Match> {
+        if k == 0 || !self.covers(lat, lon) {
+            return Vec::new();
+        }
+        let query = [lat, lon];
+        let mut lines = Vec::new();
+        let reach = self.k_nearest_lines(query, query, k, filter, &mut lines);
+        if 180.0 - lon.abs() < reach {
+            self.k_nearest_lines(query, wrapped(query), k, filter, &mut lines);
+        }
+        self.closest_per_feature(query, lines.into_iter(), f64::INFINITY, k)
+    }
+
+    /// Collects every line around `frame` that can belong to one of the `k`
+    /// features nearest to `query`, including any exactly as near as the
+    /// last of them so the cut can be made by [`by_distance`], and returns
+    /// the planar distance in degrees it searched (infinite if there are
+    /// fewer than `k` features). The first `k` features in planar order are
+    /// all within the farthest of their distances in meters, so none nearer
+    /// than that lies beyond its [`planar_reach`].
+    fn k_nearest_lines<'a>(
+        &'a self,
+        query: [f64; 2],
+        frame: [f64; 2],
+        k: usize,
+        filter: &'a TagFilter,
+        lines: &mut Vec<(&'a Segment, [f64; 2])>,
+    ) -> f64 {
+        let mut seen = HashSet::new();
+        let mut farthest_m: f64 = 0.0;
+        let mut reach = f64::INFINITY;
+        for line in self.nearest_lines(frame, filter) {
+            if line.geom().distance_2(&frame).sqrt() > reach {
+                break;
+            }
+            lines.push((line, frame));
+            if seen.len() < k && seen.insert(line.data) {
+                let location = line.geom().nearest_point(&frame);
+                farthest_m = farthest_m.max(self.metric.distance_m(query, location));
+                if seen.len() == k {
+                    reach = planar_reach(frame[0], farthest_m);
+                }
+            }
+        }
+        reach
+    }
+
+    /// The nearest feature for each value of the tag `key` among those
+    /// matching `filter` and within `max_distance_m`, for up to
+    /// `max_categories` values, the nearest ones first. Without a distance
+    /// limit, finding fewer values than that visits the whole index.
+    pub fn find_nearest_per_category(
+        &self,
+        lat: f64,
+        lon: f64,
+        key: &str,
+        max_categories: usize,
+        max_distance_m: f64,
+        filter: &TagFilter,
+    ) -> HashMap<String, Match> {
+        if max_categories == 0 || !self.covers(lat, lon) {
+            return HashMap::new();
+        }
+        let query = [lat, lon];
+        // Lines planarly farther than the box `find_within_radius` would
+        // search can't be within the distance.
+        let dlat = max_distance_m / METERS_PER_DEGREE;
+        let limit = dlat.hypot(dlat / lat.to_radians().cos().max(1e-6));
+        // Each side of the antimeridian gets its own cap, as the values
+        // nearest on the far side may not be among the first ones found on
+        // this side.
+        let mut nearest = HashMap::new();
+        let reach = self.nearest_per_category(
+            query,
+            query,
+            key,
+            max_categories,
+            limit,
+            filter,
+            &mut nearest,
+        );
+        let mut across = HashMap::new();
+        if 180.0 - lon.abs() < reach {
+            self.nearest_per_category(
+                query,
+                wrapped(query),
+                key,
+                max_categories,
+                limit,
+                filter,
+                &mut across,
+            );
+        }
+        for (value, found) in across {
+            nearest
+                .entry(value)
+                .and_modify(|best| {
+                    if found.0 < best.0 {
+                        *best = found;
+                    }
+                })
+                .or_insert(found);
+        }
+        let mut matches: Vec<(&str, Match)> = nearest
+            .into_iter()
+            .filter(|(_, (rank, _))| f64::from_bits(rank.0) <= max_distance_m)
+            .filter_map(|(value, (rank, location))| {
+                Some((value, self.to_match(rank.3, query, location)?))
+            })
+            .collect();
+        matches.sort_by(|(_, a), (_, b)| by_distance(a, b));
+        matches.truncate(max_categories);
+        matches
+            .into_iter()
+            .map(|(value, found)| (value.to_string(), found))
+            .collect()
+    }
+
+    /// Keeps in `nearest` the closest line found around `frame` for each
+    /// value of `key`, with its [`Rank`] (so features exactly as near are
+    /// told apart like [`by_distance`] does) and location. Goes outwards
+    /// until lines are farther than `limit` degrees or than any of the
+    /// `max_categories` nearest values can be. The first that many values
+    /// found are all within the farthest of their distances in meters, so
+    /// none nearer lies beyond its [`planar_reach`], even where planar order
+    /// isn't the order in meters. Returns how far it went, in degrees.
+    #[allow(clippy::too_many_arguments)]
+    fn nearest_per_category<'a>(
+        &'a self,
+        query: [f64; 2],
+        frame: [f64; 2],
+        key: &str,
+        max_categories: usize,
+        limit: f64,
+        filter: &'a TagFilter,
+        nearest: &mut HashMap<&'a str, (Rank, [f64; 2])>,
+    ) -> f64 {
+        let mut farthest_m: f64 = 0.0;
+        let mut reach = f64::INFINITY;
+        for line in self.nearest_lines(frame, filter) {
+            if line.geom().distance_2(&frame).sqrt() > reach.min(limit) {
+                return reach.min(limit);
+            }
+            let feature = &self.features[line.data];
+            let Some(value) = feature
+                .tags
+                .get(key)
+                .or_else(|| feature.extra_tags.get(key))
+            else {
+                continue;
+            };
+            let location = line.geom().nearest_point(&frame);
+            let distance_m = self.metric.distance_m(query, location);
+            let found = (self.rank(line.data, distance_m), location);
+            match nearest.entry(value) {
+                Entry::Occupied(mut best) => {
+                    if found.0 < best.get().0 {
+                        best.insert(found);
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(found);
+                    if nearest.len() <= max_categories {
+                        farthest_m = farthest_m.max(distance_m);
+                        if nearest.len() == max_categories {
+                            reach = planar_reach(frame[0], farthest_m);
+                        }
+                    }
+                }
+            }
+        }
+        f64::INFINITY
+    }
+
+    /// The `limit` nearest ways within `radius_m` matching `filter`, sorted
+    /// by ascending distance. A circle reaching over the antimeridian is also
+    /// searched on the other side.
+    pub fn find_within_radius(
+        &self,
+        lat: f64,
+        lon: f64,
+        radius_m: f64,
+        filter: &TagFilter,
+        limit: usize,
+    ) -> Vec<Match> {
+        if !self.covers(lat, lon) {
+            return Vec::new();
+        }
+        let query = [lat, lon];
+        let dlat = (radius_m / METERS_PER_DEGREE).min(180.0);
+        let dlon = (dlat / lat.to_radians().cos().max(1e-6)).min(360.0);
+        let around = |[lat, lon]: [f64; 2]| {
+            AABB::from_corners([lat - dlat, lon - dlon], [lat + dlat, lon + dlon])
+        };
+        let mut frames = vec![query];
+        if lon.abs() + dlon > 180.0 {
+            frames.push(wrapped(query));
+        }
+
+        self.closest_per_feature(
+            query,
+            frames.into_iter().flat_map(|frame| {
+                self.index
+                    .in_envelope(around(frame))
+                    .filter(|line| filter.is_empty() || filter.matches(&self.features[line.data]))
+                    .map(move |line| (line, frame))
+            }),
+            radius_m,
+            limit,
+        )
+    }
+
+    /// The `limit` ways intersecting the box nearest its center, sorted by
+    /// distance from it. `min_lon > max_lon` means the box crosses the
+    /// antimeridian.
+    pub fn find_in_bbox(
+        &self,
+        min_lat: f64,
+        min_lon: f64,
+        max_lat: f64,
+        max_lon: f64,
+        limit: usize,
+    ) -> Vec<Match> {
+        let envelopes = if min_lon > max_lon {
+            vec![
+                AABB::from_corners([min_lat, min_lon], [max_lat, 180.0]),
+                AABB::from_corners([min_lat, -180.0], [max_lat, max_lon]),
+            ]
+        } else {
+            vec![AABB::from_corners([min_lat, min_lon], [max_lat, max_lon])]
+        };
+        let mut center_lon = (min_lon + max_lon) / 2.0;
+        if min_lon > max_lon {
+            center_lon += if center_lon > 0.0 { -180.0 } else { 180.0 };
+        }
+        let center = [(min_lat + max_lat) / 2.0, center_lon];
+
+        self.closest_per_feature(
+            center,
+            envelopes
+                .into_iter()
+                .flat_map(|envelope| self.index.in_envelope(envelope))
+                .map(|line| (line, center)),
+            f64::INFINITY,
+            limit,
+        )
+    }
+
+    /// Reduces candidate lines to one match per feature, keeping each
+    /// feature's point closest to `query`, and returns the `limit` nearest
+    /// features sorted by ascending distance. Each line comes with the frame
+    /// to find its nearest point from: the query itself, or the query
+    /// shifted across the antimeridian. Only the best `limit` features seen
+    /// so far are held, so a query over a large area costs memory for the
+    /// answer rather than for everything in the area.
+    fn closest_per_feature<'a>(
+        &'a self,
+        query: [f64; 2],
+        lines: impl Iterator<Item = (&'a Segment, [f64; 2])>,
+        max_distance_m: f64,
+        limit: usize,
+    ) -> Vec<Match> {
+        if limit == 0 {
+            return Vec::new();
+        }
+        // The kept features by rank, the worst last, and the closest point
+        // of each.
+        let mut ranked = BTreeSet::new();
+        let mut nearest: HashMap<usize, (f64, [f64; 2])> = HashMap::new();
+        for (line, frame) in lines {
+            let location = line.geom().nearest_point(&frame);
+            let distance_m = self.metric.distance_m(query, location);
+            if distance_m > max_distance_m {
+                continue;
+            }
+            let feature = line.data;
+            if let Some(best) = nearest.get_mut(&feature) {
+                // Equally near points of one feature are told apart by
+                // position, for the same reason as `by_distance`.
+                if (distance_m, location[0], location[1]) < (best.0, best.1[0], best.1[1]) {
+                    ranked.remove(&self.rank(feature, best.0));
+                    ranked.insert(self.rank(feature, distance_m));
+                    *best = (distance_m, location);
+                }
+                continue;
+            }
+            let candidate = self.rank(feature, distance_m);
+            if ranked.len() == limit {
+                match ranked.last() {
+                    Some(worst) if candidate < *worst => {
+                        if let Some(worst) = ranked.pop_last() {
+                            nearest.remove(&worst.3);
+                        }
+                    }
+                    _ => continue,
+                }
+            }
+            ranked.insert(candidate);
+            nearest.insert(feature, (distance_m, location));
+        }
+
+        ranked
+            .into_iter()
+            .filter_map(|(_, _, _, feature)| {
+                let (_, location) = nearest[&feature];
+                self.to_match(feature, query, location)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [spatial::Kind; 4] = [
+        spatial::Kind::Grid,
+        spatial::Kind::Kdtree,
+        spatial::Kind::Rtree,
+        spatial::Kind::S2,
+    ];
+
+    /// An index of points named after their position in `points`.
+    fn named(kind: spatial::Kind, points: &[[f64; 2]]) -> GeoIndex {
+        GeoIndex::from_points(
+            vec!["name".to_string()],
+            kind,
+            points.iter().enumerate().map(|(i, &point)| {
+                let tags = BTreeMap::from([("name".to_string(), i.to_string())]);
+                (point, tags)
+            }),
+        )
+    }
+
+    /// The point `north` and `east` meters away from `[lat, lon]`.
+    fn offset([lat, lon]: [f64; 2], north: f64, east: f64) -> [f64; 2] {
+        let east_per_degree = METERS_PER_DEGREE * lat.to_radians().cos();
+        [
+            lat + north / METERS_PER_DEGREE,
+            lon + east / east_per_degree,
+        ]
+    }
+
+    fn nearest(geo: &GeoIndex, [lat, lon]: [f64; 2]) -> Option<String> {
+        let found = geo.find(lat, lon, f64::INFINITY, &TagFilter::default());
+        found.ok()?.feature.name
+    }
+
+    fn k_nearest(geo: &GeoIndex, [lat, lon]: [f64; 2], k: usize) -> Vec<String> {
+        let found = geo.find_k_nearest(lat, lon, k, &TagFilter::default());
+        found
+            .into_iter()
+            .filter_map(|found| found.feature.name)
+            .collect()
+    }
+
+    #[test]
+    fn tells_apart_features_half_a_meter_apart() {
+        for kind in KINDS {
+            for base in [[0.0, 0.0], [21.0228, 105.8011], [-45.0, -170.0]] {
+                for (north, east) in [(0.5, 0.0), (0.0, 0.5)] {
+                    let other = offset(base, north, east);
+                    let geo = named(kind, &[base, other]);
+                    for (fraction, expected) in [(0.3, "0"), (0.7, "1")] {
+                        let query = offset(base, north * fraction, east * fraction);
+                        assert_eq!(
+                            nearest(&geo, query).as_deref(),
+                            Some(expected),
+                            "{:?} at {:?}, {} of the way",
+                            kind,
+                            base,
+                            fraction
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ranks_by_meters_at_high_latitude() {
+        // In planar degrees the feature north of the query is nearer, but a
+        // degree of longitude at 70° spans only a third of the meters.
+        for kind in KINDS {
+            let query = [70.0, 10.0];
+            let north = offset(query, 0.4, 0.0);
+            let east = offset(query, 0.0, 0.3);
+            let geo = named(kind, &[north, east]);
+            assert_eq!(nearest(&geo, query).as_deref(), Some("1"), "{:?}", kind);
+            assert_eq!(k_nearest(&geo, query, 1), ["1"], "{:?}", kind);
+            assert_eq!(k_nearest(&geo, query, 2), ["1", "0"], "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn keeps_the_categories_nearest_in_meters() {
+        // As above, with each feature a category of its own: the one east
+        // is found second but is the nearer.
+        for kind in KINDS {
+            let query = [70.0, 10.0];
+            let geo = named(kind, &[offset(query, 0.4, 0.0), offset(query, 0.0, 0.3)]);
+            let found = geo.find_nearest_per_category(
+                query[0],
+                query[1],
+                "name",
+                1,
+                f64::INFINITY,
+                &TagFilter::default(),
+            );
+            let values: Vec<_> = found.keys().map(String::as_str).collect();
+            assert_eq!(values, ["1"], "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn breaks_ties_within_a_category_by_osm_id() {
+        let point = [48.8566, 2.3522];
+        for kind in KINDS {
+            let geo = GeoIndex::from_points(
+                vec!["name".to_string()],
+                kind,
+                (0..50).map(|_| (point, BTreeMap::from([("name".into(), "same".into())]))),
+            );
+            let found = geo.find_nearest_per_category(
+                point[0],
+                point[1],
+                "name",
+                1,
+                f64::INFINITY,
+                &TagFilter::default(),
+            );
+            assert_eq!(found["same"].feature.osm_id, 1, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn finds_features_across_the_antimeridian() {
+        for kind in KINDS {
+            // From either side: 0.0002° (22m) away across the antimeridian,
+            // 0.0009° (100m) away on the query's side, and one far away.
+            for side in [1.0, -1.0] {
+                let geo = named(
+                    kind,
+                    &[[0.0, -side * 179.9999], [0.0, side * 179.999], [0.0, 0.0]],
+                );
+                let query = [0.0, side * 179.9999];
+                let found = geo
+                    .find(query[0], query[1], f64::INFINITY, &TagFilter::default())
+                    .unwrap();
+                assert_eq!(found.feature.name.as_deref(), Some("0"), "{:?}", kind);
+                assert!(
+                    (found.distance_m - 22.2).abs() < 0.1,
+                    "{:?}: {}",
+                    kind,
+                    found.distance_m
+                );
+                assert_eq!(k_nearest(&geo, query, 2), ["0", "1"], "{:?}", kind);
+
+                let within: Vec<_> = geo
+                    .find_within_radius(query[0], query[1], 50.0, &TagFilter::default(), 10)
+                    .into_iter()
+                    .filter_map(|found| found.feature.name)
+                    .collect();
+                assert_eq!(within, ["0"], "{:?}", kind);
+            }
+
+            let geo = named(kind, &[[0.0, -179.9999], [0.0, 179.999], [0.0, 0.0]]);
+            let in_box: Vec<_> = geo
+                .find_in_bbox(-1.0, 179.99, 1.0, -179.99, 10)
+                .into_iter()
+                .filter_map(|found| found.feature.name)
+                .collect();
+            assert_eq!(in_box, ["0", "1"], "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn breaks_ties_between_coincident_features_by_osm_id() {
+        // Enough features at one point that the backends lay them out in
+        // orders of their own; alongside, two more at the same distance on
+        // opposite sides of the query.
+        let point = [48.8566, 2.3522];
+        let mut points = vec![point; 50];
+        points.push(offset(point, 0.0, -10.0));
+        points.push(offset(point, 0.0, 10.0));
+        let ids = |found: Vec<Match>| -> Vec<i64> {
+            found.iter().map(|found| found.feature.osm_id).collect()
+        };
+        let filter = TagFilter::default();
+        let answers = |geo: &GeoIndex| {
+            let nearest = geo
+                .find(point[0], point[1], f64::INFINITY, &filter)
+                .unwrap();
+            let query = offset(point, 5.0, 0.0);
+            (
+                nearest.feature.osm_id,
+                ids(geo.find_k_nearest(point[0], point[1], 10, &filter)),
+                ids(geo.find_within_radius(point[0], point[1], 1.0, &filter, 100)),
+                ids(geo.find_k_nearest(query[0], query[1], 60, &filter)),
+            )
+        };
+
+        let expected = answers(&named(spatial::Kind::Rtree, &points));
+        assert_eq!(expected.0, 1);
+        assert_eq!(expected.1, (1..=10).collect::<Vec<_>>());
+        assert_eq!(expected.2, (1..=50).collect::<Vec<_>>());
+        for kind in KINDS {
+            for _ in 0..2 {
+                assert_eq!(answers(&named(kind, &points)), expected, "{:?}", kind);
+            }
+        }
+    }
+
+    /// Encodes `value` as a protobuf varint.
+    fn varint(mut value: u64, out: &mut Vec<u8>) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn zigzag(value: i64) -> u64 {
+        ((value << 1) ^ (value >> 63)) as u64
+    }
+
+    fn field(number: u64, bytes: &[u8], out: &mut Vec<u8>) {
+        varint(number << 3 | 2, out);
+        varint(bytes.len() as u64, out);
+        out.extend(bytes);
+    }
+
+    fn packed(number: u64, values: impl IntoIterator<Item = u64>, out: &mut Vec<u8>) {
+        let mut bytes = Vec::new();
+        values
+            .into_iter()
+            .for_each(|value| varint(value, &mut bytes));
+        field(number, &bytes, out);
+    }
+
+    /// A pbf of `nodes` (id, untagged if `None`, else named), `ways` (id,
+    /// node ids, name) and multipolygon `relations` (id, member way ids,
+    /// name). Strings 1 and 2 are `name` and `type`, 3 `multipolygon`.
+    fn write_pbf(
+        path: &std::path::Path,
+        nodes: &[(i64, [f64; 2], Option<&str>)],
+        ways: &[(i64, Vec<i64>, Option<&str>)],
+        relations: &[(i64, Vec<i64>, &str)],
+    ) {
+        let mut names = Vec::new();
+        let mut name = |value: &str| -> u64 {
+            names.push(value.to_string());
+            names.len() as u64 + 3
+        };
+        let mut group = Vec::new();
+        for &(id, [lat, lon], tag) in nodes {
+            let mut node = Vec::new();
+            node.push(1 << 3);
+            varint(zigzag(id), &mut node);
+            if let Some(tag) = tag {
+                packed(2, [1], &mut node);
+                packed(3, [name(tag)], &mut node);
+            }
+            node.push(8 << 3);
+            varint(zigzag((lat * 1e7).round() as i64), &mut node);
+            node.push(9 << 3);
+            varint(zigzag((lon * 1e7).round() as i64), &mut node);
+            field(1, &node, &mut group);
+        }
+        let mut groups = Vec::new();
+        field(2, &group, &mut groups);
+        let delta = |ids: &[i64]| -> Vec<u64> {
+            let previous = std::iter::once(0).chain(ids.iter().copied());
+            ids.iter()
+                .zip(previous)
+                .map(|(id, previous)| zigzag(id - previous))
+                .collect()
+        };
+        let mut group = Vec::new();
+        for (id, refs, tag) in ways {
+            let mut way = vec![1 << 3];
+            varint(*id as u64, &mut way);
+            if let Some(tag) = tag {
+                packed(2, [1], &mut way);
+                packed(3, [name(tag)], &mut way);
+            }
+            packed(8, delta(refs), &mut way);
+            field(3, &way, &mut group);
+        }
+        for (id, members, tag) in relations {
+            let mut relation = vec![1 << 3];
+            varint(*id as u64, &mut relation);
+            packed(2, [1, 2], &mut relation);
+            packed(3, [name(tag), 3], &mut relation);
+            packed(8, members.iter().map(|_| 0), &mut relation);
+            packed(9, delta(members), &mut relation);
+            packed(10, members.iter().map(|_| 1), &mut relation);
+            field(4, &relation, &mut group);
+        }
+        field(2, &group, &mut groups);
+
+        let mut strings = Vec::new();
+        for string in ["", "name", "type", "multipolygon"]
+            .into_iter()
+            .chain(names.iter().map(String::as_str))
+        {
+            field(1, string.as_bytes(), &mut strings);
+        }
+        let mut block = Vec::new();
+        field(1, &strings, &mut block);
+        block.extend(groups);
+
+        let mut header = Vec::new();
+        field(4, b"OsmSchema-V0.6", &mut header);
+        let mut file = Vec::new();
+        for (kind, data) in [("OSMHeader", header), ("OSMData", block)] {
+            let mut blob = Vec::new();
+            field(1, &data, &mut blob);
+            let mut blob_header = Vec::new();
+            field(1, kind.as_bytes(), &mut blob_header);
+            blob_header.push(3 << 3);
+            varint(blob.len() as u64, &mut blob_header);
+            file.extend((blob_header.len() as u32).to_be_bytes());
+            file.extend(blob_header);
+            file.extend(blob);
+        }
+        std::fs::write(path, file).unwrap();
+    }
+
+    /// An index built from a pbf of named `ways` through the given points,
+    /// closed where the last point repeats the first, and named `points`.
+    fn indexed(ways: &[(&str, Vec<[f64; 2]>)], points: &[(&str, [f64; 2])]) -> GeoIndex {
+        static BUILDS: AtomicU64 = AtomicU64::new(0);
+        let mut nodes = Vec::new();
+        let mut way_refs = Vec::new();
+        for (id, (name, way)) in (1..).zip(ways) {
+            let first = nodes.len() as i64 + 1;
+            let mut refs = Vec::new();
+            for (i, &point) in way.iter().enumerate() {
+                if i > 0 && i == way.len() - 1 && point == way[0] {
+                    refs.push(first);
+                    continue;
+                }
+                nodes.push((nodes.len() as i64 + 1, point, None));
+                refs.push(nodes.len() as i64);
+            }
+            way_refs.push((id, refs, Some(*name)));
+        }
+        for &(name, point) in points {
+            nodes.push((nodes.len() as i64 + 1, point, Some(name)));
+        }
+        let path = std::env::temp_dir().join(format!(
+            "indexed-{}-{}.osm.pbf",
+            std::process::id(),
+            BUILDS.fetch_add(1, Ordering::Relaxed)
+        ));
+        write_pbf(&path, &nodes, &way_refs, &[]);
+        let mut geo = GeoIndex::new(
+            vec!["name".to_string()],
+            Vec::new(),
+            spatial::Kind::Rtree,
+            None,
+            false,
+            false,
+        );
+        geo.build(&[path.to_string_lossy().into_owned()], None);
+        std::fs::remove_file(&path).unwrap();
+        geo
+    }
+
+    #[test]
+    fn measures_to_the_way_rather_than_chords_from_its_first_node() {
+        // An L east then north, and a point feature 150m from the query,
+        // which lies 55m east of the way's second leg near the elbow. The
+        // chord from the first node to the last would put the way 590m away.
+        let way = vec![[0.0, 0.0], [0.0, 0.01], [0.01, 0.01]];
+        let query = [0.003, 0.0105];
+        let point = offset(query, 0.0, 150.0);
+        let geo = indexed(&[("way", way)], &[("point", point)]);
+
+        let found = geo
+            .find(query[0], query[1], f64::INFINITY, &TagFilter::default())
+            .unwrap();
+        assert_eq!(found.feature.name.as_deref(), Some("way"));
+        assert!(
+            (found.distance_m - 55.6).abs() < 0.5,
+            "{}",
+            found.distance_m
+        );
+        assert!(
+            (found.location[0] - 0.003).abs() < 1e-9,
+            "{:?}",
+            found.location
+        );
+        assert!(
+            (found.location[1] - 0.01).abs() < 1e-9,
+            "{:?}",
+            found.location
+        );
+    }
+
+    #[test]
+    fn measures_to_the_edges_of_a_non_convex_area() {
+        // A closed C opening east, 330m across, with the query in the middle
+        // of its notch, 55m from the edges above and below. Chords from the
+        // first node would cross the notch, and its centroid lies inside the
+        // notch too, 71m from the query.
+        let u = 0.001;
+        let ring = [
+            [0, 0],
+            [3, 0],
+            [3, 3],
+            [2, 3],
+            [2, 1],
+            [1, 1],
+            [1, 3],
+            [0, 3],
+            [0, 0],
+        ];
+        let ring = ring
+            .iter()
+            .map(|&[lat, lon]| [lat as f64 * u, lon as f64 * u])
+            .collect();
+        let geo = indexed(&[("area", ring)], &[]);
+
+        let found = geo
+            .find(1.5 * u, 2.0 * u, f64::INFINITY, &TagFilter::default())
+            .unwrap();
+        assert!(
+            (found.distance_m - 55.6).abs() < 0.5,
+            "{}",
+            found.distance_m
+        );
+        assert!(
+            (found.location[1] - 2.0 * u).abs() < 1e-9,
+            "{:?}",
+            found.location
+        );
+    }
+
+    #[test]
+    fn keeps_only_the_needed_nodes_under_max_memory_mb() {
+        // One tagged way, a multipolygon of one untagged way, a tagged node
+        // and a thousand nodes nothing indexed uses.
+        let nodes: Vec<_> = (1..=1100)
+            .map(|id| {
+                let tag = (id == 1100).then_some("node");
+                (id, [46.0 + id as f64 * 1e-4, 7.0], tag)
+            })
+            .collect();
+        let mut ways = vec![(1, vec![1, 2, 3], Some("way")), (2, vec![4, 5, 6, 7], None)];
+        ways.extend((3..100).map(|id| (id, (id * 10..id * 10 + 10).collect(), None)));
+        let relations = [(1, vec![2], "relation")];
+        let path = std::env::temp_dir().join(format!("multipass-{}.osm.pbf", std::process::id()));
+        write_pbf(&path, &nodes, &ways, &relations);
+
+        let build = |max_memory_mb| {
+            let mut geo = GeoIndex::new(
+                vec!["name".to_string()],
+                Vec::new(),
+                spatial::Kind::Rtree,
+                None,
+                false,
+                false,
+            );
+            let mut located = 0;
+            let mut observe = |event: &BuildEvent| {
+                if let BuildEvent::FileFinished { located: n, .. } = *event {
+                    located = n;
+                }
+            };
+            let paths = [path.to_string_lossy().into_owned()];
+            geo.build_observed(&paths, max_memory_mb, &mut observe);
+            (geo, located)
+        };
+        let (single, all) = build(None);
+        let (multi, needed) = build(Some(0));
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(all, nodes.len());
+        assert_eq!(needed, 7);
+        assert_eq!(single.features.len(), 3);
+        assert_eq!(
+            format!("{:?}", multi.features),
+            format!("{:?}", single.features)
+        );
+        let query = offset([46.0002, 7.0], 0.0, 3.0);
+        assert_eq!(k_nearest(&single, query, 3), ["way", "relation", "node"]);
+        assert_eq!(k_nearest(&multi, query, 3), k_nearest(&single, query, 3));
+    }
+
+    #[test]
+    fn applies_changes_to_tagged_nodes() {
+        // Way 42 and node 42 are different elements; node 7 gets a name.
+        let nodes: Vec<_> = (1..=42)
+            .map(|id| {
+                let tag = (id == 42).then_some("node");
+                (id, [52.0 + id as f64 * 1e-3, 13.0], tag)
+            })
+            .collect();
+        let ways = [(42, vec![1, 2, 3], Some("way"))];
+        let dir = std::env::temp_dir();
+        let pbf = dir.join(format!("osc-{}.osm.pbf", std::process::id()));
+        let osc = dir.join(format!("osc-{}.osc", std::process::id()));
+        write_pbf(&pbf, &nodes, &ways, &[]);
+        std::fs::write(
+            &osc,
+            r#"<osmChange version="0.6">
+              <delete><node id="42"/></delete>
+              <modify>
+                <node id="7" lat="52.5" lon="13.5"><tag k="name" v="renamed"/></node>
+              </modify>
+            </osmChange>"#,
+        )
+        .unwrap();
+
+        let mut geo = GeoIndex::new(
+            vec!["name".to_string()],
+            Vec::new(),
+            spatial::Kind::Rtree,
+            None,
+            false,
+            true,
+        );
+        geo.build(&[pbf.to_string_lossy().into_owned()], None);
+        let applied = geo.apply_osc(&osc.to_string_lossy());
+        std::fs::remove_file(&pbf).unwrap();
+        std::fs::remove_file(&osc).unwrap();
+        applied.unwrap();
+
+        let features: Vec<_> = geo
+            .features
+            .iter()
+            .map(|feature| (feature.osm_type, feature.osm_id, feature.name.as_deref()))
+            .collect();
+        assert_eq!(
+            features,
+            [
+                (OsmType::Way, 42, Some("way")),
+                (OsmType::Node, 7, Some("renamed"))
+            ]
+        );
+        assert_eq!(nearest(&geo, [52.5, 13.5]).as_deref(), Some("renamed"));
+        assert_eq!(nearest(&geo, [52.042, 13.0]).as_deref(), Some("way"));
     }
 
-    pub fn find(&self, lat: f32, lon: f32) -> Option<String> {
-        let way_id = self
-            .tree
-            .nearest_neighbor(&[lat, lon])
-            .map(|res| res.data)?;
-        self.ways.get(&way_id).map(|name| name.clone())
+    #[test]
+    fn tracks_only_the_nodes_indexed_ways_use() {
+        let nodes: Vec<_> = (1..=42)
+            .map(|id| {
+                let tag = (id == 42).then_some("node");
+                (id, [52.0 + id as f64 * 1e-3, 13.0], tag)
+            })
+            .collect();
+        let ways = [(42, vec![1, 2, 3], Some("way"))];
+        let dir = std::env::temp_dir();
+        let pbf = dir.join(format!("tracked-{}.osm.pbf", std::process::id()));
+        let osc = dir.join(format!("tracked-{}.osc", std::process::id()));
+        write_pbf(&pbf, &nodes, &ways, &[]);
+        // Untracked node 30 moves; way 51 is new, along with its nodes;
+        // untagged way 52 isn't indexed; way 50 uses nodes never seen.
+        std::fs::write(
+            &osc,
+            r#"<osmChange version="0.6">
+              <modify>
+                <node id="30" lat="53" lon="13"/>
+                <node id="2" lat="52.1" lon="13"/>
+              </modify>
+              <create>
+                <node id="600" lat="53" lon="14"/>
+                <node id="601" lat="53.001" lon="14"/>
+                <way id="51"><nd ref="600"/><nd ref="601"/><tag k="name" v="new"/></way>
+                <node id="602" lat="54" lon="14"/>
+                <node id="603" lat="54.001" lon="14"/>
+                <way id="52"><nd ref="602"/><nd ref="603"/></way>
+                <way id="50"><nd ref="500"/><nd ref="501"/><tag k="name" v="ghost"/></way>
+              </create>
+            </osmChange>"#,
+        )
+        .unwrap();
+
+        let mut geo = GeoIndex::new(
+            vec!["name".to_string()],
+            Vec::new(),
+            spatial::Kind::Rtree,
+            None,
+            false,
+            true,
+        );
+        geo.build(&[pbf.to_string_lossy().into_owned()], None);
+        let tracked = |geo: &GeoIndex| -> BTreeSet<i64> {
+            geo.updates
+                .as_ref()
+                .unwrap()
+                .nodes
+                .keys()
+                .copied()
+                .collect()
+        };
+        assert_eq!(tracked(&geo), BTreeSet::from([1, 2, 3, 42]));
+        let applied = geo.apply_osc(&osc.to_string_lossy());
+        std::fs::remove_file(&pbf).unwrap();
+        std::fs::remove_file(&osc).unwrap();
+        applied.unwrap();
+
+        assert_eq!(tracked(&geo), BTreeSet::from([1, 2, 3, 42, 600, 601]));
+        let features: Vec<_> = geo.features.iter().map(|f| f.name.as_deref()).collect();
+        assert_eq!(features, [Some("node"), Some("way"), Some("new")]);
+        assert_eq!(geo.len(), 3);
+        assert_eq!(nearest(&geo, [52.1, 13.0]).as_deref(), Some("way"));
     }
 }
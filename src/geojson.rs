@@ -0,0 +1,49 @@
+use serde::Serialize;
+
+/// A GeoJSON `FeatureCollection` of points carrying `P` as properties.
+#[derive(Serialize)]
+pub struct FeatureCollection<P> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    features: Vec<Feature<P>>,
+    /// Foreign member mirroring the envelope's flag when a result cap was hit
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+}
+
+#[derive(Serialize)]
+struct Feature<P> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    geometry: Point,
+    properties: P,
+}
+
+#[derive(Serialize)]
+struct Point {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// GeoJSON orders coordinates as `[lon, lat]`.
+    coordinates: [f64; 2],
+}
+
+impl<P> FeatureCollection<P> {
+    /// Builds a collection from `([lat, lon], properties)` pairs.
+    pub fn new(points: impl IntoIterator<Item = ([f64; 2], P)>, truncated: bool) -> Self {
+        FeatureCollection {
+            kind: "FeatureCollection",
+            features: points
+                .into_iter()
+                .map(|([lat, lon], properties)| Feature {
+                    kind: "Feature",
+                    geometry: Point {
+                        kind: "Point",
+                        coordinates: [lon, lat],
+                    },
+                    properties,
+                })
+                .collect(),
+            truncated,
+        }
+    }
+}
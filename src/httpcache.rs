@@ -0,0 +1,103 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+
+use poem::{
+    http::{header, HeaderValue, Method, StatusCode},
+    Endpoint, IntoResponse, Middleware, Request, Response, Result,
+};
+
+/// Lets clients and CDNs cache `GET` responses until the index changes. Each
+/// response carries an ETag derived from the path, the query string and
+/// `version`, which must change whenever the same query could be answered
+/// differently; a request whose `If-None-Match` lists it gets a bodiless 304
+/// without a lookup.
+#[derive(Clone)]
+pub struct HttpCache {
+    cache_control: HeaderValue,
+    version: Arc<dyn Fn() -> u64 + Send + Sync>,
+}
+
+impl HttpCache {
+    /// Responses to authenticated requests are marked `private`, so shared
+    /// caches don't hand them to clients without the key.
+    pub fn new(
+        max_age_secs: u64,
+        private: bool,
+        version: impl Fn() -> u64 + Send + Sync + 'static,
+    ) -> Self {
+        let scope = if private { "private" } else { "public" };
+        HttpCache {
+            cache_control: HeaderValue::from_str(&format!("{}, max-age={}", scope, max_age_secs))
+                .expect("no invalid characters"),
+            version: Arc::new(version),
+        }
+    }
+
+    /// Weak, since the body isn't byte-for-byte the same each time: it may
+    /// be gzipped, and `query_time_us` and `request_id` vary.
+    fn etag(&self, req: &Request) -> HeaderValue {
+        let mut hasher = DefaultHasher::new();
+        ((self.version)(), req.uri().path(), req.uri().query()).hash(&mut hasher);
+        HeaderValue::from_str(&format!("W/\"{:016x}\"", hasher.finish()))
+            .expect("no invalid characters")
+    }
+}
+
+/// Whether `If-None-Match` lists `etag` (or is `*`), comparing weakly.
+fn matches(req: &Request, etag: &HeaderValue) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag.to_str().unwrap_or_default());
+    req.headers()
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+impl<E: Endpoint> Middleware<E> for HttpCache {
+    type Output = HttpCacheEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        HttpCacheEndpoint {
+            inner: ep,
+            cache: self.clone(),
+        }
+    }
+}
+
+pub struct HttpCacheEndpoint<E> {
+    inner: E,
+    cache: HttpCache,
+}
+
+#[poem::async_trait]
+impl<E: Endpoint> Endpoint for HttpCacheEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        if req.method() != Method::GET {
+            return self.inner.call(req).await.map(IntoResponse::into_response);
+        }
+        let etag = self.cache.etag(&req);
+        if matches(&req, &etag) {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(header::ETAG, etag)
+                .header(header::CACHE_CONTROL, self.cache.cache_control.clone())
+                .finish());
+        }
+        let mut resp = self.inner.call(req).await?.into_response();
+        // Handlers set their own Cache-Control on answers that mustn't be
+        // reused, such as timeouts.
+        if resp.status() == StatusCode::OK && !resp.headers().contains_key(header::CACHE_CONTROL) {
+            resp.headers_mut().insert(header::ETAG, etag);
+            resp.headers_mut()
+                .insert(header::CACHE_CONTROL, self.cache.cache_control.clone());
+        }
+        Ok(resp)
+    }
+}
@@ -0,0 +1,172 @@
+use std::{fmt, str::FromStr};
+
+use serde_json::{Map, Value};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{
+    filter::Targets,
+    fmt::{
+        format::Writer,
+        time::{FormatTime, SystemTime},
+        FmtContext, FormatEvent, FormatFields,
+    },
+    layer::{Context, Layer, SubscriberExt},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for Level {
+    fn from(level: LogLevel) -> Level {
+        match level {
+            LogLevel::Error => Level::ERROR,
+            LogLevel::Warn => Level::WARN,
+            LogLevel::Info => Level::INFO,
+            LogLevel::Debug => Level::DEBUG,
+            LogLevel::Trace => Level::TRACE,
+        }
+    }
+}
+
+/// `RUST_LOG` if it is set, in the `target=level,...` syntax. Otherwise the
+/// server's own events at `level`, and other crates' (poem, hyper, ...) at
+/// `level` or warn, whichever is less verbose.
+fn targets(level: LogLevel) -> Targets {
+    let level = Level::from(level);
+    let fallback = || {
+        Targets::new()
+            .with_default(level.min(Level::WARN))
+            .with_target(env!("CARGO_CRATE_NAME"), level)
+    };
+    match std::env::var("RUST_LOG") {
+        Ok(var) => Targets::from_str(&var).unwrap_or_else(|e| {
+            eprintln!("Ignoring `RUST_LOG={:?}`: {}", var, e);
+            fallback()
+        }),
+        Err(_) => fallback(),
+    }
+}
+
+/// Installs the global subscriber, filtered by [`targets`].
+pub fn init(format: LogFormat, level: LogLevel) {
+    let subscriber = tracing_subscriber::fmt().with_max_level(Level::TRACE);
+    match format {
+        LogFormat::Text => subscriber.finish().with(targets(level)).init(),
+        LogFormat::Json => subscriber
+            .event_format(Json)
+            .finish()
+            .with(targets(level))
+            .with(SpanFields)
+            .init(),
+    }
+}
+
+/// Writes each event as one JSON object with `timestamp`, `level`, `target`
+/// and `message` plus any other fields the event and the spans it happened
+/// in recorded, such as `request_id`.
+pub struct Json;
+
+impl<S, N> FormatEvent<S, N> for Json
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+
+        let metadata = event.metadata();
+        let mut fields = Fields(Map::new());
+        fields.0.insert("timestamp".into(), timestamp.into());
+        fields
+            .0
+            .insert("level".into(), metadata.level().as_str().into());
+        fields.0.insert("target".into(), metadata.target().into());
+        for span in ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+        {
+            if let Some(span_fields) = span.extensions().get::<Fields>() {
+                fields.0.extend(span_fields.0.clone());
+            }
+        }
+        event.record(&mut fields);
+        writeln!(writer, "{}", Value::Object(fields.0))
+    }
+}
+
+struct Fields(Map<String, Value>);
+
+impl Visit for Fields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{:?}", value).into());
+    }
+}
+
+/// Keeps each span's fields for [`Json`].
+struct SpanFields;
+
+impl<S> Layer<S> for SpanFields
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut fields = Fields(Map::new());
+            attrs.record(&mut fields);
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<Fields>() {
+                values.record(fields);
+            }
+        }
+    }
+}
@@ -1,41 +1,566 @@
-use futures_util::{SinkExt, StreamExt};
-use std::sync::Arc;
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use std::sync::{Arc, RwLock};
+use tracing::Instrument;
 
-use geo::GeoIndex;
+use admin::AdminMatch;
+use auth::ApiKey;
+use cache::CacheCompression;
+use compression::Gzip;
+use geo::{Bbox, GeoIndex, Match, Metric, OsmType, QueryError, TagFilter};
+use geojson::FeatureCollection;
+use httpcache::HttpCache;
+use logging::{LogFormat, LogLevel};
+use metrics::Metrics;
 use poem::{
     get, handler,
-    listener::TcpListener,
-    middleware::Tracing,
+    http::{header, Method, StatusCode},
+    listener::{AcceptorExt, BoxAcceptor, TcpAcceptor},
+    middleware::{Cors, Tracing},
+    post,
     web::{
-        websocket::{Message, WebSocket},
-        Data,
+        websocket::{CloseCode, Message, WebSocket, WebSocketStream},
+        Data, Json, Path, Query,
     },
-    EndpointExt, Route, Server,
+    Endpoint, EndpointExt, IntoResponse, Route, Server,
 };
+use querycache::QueryCache;
+use ratelimit::RateLimit;
+use requestid::{AssignRequestId, RequestId};
 
 use clap::Parser;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{Hash, Hasher};
 
 #[derive(Debug, Deserialize, Serialize)]
 struct QueryParams {
-    latitude: f32,
-    longitude: f32,
+    /// Decimal degrees, as a number or a string, or a string of degrees,
+    /// minutes and seconds such as `40°44'54.36"N`
+    #[serde(deserialize_with = "dms::latitude")]
+    latitude: f64,
+    #[serde(deserialize_with = "dms::longitude")]
+    longitude: f64,
+    /// Return up to `k` nearest features instead of only the closest one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    k: Option<usize>,
+    /// Return every feature within this many meters; takes precedence over `k`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    radius_m: Option<f64>,
+    /// Ignore features farther than this many meters, overriding `--max-match-distance-m`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_match_distance_m: Option<f64>,
+    /// Only return features at the point itself, i.e. within `tolerance_m`,
+    /// rather than the nearest ones wherever they are
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    exact: bool,
+    /// Return the nearest feature for each value of this tag, e.g. the
+    /// nearest of every `amenity`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    categorize: Option<String>,
+    /// How far from the point an `exact` match may be, in meters (default 1)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tolerance_m: Option<f64>,
+    /// Only match features carrying these tag values, given as an object or
+    /// as `key=value` pairs separated by commas
+    #[serde(default, skip_serializing_if = "TagFilter::is_empty")]
+    filter: TagFilter,
+    /// Preferred languages for `wikipedia` in order, e.g. `de` for the
+    /// `wikipedia:de` tag
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lang: Option<Languages>,
+    /// Also return the feature's `--store-tags` in `all_tags`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    include_tags: bool,
+    /// Unit of `distance` in the response
+    #[serde(default)]
+    units: DistanceUnit,
+    #[serde(default)]
+    format: OutputFormat,
+    /// Shape of the matches in the JSON envelope (default 1)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    v: Option<ResponseVersion>,
+    /// Echoed back in the response, to match answers to queries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+impl QueryParams {
+    /// A plain nearest lookup at the point.
+    fn at(latitude: f64, longitude: f64) -> QueryParams {
+        QueryParams {
+            latitude,
+            longitude,
+            k: None,
+            radius_m: None,
+            max_match_distance_m: None,
+            exact: false,
+            categorize: None,
+            tolerance_m: None,
+            filter: TagFilter::default(),
+            lang: None,
+            include_tags: false,
+            units: DistanceUnit::M,
+            format: OutputFormat::Json,
+            v: None,
+            request_id: None,
+        }
+    }
+}
+
+/// Order of the two numbers in a query sent as a bare coordinate array.
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum CoordOrder {
+    /// `[latitude, longitude]`
+    Latlon,
+    /// `[longitude, latitude]`, as in GeoJSON
+    Lonlat,
+}
+
+fn is_pair(values: &[serde_json::Value]) -> bool {
+    values.len() == 2
+        && values
+            .iter()
+            .all(|value| value.is_number() || value.is_string())
+}
+
+/// Reads a query given either as an object of [`QueryParams`] fields or as a
+/// bare coordinate pair in `order`, whose entries may be DMS strings too.
+fn parse_query(value: serde_json::Value, order: CoordOrder) -> serde_json::Result<QueryParams> {
+    if !matches!(&value, serde_json::Value::Array(values) if is_pair(values)) {
+        return serde_json::from_value(value);
+    }
+    let [a, b]: [serde_json::Value; 2] = serde_json::from_value(value)?;
+    let (latitude, longitude) = match order {
+        CoordOrder::Latlon => (a, b),
+        CoordOrder::Lonlat => (b, a),
+    };
+    Ok(QueryParams::at(
+        dms::latitude(latitude)?,
+        dms::longitude(longitude)?,
+    ))
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum DistanceUnit {
+    #[default]
+    M,
+    Km,
+    Mi,
+}
+
+impl DistanceUnit {
+    fn convert(self, meters: f64) -> f64 {
+        match self {
+            DistanceUnit::M => meters,
+            DistanceUnit::Km => meters / 1000.0,
+            DistanceUnit::Mi => meters / 1609.344,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    /// The `Response` envelope
+    #[default]
+    Json,
+    /// A GeoJSON `FeatureCollection` of the matches
+    Geojson,
+}
+
+/// Schema of the matches in a JSON answer, chosen with `v`, so the full
+/// one can keep growing without breaking clients that expect the first.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(try_from = "u8", into = "u8")]
+enum ResponseVersion {
+    /// Only `wikipedia`, as the first releases answered
+    #[default]
+    V1,
+    /// Every field of `DataResponse`
+    V2,
+}
+
+impl TryFrom<u8> for ResponseVersion {
+    type Error = String;
+
+    fn try_from(v: u8) -> Result<Self, String> {
+        match v {
+            1 => Ok(ResponseVersion::V1),
+            2 => Ok(ResponseVersion::V2),
+            v => Err(format!("unsupported response version {}; use 1 or 2", v)),
+        }
+    }
+}
+
+impl From<ResponseVersion> for u8 {
+    fn from(v: ResponseVersion) -> u8 {
+        match v {
+            ResponseVersion::V1 => 1,
+            ResponseVersion::V2 => 2,
+        }
+    }
+}
+
+/// Language codes in order of preference. Reads from a JSON array or from
+/// codes separated by commas, the form a query string can carry.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "LanguagesRepr")]
+struct Languages(Vec<String>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LanguagesRepr {
+    List(Vec<String>),
+    Text(String),
+}
+
+impl TryFrom<LanguagesRepr> for Languages {
+    type Error = String;
+
+    fn try_from(repr: LanguagesRepr) -> Result<Self, String> {
+        let langs = match repr {
+            LanguagesRepr::List(langs) => langs,
+            LanguagesRepr::Text(text) => text.split(',').map(str::to_string).collect(),
+        };
+        let langs: Vec<String> = langs
+            .into_iter()
+            .map(|lang| lang.trim().to_string())
+            .filter(|lang| !lang.is_empty())
+            .collect();
+        if langs.is_empty() {
+            return Err("lang names no language".to_string());
+        }
+        Ok(Languages(langs))
+    }
+}
+
+/// The language of a plain `wikipedia` tag, which OSM writes as
+/// `<lang>:<title>`.
+fn wikipedia_prefix(title: &str) -> Option<String> {
+    let (lang, _) = title.split_once(':')?;
+    (!lang.is_empty() && lang.bytes().all(|b| b.is_ascii_lowercase() || b == b'-'))
+        .then(|| lang.to_string())
+}
+
+#[derive(Debug, Deserialize)]
+struct BboxParams {
+    min_lat: f64,
+    min_lon: f64,
+    max_lat: f64,
+    max_lon: f64,
+    #[serde(default)]
+    format: OutputFormat,
+}
+
+#[derive(Debug, Deserialize)]
+struct AdminParams {
+    latitude: f64,
+    longitude: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchQuery {
+    points: Vec<serde_json::Value>,
+}
+
+#[derive(clap::Subcommand, Clone, Debug)]
+enum Command {
+    /// Look up a single point, print the JSON response and exit
+    Query {
+        #[arg(long, allow_negative_numbers = true)]
+        lat: f64,
+        #[arg(long, allow_negative_numbers = true)]
+        lon: f64,
+    },
+    /// Build the index from the pbf files, write it to `--cache` and exit
+    Build,
+    /// Time random lookups spread uniformly over the index's bounding box
+    Bench {
+        /// Number of lookups to run
+        #[arg(long, default_value_t = 100_000)]
+        queries: usize,
+        /// Seed for the query points, so runs can be compared
+        #[arg(long, default_value_t = 1)]
+        seed: u64,
+    },
+    /// Write every indexed feature to stdout and exit
+    Export {
+        #[arg(long, value_enum, default_value_t = export::Format::Csv)]
+        format: export::Format,
+    },
 }
 
 /// Pbf query server
-#[derive(Parser, Debug)]
+#[derive(Parser, Clone, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// TOML file providing values for any of these options, keyed by name with
+    /// underscores; command-line flags override it, and it overrides env vars
+    #[arg(long, env)]
+    config: Option<String>,
+
     /// Cached geo-index for faster load time
     #[arg(short, long, env)]
     cache: Option<String>,
 
-    /// Path to pbf file
-    #[arg(short, long, env)]
-    pbf: String,
+    /// Ignore an existing cache and rebuild it from the pbf
+    #[arg(long, env)]
+    force_rebuild: bool,
+
+    /// Compression used when writing a new cache file; both kinds are read back
+    #[arg(long, env, value_enum, default_value_t = CacheCompression::Gzip)]
+    cache_compression: CacheCompression,
+
+    /// Log output format
+    #[arg(long, env, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Level of the server's own log events; `RUST_LOG` takes precedence when set
+    #[arg(long, env, value_enum, default_value_t = LogLevel::Info)]
+    log_level: LogLevel,
+
+    /// Read newline-delimited JSON queries from stdin and answer each on stdout
+    /// instead of starting the server
+    #[arg(long)]
+    stdin: bool,
+
+    /// Path or http:// URL of a pbf file, optionally gzipped; repeat or separate
+    /// with commas to merge several extracts. `name=path` puts the file in a
+    /// separate index served under `/name/`
+    #[arg(short, long, env, value_delimiter = ',', required = true)]
+    pbf: Vec<String>,
+
+    /// Spatial structure to store the index in
+    #[arg(long, env, value_enum, default_value_t = spatial::Kind::Rtree)]
+    index: spatial::Kind,
+
+    /// Cell size of `--index grid`, in degrees. Left unset, cells are sized
+    /// for a couple of segments each on average; see the README for values
+    /// suited to dense cities and to sparse regions
+    #[arg(long, env, value_parser = positive_degrees)]
+    grid_cell_degrees: Option<f64>,
+
+    /// Only index features within this box, given as
+    /// `min_lat,min_lon,max_lat,max_lon`. A way is kept whole if any of its
+    /// nodes is inside. Queries outside the box are answered as outside the
+    /// coverage without a lookup
+    #[arg(long, env, value_parser = parse_bbox)]
+    bbox: Option<Bbox>,
+
+    /// Memory budget in megabytes for building the index. A pbf whose
+    /// single-pass build is estimated to need more is read in several passes
+    /// that keep only the node locations the indexed features use
+    #[arg(long, env)]
+    max_memory_mb: Option<u64>,
+
+    /// How match distances are computed: `haversine` is exact on the sphere,
+    /// `equirect` a faster flat approximation that drifts by about 0.1% for
+    /// matches hundreds of kilometers away
+    #[arg(long, env, value_enum, default_value_t = Metric::Haversine)]
+    distance: Metric,
+
+    /// Also index `boundary=administrative` relations for `/admin` lookups;
+    /// costs an extra pass over each pbf
+    #[arg(long, env)]
+    admin_boundaries: bool,
+
+    /// Directory of OsmChange files (`.osc`, `.osc.gz`) applied on top of the
+    /// pbf in name order, at startup and whenever new ones appear
+    #[arg(long, env)]
+    osc_dir: Option<String>,
+
+    /// Seconds between checks of `--osc-dir` for new change files
+    #[arg(long, env, default_value_t = 60)]
+    osc_poll_secs: u64,
+
+    /// Rebuild the index whenever a local `--pbf` file changes on disk, like
+    /// a SIGHUP would
+    #[arg(long, env)]
+    watch: bool,
+
+    /// Seconds between checks of the `--pbf` files for `--watch`
+    #[arg(long, env, default_value_t = 10)]
+    watch_poll_secs: u64,
+
+    /// Comma-separated tag keys; elements carrying any of them get indexed
+    #[arg(long, env, value_delimiter = ',', default_value = "wikipedia,wikidata")]
+    index_tags: Vec<String>,
+
+    /// Comma-separated tag keys kept on indexed features and returned with
+    /// `include_tags`, on top of the index tags; `*` keeps every tag
+    #[arg(long, env, value_delimiter = ',')]
+    store_tags: Vec<String>,
+
+    /// Address to bind the server to
+    #[arg(long, env, default_value = "0.0.0.0")]
+    host: String,
+
+    /// Port to listen on
+    #[arg(long, env, default_value_t = 3000)]
+    port: u16,
+
+    /// Listen on this Unix domain socket instead of `--host` and `--port`;
+    /// the socket file is removed again on clean shutdown
+    #[arg(long, env)]
+    unix_socket: Option<String>,
+
+    /// Also answer MessagePack queries sent as UDP datagrams to this port on
+    /// `--host`, against the unnamed dataset
+    #[arg(long, env)]
+    udp_port: Option<u16>,
+
+    /// Also answer newline-delimited JSON queries, one line back per line,
+    /// on TCP connections to this port on `--host`, against the unnamed
+    /// dataset
+    #[arg(long, env)]
+    tcp_line_port: Option<u16>,
+
+    /// Seconds to let in-flight connections drain after SIGINT/SIGTERM
+    #[arg(long, env, default_value_t = 10)]
+    shutdown_timeout_secs: u64,
+
+    /// Maximum number of simultaneous WebSocket connections (unlimited if unset)
+    #[arg(long, env)]
+    max_connections: Option<usize>,
+
+    /// Seconds of WebSocket inactivity before the server sends a ping (0 disables)
+    #[arg(long, env, default_value_t = 30)]
+    ws_ping_interval_secs: u64,
+
+    /// Seconds to wait for any frame after a ping before closing the connection
+    #[arg(long, env, default_value_t = 10)]
+    ws_pong_timeout_secs: u64,
+
+    /// Largest WebSocket message accepted, in bytes; bigger ones are answered
+    /// with an error without being parsed
+    #[arg(long, env, default_value_t = 1024 * 1024)]
+    max_message_bytes: usize,
+
+    /// Maximum number of features any query returns: `k` nearest, radius,
+    /// bounding-box and `categorize` queries alike
+    #[arg(long, env, default_value_t = 1000)]
+    max_results: usize,
+
+    /// Maximum number of points in one `/snap` trace
+    #[arg(long, env, default_value_t = 1000)]
+    max_snap_points: usize,
+
+    /// Meters beyond which the nearest feature is not returned as a match
+    /// (unlimited if unset); queries can override it with `max_match_distance_m`
+    #[arg(long, env)]
+    max_match_distance_m: Option<f64>,
+
+    /// Key clients must send as `Authorization: Bearer <key>`; auth is disabled if unset
+    #[arg(long, env)]
+    api_key: Option<String>,
+
+    /// Requests per second allowed from a single client IP (unlimited if unset)
+    #[arg(long, env)]
+    rate_limit_per_sec: Option<f64>,
+
+    /// Origin allowed to call the HTTP endpoints cross-origin (repeatable or
+    /// comma-separated); no CORS headers are sent if unset
+    #[arg(long, env, value_delimiter = ',')]
+    cors_allow_origin: Vec<String>,
+
+    /// Milliseconds a single request may take before it is answered with an error
+    #[arg(long, env, default_value_t = 2000)]
+    query_timeout_ms: u64,
+
+    /// How many lookups of a `POST /query/batch` request run concurrently
+    #[arg(long, env, default_value_t = 8)]
+    batch_parallelism: usize,
+
+    /// Order of the numbers in a query sent as a bare `[a, b]` array instead
+    /// of an object with `latitude` and `longitude`
+    #[arg(long, env, value_enum, default_value_t = CoordOrder::Latlon)]
+    coord_order: CoordOrder,
+
+    /// Number of recent lookup results to keep and answer repeated queries
+    /// from (0 disables the cache)
+    #[arg(long, env, default_value_t = 0)]
+    query_cache_size: usize,
+
+    /// Seconds between log lines reporting the query cache's hit ratio
+    /// (0 disables them)
+    #[arg(long, env, default_value_t = 300)]
+    query_cache_log_secs: u64,
+
+    /// Smallest HTTP response, in bytes, that is gzipped for clients sending
+    /// `Accept-Encoding: gzip`
+    #[arg(long, env, default_value_t = 1024)]
+    compression_min_bytes: usize,
+
+    /// Seconds clients and CDNs may reuse a `GET /query` response before
+    /// revalidating it with its ETag
+    #[arg(long, env, default_value_t = 60)]
+    http_max_age_secs: u64,
 }
 
+/// Server-side limits applied to every query
+#[derive(Clone, Debug)]
+struct QueryConfig {
+    max_results: usize,
+    max_snap_points: usize,
+    max_match_distance_m: Option<f64>,
+    timeout: std::time::Duration,
+    rate_limit: RateLimit,
+    /// Lookups of one `/query/batch` request run at the same time
+    batch_parallelism: usize,
+    /// How to read a query sent as a bare coordinate array
+    coord_order: CoordOrder,
+    /// Results and whether they were truncated, by rounded query
+    query_cache: QueryCache<(Result<QueryData, QueryError>, bool)>,
+}
+
+/// WebSocket keep-alive and connection-limit settings
+#[derive(Clone, Debug)]
+struct WsConfig {
+    ping_interval: Option<std::time::Duration>,
+    pong_timeout: std::time::Duration,
+    max_message_bytes: usize,
+    /// One permit per open connection, sized by `--max-connections`
+    connections: Arc<tokio::sync::Semaphore>,
+    /// Turns true on SIGINT/SIGTERM, so open connections are closed properly
+    /// rather than cut off when draining times out
+    shutdown: tokio::sync::watch::Receiver<bool>,
+    /// Held by every connection; once all clones are gone so are they, which
+    /// is what shutdown waits for
+    open: tokio::sync::mpsc::Sender<()>,
+}
+
+mod admin;
+mod auth;
+mod cache;
+mod compression;
+mod config;
+mod dms;
+mod export;
+mod fetch;
 mod geo;
+mod geojson;
+mod httpcache;
+mod logging;
+mod memory;
+mod metrics;
+mod msgpack;
+mod openapi;
+mod osc;
+mod pbf;
+mod progress;
+mod querycache;
+mod ratelimit;
+mod requestid;
+mod s2;
+mod snap;
+mod spatial;
 
 #[derive(serde::Serialize)]
 struct Response<T> {
@@ -44,107 +569,3071 @@ struct Response<T> {
     data: Option<T>,
     #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
+    /// Set when a multi-result query hit the server-side cap
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+    /// Microseconds the index lookup took, for requests that reached it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    query_time_us: Option<u64>,
+    /// The query's own `request_id`, or else the id the server gave the
+    /// request or message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
 }
 
-#[derive(serde::Serialize)]
+#[derive(Clone, serde::Serialize)]
 struct DataResponse {
-    wikipedia: String,
+    /// The value of the `categorize` tag this is the nearest feature for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wikipedia: Option<String>,
+    /// The language `wikipedia` is in, where known, for queries with `lang`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wikipedia_lang: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wikidata: Option<String>,
+    /// Every configured index tag the feature carries
+    tags: BTreeMap<String, String>,
+    /// `tags` together with the stored tags, for queries with `include_tags`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    all_tags: Option<BTreeMap<String, String>>,
+    #[serde(skip)]
+    extra_tags: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// Article titles by language code, from `wikipedia:<lang>` tags
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    wikipedia_langs: BTreeMap<String, String>,
+    distance_m: f64,
+    /// `distance_m` converted to `distance_unit`
+    distance: f64,
+    distance_unit: DistanceUnit,
+    matched_latitude: f64,
+    matched_longitude: f64,
+    osm_type: OsmType,
+    osm_id: i64,
 }
 
-#[handler]
-async fn ws_handler(data: Data<&Arc<GeoIndex>>, ws: WebSocket) -> impl poem::IntoResponse {
-    // Clone the Arc to avoid lifetime issues
-    let geo_index = data.0.clone();
+impl From<Match> for DataResponse {
+    fn from(found: Match) -> Self {
+        DataResponse {
+            category: None,
+            wikipedia: found.feature.tags.get("wikipedia").cloned(),
+            wikipedia_lang: None,
+            wikidata: found.feature.tags.get("wikidata").cloned(),
+            tags: found.feature.tags,
+            all_tags: None,
+            extra_tags: found.feature.extra_tags,
+            name: found.feature.name,
+            wikipedia_langs: found.feature.wikipedia_langs,
+            distance_m: found.distance_m,
+            distance: found.distance_m,
+            distance_unit: DistanceUnit::M,
+            matched_latitude: found.location[0],
+            matched_longitude: found.location[1],
+            osm_type: found.feature.osm_type,
+            osm_id: found.feature.osm_id,
+        }
+    }
+}
 
-    ws.on_upgrade(move |socket| async move {
-        let (mut sink, mut stream) = socket.split();
+impl DataResponse {
+    /// Answers `wikipedia` in the first of `langs` the feature has a title
+    /// in, from its `wikipedia:<lang>` tag or a plain `wikipedia` tag in that
+    /// language. Failing that, keeps the plain tag, or else takes any
+    /// language the feature has.
+    fn prefer_lang(&mut self, langs: &Languages) {
+        let plain = self
+            .wikipedia
+            .clone()
+            .map(|title| (wikipedia_prefix(&title), title));
+        let preferred = langs.0.iter().find_map(|lang| {
+            let title = self.wikipedia_langs.get(lang).cloned().or_else(|| {
+                plain
+                    .as_ref()
+                    .filter(|(prefix, _)| prefix.as_ref() == Some(lang))
+                    .map(|(_, title)| title.clone())
+            });
+            title.map(|title| (Some(lang.clone()), title))
+        });
+        let chosen = preferred.or(plain).or_else(|| {
+            self.wikipedia_langs
+                .iter()
+                .next()
+                .map(|(lang, title)| (Some(lang.clone()), title.clone()))
+        });
+        if let Some((lang, title)) = chosen {
+            self.wikipedia = Some(title);
+            self.wikipedia_lang = lang;
+        }
+    }
 
-        while let Some(Ok(msg)) = stream.next().await {
-            if let Message::Text(text) = msg {
-                match serde_json::from_str::<QueryParams>(&text) {
-                    Ok(params) => {
-                        let response = if let Some(wikipedia) =
-                            geo_index.find(params.latitude, params.longitude)
-                        {
-                            Response {
-                                success: true,
-                                data: Some(DataResponse { wikipedia }),
-                                error: None,
-                            }
-                        } else {
-                            Response {
-                                success: false,
-                                data: None,
-                                error: Some("No address found".to_string()),
-                            }
-                        };
+    fn convert_distance(&mut self, unit: DistanceUnit) {
+        self.distance = unit.convert(self.distance_m);
+        self.distance_unit = unit;
+    }
+}
 
-                        if let Ok(response_text) = serde_json::to_string(&response) {
-                            if sink.send(Message::Text(response_text)).await.is_err() {
-                                break;
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        let error_response = Response::<DataResponse> {
-                            success: false,
-                            data: None,
-                            error: Some(format!("Invalid query format: {}", e)),
-                        };
+/// A single match for plain queries, a list when the client asked for `k`.
+#[derive(Clone, serde::Serialize)]
+#[serde(untagged)]
+enum QueryData {
+    Single(Box<DataResponse>),
+    Many(Vec<DataResponse>),
+}
 
-                        if let Ok(response_text) = serde_json::to_string(&error_response) {
-                            let _ = sink.send(Message::Text(response_text)).await;
-                        }
-                    }
-                }
+impl QueryData {
+    fn as_mut_slice(&mut self) -> &mut [DataResponse] {
+        match self {
+            QueryData::Single(data) => std::slice::from_mut(&mut **data),
+            QueryData::Many(data) => data.as_mut_slice(),
+        }
+    }
+
+    /// Answers `wikipedia` in the first of `langs` each feature has.
+    fn prefer_lang(&mut self, langs: &Languages) {
+        for data in self.as_mut_slice() {
+            data.prefer_lang(langs);
+        }
+    }
+
+    /// Fills in `all_tags`.
+    fn include_tags(&mut self) {
+        for data in self.as_mut_slice() {
+            let mut all_tags = data.tags.clone();
+            all_tags.extend(std::mem::take(&mut data.extra_tags));
+            data.all_tags = Some(all_tags);
+        }
+    }
+
+    /// Expresses `distance` in `unit`; lookups always measure in meters.
+    fn convert_distances(&mut self, unit: DistanceUnit) {
+        for data in self.as_mut_slice() {
+            data.convert_distance(unit);
+        }
+    }
+
+    fn into_vec(self) -> Vec<DataResponse> {
+        match self {
+            QueryData::Single(data) => vec![*data],
+            QueryData::Many(data) => data,
+        }
+    }
+}
+
+/// A match as `v=1` answers it.
+#[derive(serde::Serialize)]
+struct DataResponseV1 {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wikipedia: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum QueryDataV1 {
+    Single(DataResponseV1),
+    Many(Vec<DataResponseV1>),
+}
+
+impl From<QueryData> for QueryDataV1 {
+    fn from(data: QueryData) -> Self {
+        let v1 = |data: DataResponse| DataResponseV1 {
+            wikipedia: data.wikipedia,
+        };
+        match data {
+            QueryData::Single(data) => QueryDataV1::Single(v1(*data)),
+            QueryData::Many(data) => QueryDataV1::Many(data.into_iter().map(v1).collect()),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum QueryOutput {
+    Envelope(Response<QueryData>),
+    EnvelopeV1(Response<QueryDataV1>),
+    GeoJson(FeatureCollection<DataResponse>),
+}
+
+impl QueryOutput {
+    /// Sets the envelope's `request_id` unless the query brought its own.
+    /// GeoJSON has nowhere to put it.
+    fn or_request_id(mut self, id: &RequestId) -> QueryOutput {
+        let request_id = match &mut self {
+            QueryOutput::Envelope(response) => &mut response.request_id,
+            QueryOutput::EnvelopeV1(response) => &mut response.request_id,
+            QueryOutput::GeoJson(_) => return self,
+        };
+        request_id.get_or_insert_with(|| id.0.clone());
+        self
+    }
+}
+
+impl Response<QueryData> {
+    /// Errors always keep the envelope so clients can read `error`. The
+    /// version only shapes the envelope; GeoJSON has its own.
+    fn render(self, format: OutputFormat, version: ResponseVersion) -> QueryOutput {
+        match (format, self.success, self.data) {
+            (OutputFormat::Geojson, true, Some(data)) => {
+                QueryOutput::GeoJson(FeatureCollection::new(
+                    data.into_vec()
+                        .into_iter()
+                        .map(|data| ([data.matched_latitude, data.matched_longitude], data)),
+                    self.truncated,
+                ))
+            }
+            (_, success, data) if version == ResponseVersion::V1 => {
+                QueryOutput::EnvelopeV1(Response {
+                    success,
+                    data: data.map(QueryDataV1::from),
+                    error: self.error,
+                    truncated: self.truncated,
+                    query_time_us: self.query_time_us,
+                    request_id: self.request_id,
+                })
             }
+            (_, success, data) => QueryOutput::Envelope(Response {
+                success,
+                data,
+                error: self.error,
+                truncated: self.truncated,
+                query_time_us: self.query_time_us,
+                request_id: self.request_id,
+            }),
+        }
+    }
+}
+
+fn many(found: Vec<Match>) -> Option<QueryData> {
+    (!found.is_empty())
+        .then(|| QueryData::Many(found.into_iter().map(DataResponse::from).collect()))
+}
+
+/// How far from the point an `exact` lookup looks unless told otherwise:
+/// enough for coordinates rounded to OSM's seven decimals, but not for a
+/// neighbouring feature.
+const DEFAULT_TOLERANCE_M: f64 = 1.0;
+
+/// Rejects coordinates that can't be on Earth before they reach the index.
+fn validate(params: &QueryParams) -> Result<(), String> {
+    if !params.latitude.is_finite() || !(-90.0..=90.0).contains(&params.latitude) {
+        return Err(format!("latitude {} out of range", params.latitude));
+    }
+    if !params.longitude.is_finite() || !(-180.0..=180.0).contains(&params.longitude) {
+        return Err(format!("longitude {} out of range", params.longitude));
+    }
+    if let Some(radius_m) = params.radius_m {
+        if !radius_m.is_finite() || radius_m < 0.0 {
+            return Err(format!("radius_m {} out of range", radius_m));
+        }
+    }
+    if let Some(max) = params.max_match_distance_m {
+        if !max.is_finite() || max < 0.0 {
+            return Err(format!("max_match_distance_m {} out of range", max));
+        }
+    }
+    if let Some(tolerance_m) = params.tolerance_m {
+        if !params.exact {
+            return Err("tolerance_m only applies with exact=true".to_string());
+        }
+        if !tolerance_m.is_finite() || tolerance_m < 0.0 {
+            return Err(format!("tolerance_m {} out of range", tolerance_m));
+        }
+    }
+    if params.exact && params.radius_m.is_some() {
+        return Err("exact and radius_m can't be combined".to_string());
+    }
+    if params.categorize.is_some() && (params.k.is_some() || params.radius_m.is_some()) {
+        return Err("categorize can't be combined with k or radius_m".to_string());
+    }
+    Ok(())
+}
+
+/// Filters only work on tags the index keeps on its features.
+fn check_filter(geo_index: &GeoIndex, filter: &TagFilter) -> Result<(), String> {
+    match filter.keys().find(|key| !geo_index.keeps_tag(key)) {
+        Some(key) => Err(format!(
+            "cannot filter on {}: the index doesn't keep it; add it to --index-tags or --store-tags",
+            key
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Like filters, categories come from tags the index keeps.
+fn check_categorize(geo_index: &GeoIndex, key: Option<&str>) -> Result<(), String> {
+    match key {
+        Some(key) if !geo_index.keeps_tag(key) => Err(format!(
+            "cannot categorize by {}: the index doesn't keep it; add it to --index-tags or --store-tags",
+            key
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn micros(elapsed: std::time::Duration) -> u64 {
+    elapsed.as_micros().try_into().unwrap_or(u64::MAX)
+}
+
+/// Runs `lookup`, also returning how many microseconds it took.
+fn timed<T>(lookup: impl FnOnce() -> T) -> (T, u64) {
+    let start = std::time::Instant::now();
+    let result = lookup();
+    (result, micros(start.elapsed()))
+}
+
+fn query(
+    geo_index: &GeoIndex,
+    config: &QueryConfig,
+    metrics: &Metrics,
+    params: &QueryParams,
+) -> Response<QueryData> {
+    if let Err(error) = validate(params)
+        .and_then(|()| check_filter(geo_index, &params.filter))
+        .and_then(|()| check_categorize(geo_index, params.categorize.as_deref()))
+    {
+        return Response {
+            success: false,
+            data: None,
+            error: Some(error),
+            truncated: false,
+            query_time_us: None,
+            request_id: params.request_id.clone(),
+        };
+    }
+
+    let max_distance_m = if params.exact {
+        params.tolerance_m.unwrap_or(DEFAULT_TOLERANCE_M)
+    } else {
+        params
+            .max_match_distance_m
+            .or(config.max_match_distance_m)
+            .unwrap_or(f64::INFINITY)
+    };
+    let found = |found: Vec<Match>| {
+        many(found).ok_or_else(|| geo_index.miss(params.latitude, params.longitude))
+    };
+    let lookup = || match (params.radius_m, params.k) {
+        _ if params.categorize.is_some() => {
+            let key = params.categorize.as_deref().unwrap_or_default();
+            // One category more than allowed tells whether there were more.
+            let nearest = geo_index.find_nearest_per_category(
+                params.latitude,
+                params.longitude,
+                key,
+                config.max_results + 1,
+                max_distance_m,
+                &params.filter,
+            );
+            let mut nearest: Vec<(String, Match)> = nearest.into_iter().collect();
+            nearest.sort_by(|(_, a), (_, b)| geo::by_distance(a, b));
+            let truncated = nearest.len() > config.max_results;
+            nearest.truncate(config.max_results);
+            let data = (!nearest.is_empty()).then(|| {
+                QueryData::Many(
+                    nearest
+                        .into_iter()
+                        .map(|(category, found)| DataResponse {
+                            category: Some(category),
+                            ..found.into()
+                        })
+                        .collect(),
+                )
+            });
+            (
+                data.ok_or_else(|| geo_index.miss(params.latitude, params.longitude)),
+                truncated,
+            )
+        }
+        (Some(radius_m), _) => {
+            // One feature more than allowed tells whether there were more.
+            let mut matches = geo_index.find_within_radius(
+                params.latitude,
+                params.longitude,
+                radius_m,
+                &params.filter,
+                config.max_results + 1,
+            );
+            let truncated = matches.len() > config.max_results;
+            matches.truncate(config.max_results);
+            (found(matches), truncated)
+        }
+        (None, Some(k)) => {
+            // Past the cap, one feature more than allowed tells whether there
+            // were more.
+            let mut matches = geo_index.find_k_nearest(
+                params.latitude,
+                params.longitude,
+                k.min(config.max_results + 1),
+                &params.filter,
+            );
+            matches.retain(|found| found.distance_m <= max_distance_m);
+            let truncated = matches.len() > config.max_results;
+            matches.truncate(config.max_results);
+            (found(matches), truncated)
+        }
+        (None, None) => {
+            let found = geo_index
+                .find(
+                    params.latitude,
+                    params.longitude,
+                    max_distance_m,
+                    &params.filter,
+                )
+                .map(|found| QueryData::Single(Box::new(found.into())));
+            (found, false)
+        }
+    };
+    let start = std::time::Instant::now();
+    let key = querycache::Key::new(
+        params.latitude,
+        params.longitude,
+        params.k,
+        params.radius_m,
+        max_distance_m,
+        &params.filter,
+        params.categorize.as_deref(),
+    );
+    let cache = &config.query_cache;
+    let cached = cache.get(geo_index.revision(), &key);
+    if cache.is_enabled() {
+        metrics.observe_query_cache(cached.is_some());
+    }
+    let (mut data, truncated) = cached.unwrap_or_else(|| {
+        let result = lookup();
+        cache.insert(geo_index.revision(), key, result.clone());
+        result
+    });
+    let elapsed = start.elapsed();
+    metrics.observe_query(elapsed, data.is_ok());
+    if params.exact {
+        data = data.map_err(|error| match error {
+            QueryError::EmptyIndex => error,
+            _ => QueryError::NothingAtPoint,
+        });
+    }
+    if let Ok(data) = &mut data {
+        if let Some(lang) = &params.lang {
+            data.prefer_lang(lang);
+        }
+        if params.include_tags {
+            data.include_tags();
         }
+        data.convert_distances(params.units);
+    }
+    match data {
+        Ok(data) => Response {
+            success: true,
+            data: Some(data),
+            error: None,
+            truncated,
+            query_time_us: Some(micros(elapsed)),
+            request_id: params.request_id.clone(),
+        },
+        Err(error) => Response {
+            success: false,
+            data: None,
+            error: Some(error.to_string()),
+            truncated,
+            query_time_us: Some(micros(elapsed)),
+            request_id: params.request_id.clone(),
+        },
+    }
+}
+
+fn invalid_query(e: impl std::fmt::Display) -> Response<QueryData> {
+    Response {
+        success: false,
+        data: None,
+        error: Some(format!("Invalid query format: {}", e)),
+        truncated: false,
+        query_time_us: None,
+        request_id: None,
+    }
+}
+
+/// Runs a lookup on the blocking pool so a slow one can't stall the runtime,
+/// giving up after the configured timeout. The lookup itself keeps running to
+/// completion in the background; only the caller stops waiting for it.
+async fn with_timeout<T: Send + 'static>(
+    config: &QueryConfig,
+    lookup: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, Response<QueryData>> {
+    // Keeps what the lookup logs inside the caller's span.
+    let span = tracing::Span::current();
+    let lookup = move || span.in_scope(lookup);
+    match tokio::time::timeout(config.timeout, tokio::task::spawn_blocking(lookup)).await {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err(e)) => Err(Response {
+            success: false,
+            data: None,
+            error: Some(format!("Query failed: {}", e)),
+            truncated: false,
+            query_time_us: None,
+            request_id: None,
+        }),
+        Err(_) => Err(Response {
+            success: false,
+            data: None,
+            error: Some(format!(
+                "Query timed out after {} ms",
+                config.timeout.as_millis()
+            )),
+            truncated: false,
+            query_time_us: None,
+            request_id: None,
+        }),
+    }
+}
+
+/// Each point is parsed on its own so one bad entry doesn't fail the whole batch.
+fn query_batch(
+    geo_index: &GeoIndex,
+    config: &QueryConfig,
+    metrics: &Metrics,
+    points: Vec<serde_json::Value>,
+) -> Vec<QueryOutput> {
+    points
+        .into_iter()
+        .map(|point| match parse_query(point, config.coord_order) {
+            Ok(params) => query(geo_index, config, metrics, &params)
+                .render(params.format, params.v.unwrap_or_default()),
+            Err(e) => QueryOutput::Envelope(invalid_query(e)),
+        })
+        .collect()
+}
+
+/// [`query`] bounded by the query timeout; shared by the `/query` variants.
+/// An `Err` is the error answer to a lookup that timed out or failed, which
+/// mustn't be cached like an answer of the index.
+async fn serve_query(
+    geo_index: Arc<GeoIndex>,
+    config: &QueryConfig,
+    metrics: &Arc<Metrics>,
+    params: QueryParams,
+) -> Result<QueryOutput, QueryOutput> {
+    let query_config = config.clone();
+    let metrics = metrics.clone();
+    let (format, version) = (params.format, params.v.unwrap_or_default());
+    let request_id = params.request_id.clone();
+    let response = with_timeout(config, move || {
+        query(&geo_index, &query_config, &metrics, &params)
     })
+    .await;
+    response
+        .map(|response| response.render(format, version))
+        .map_err(|response| {
+            Response {
+                request_id,
+                ..response
+            }
+            .render(format, version)
+        })
 }
 
-#[tokio::main]
-async fn main() -> Result<(), std::io::Error> {
-    let args = Args::parse();
-    if std::env::var_os("RUST_LOG").is_none() {
-        std::env::set_var("RUST_LOG", "poem=debug");
+#[handler]
+async fn query_handler(
+    index: Data<&IndexHandle>,
+    config: Data<&QueryConfig>,
+    metrics: Data<&Arc<Metrics>>,
+    request_id: Data<&RequestId>,
+    Query(params): Query<QueryParams>,
+) -> poem::Response {
+    match serve_query(index.load(), &config, &metrics, params).await {
+        Ok(output) => Json(output.or_request_id(&request_id)).into_response(),
+        Err(output) => Json(output.or_request_id(&request_id))
+            .with_header(header::CACHE_CONTROL, "no-store")
+            .into_response(),
     }
-    tracing_subscriber::fmt::init();
+}
 
-    let geo = match args.cache {
-        Some(path) => {
-            //check if file path exists
-            match std::fs::File::open(&path) {
-                Ok(file) => {
-                    let start = std::time::Instant::now();
-                    println!("load index from file");
-                    let geo: GeoIndex = bincode::deserialize_from(file).unwrap();
-                    println!("Loaded index in {}ms", start.elapsed().as_millis());
-                    geo
-                }
-                Err(_e) => {
-                    println!("cannot load index => rebuild");
-                    let mut geo = GeoIndex::new();
-                    geo.build(&args.pbf);
-                    // save geo to file
-                    std::fs::write(&path, bincode::serialize(&geo).unwrap())
-                        .expect("Unable to write file");
-                    geo
+/// `POST /query` with the parameters as a JSON body, which keeps coordinates
+/// out of access logs. A malformed body gets the usual error envelope.
+#[handler]
+async fn query_post_handler(
+    index: Data<&IndexHandle>,
+    config: Data<&QueryConfig>,
+    metrics: Data<&Arc<Metrics>>,
+    request_id: Data<&RequestId>,
+    body: poem::Result<Json<serde_json::Value>>,
+) -> Json<QueryOutput> {
+    let params = body
+        .map_err(|e| e.to_string())
+        .and_then(|Json(body)| parse_query(body, config.coord_order).map_err(|e| e.to_string()));
+    let output = match params {
+        Ok(params) => serve_query(index.load(), &config, &metrics, params)
+            .await
+            .unwrap_or_else(|e| e),
+        Err(e) => QueryOutput::Envelope(invalid_query(e)),
+    };
+    Json(output.or_request_id(&request_id))
+}
+
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum BatchOutput {
+    Answers(Vec<QueryOutput>),
+    /// The body itself wasn't an array of queries.
+    Invalid(Response<QueryData>),
+}
+
+/// `POST /query/batch`: answers a JSON array of queries with an array of
+/// responses in the same order. Up to `--batch-parallelism` lookups run at
+/// once, each under its own timeout, and an entry that doesn't parse gets an
+/// error response in its slot.
+#[handler]
+async fn batch_handler(
+    index: Data<&IndexHandle>,
+    config: Data<&QueryConfig>,
+    metrics: Data<&Arc<Metrics>>,
+    points: poem::Result<Json<Vec<serde_json::Value>>>,
+) -> Json<BatchOutput> {
+    let points = match points {
+        Ok(Json(points)) => points,
+        Err(e) => return Json(BatchOutput::Invalid(invalid_query(e))),
+    };
+    // One snapshot for the whole batch, even across a reload.
+    let geo_index = index.load();
+    let answers = futures_util::stream::iter(points)
+        .map(|point| {
+            let geo_index = geo_index.clone();
+            let config = config.0;
+            let metrics = metrics.0;
+            async move {
+                match parse_query(point, config.coord_order) {
+                    Ok(params) => serve_query(geo_index, config, metrics, params)
+                        .await
+                        .unwrap_or_else(|e| e),
+                    Err(e) => QueryOutput::Envelope(invalid_query(e)),
                 }
             }
+        })
+        .buffered(config.batch_parallelism.max(1))
+        .collect()
+        .await;
+    Json(BatchOutput::Answers(answers))
+}
+
+/// Candidates per point a `/snap` trace considers unless told otherwise.
+const DEFAULT_SNAP_CANDIDATES: usize = 5;
+
+/// Choosing among the candidates takes time with their square.
+const MAX_SNAP_CANDIDATES: usize = 20;
+
+#[derive(Debug, Deserialize)]
+struct SnapParams {
+    /// The trace in order, each point a coordinate pair in `--coord-order`
+    /// or an object with `latitude` and `longitude`
+    points: Vec<serde_json::Value>,
+    /// How many of the nearest features each point chooses among
+    #[serde(default)]
+    candidates: Option<usize>,
+    /// Meters of cost per meter consecutive matches jump farther or less far
+    /// than their points moved; 0 matches every point on its own
+    #[serde(default)]
+    smoothing: f64,
+    #[serde(default)]
+    max_match_distance_m: Option<f64>,
+    #[serde(default)]
+    filter: TagFilter,
+    #[serde(default)]
+    lang: Option<Languages>,
+    #[serde(default)]
+    units: DistanceUnit,
+    #[serde(default)]
+    request_id: Option<String>,
+}
+
+/// The trace's points, once its parameters are checked.
+fn validate_snap(
+    geo_index: &GeoIndex,
+    config: &QueryConfig,
+    params: &SnapParams,
+) -> Result<Vec<[f64; 2]>, String> {
+    if params.points.len() > config.max_snap_points {
+        return Err(format!(
+            "{} points exceed the limit of {}",
+            params.points.len(),
+            config.max_snap_points
+        ));
+    }
+    if let Some(candidates) = params.candidates {
+        if !(1..=MAX_SNAP_CANDIDATES).contains(&candidates) {
+            return Err(format!(
+                "candidates must be between 1 and {}",
+                MAX_SNAP_CANDIDATES
+            ));
         }
-        None => {
-            let mut geo = GeoIndex::new();
-            geo.build(&args.pbf);
-            geo
+    }
+    if !params.smoothing.is_finite() || params.smoothing < 0.0 {
+        return Err(format!("smoothing {} out of range", params.smoothing));
+    }
+    if let Some(max) = params.max_match_distance_m {
+        if !max.is_finite() || max < 0.0 {
+            return Err(format!("max_match_distance_m {} out of range", max));
         }
-    };
+    }
+    check_filter(geo_index, &params.filter)?;
+    params
+        .points
+        .iter()
+        .enumerate()
+        .map(|(i, point)| {
+            let point = parse_query(point.clone(), config.coord_order)
+                .map_err(|e| e.to_string())
+                .and_then(|at| {
+                    validate(&QueryParams::at(at.latitude, at.longitude))?;
+                    Ok([at.latitude, at.longitude])
+                });
+            point.map_err(|e| format!("point {}: {}", i, e))
+        })
+        .collect()
+}
 
-    let app = Route::new()
-        .at("/", get(ws_handler))
-        .data(Arc::new(geo))
-        .with(Tracing);
-    Server::new(TcpListener::bind("0.0.0.0:3000"))
-        .name("Fast-pbf-server")
-        .run(app)
-        .await
+/// `POST /snap`: matches each point of a trace to a feature, in the order
+/// of the points, with `null` where none is near enough.
+#[handler]
+async fn snap_handler(
+    index: Data<&IndexHandle>,
+    config: Data<&QueryConfig>,
+    metrics: Data<&Arc<Metrics>>,
+    Data(request_id): Data<&RequestId>,
+    body: poem::Result<Json<SnapParams>>,
+) -> Json<Response<Vec<Option<DataResponse>>>> {
+    let geo_index = index.load();
+    let failed = |error: Option<String>, request_id: String| {
+        Json(Response {
+            success: false,
+            data: None,
+            error,
+            truncated: false,
+            query_time_us: None,
+            request_id: Some(request_id),
+        })
+    };
+    let checked = body
+        .map_err(|e| format!("Invalid query format: {}", e))
+        .and_then(|Json(params)| {
+            let points = validate_snap(&geo_index, &config, &params)?;
+            Ok((params, points))
+        });
+    let (params, points) = match checked {
+        Ok(checked) => checked,
+        Err(error) => return failed(Some(error), request_id.0.clone()),
+    };
+    let request_id = params.request_id.unwrap_or_else(|| request_id.0.clone());
+    let max_distance_m = params
+        .max_match_distance_m
+        .or(config.max_match_distance_m)
+        .unwrap_or(f64::INFINITY);
+    let candidates = params.candidates.unwrap_or(DEFAULT_SNAP_CANDIDATES);
+    let (smoothing, filter) = (params.smoothing, params.filter);
+    let snapped = with_timeout(&config, move || {
+        timed(|| {
+            snap::snap(
+                &geo_index,
+                &points,
+                candidates,
+                max_distance_m,
+                smoothing,
+                &filter,
+            )
+        })
+    })
+    .await;
+    let (snapped, query_time_us) = match snapped {
+        Ok(snapped) => snapped,
+        Err(response) => return failed(response.error, request_id),
+    };
+    metrics.observe_query(
+        std::time::Duration::from_micros(query_time_us),
+        snapped.iter().any(Option::is_some),
+    );
+    let data = snapped
+        .into_iter()
+        .map(|found| {
+            found.map(|found| {
+                let mut data = DataResponse::from(found);
+                if let Some(lang) = &params.lang {
+                    data.prefer_lang(lang);
+                }
+                data.convert_distance(params.units);
+                data
+            })
+        })
+        .collect();
+    Json(Response {
+        success: true,
+        data: Some(data),
+        error: None,
+        truncated: false,
+        query_time_us: Some(query_time_us),
+        request_id: Some(request_id),
+    })
+}
+
+fn validate_bbox(params: &BboxParams) -> Result<(), String> {
+    for (name, value, limit) in [
+        ("min_lat", params.min_lat, 90.0),
+        ("max_lat", params.max_lat, 90.0),
+        ("min_lon", params.min_lon, 180.0),
+        ("max_lon", params.max_lon, 180.0),
+    ] {
+        if !value.is_finite() || !(-limit..=limit).contains(&value) {
+            return Err(format!("{} {} out of range", name, value));
+        }
+    }
+    if params.min_lat > params.max_lat {
+        return Err(format!(
+            "min_lat {} is greater than max_lat {}",
+            params.min_lat, params.max_lat
+        ));
+    }
+    Ok(())
+}
+
+#[handler]
+async fn bbox_handler(
+    index: Data<&IndexHandle>,
+    config: Data<&QueryConfig>,
+    request_id: Data<&RequestId>,
+    Query(params): Query<BboxParams>,
+) -> Json<QueryOutput> {
+    if let Err(error) = validate_bbox(&params) {
+        let output = QueryOutput::Envelope(Response {
+            success: false,
+            data: None,
+            error: Some(error),
+            truncated: false,
+            query_time_us: None,
+            request_id: None,
+        });
+        return Json(output.or_request_id(&request_id));
+    }
+
+    let geo_index = index.load();
+    let max_results = config.max_results;
+    let found = with_timeout(config.0, move || {
+        timed(|| {
+            // One feature more than allowed tells whether there were more.
+            geo_index.find_in_bbox(
+                params.min_lat,
+                params.min_lon,
+                params.max_lat,
+                params.max_lon,
+                max_results + 1,
+            )
+        })
+    })
+    .await;
+    let (mut found, query_time_us) = match found {
+        Ok(found) => found,
+        Err(response) => return Json(QueryOutput::Envelope(response).or_request_id(&request_id)),
+    };
+    let truncated = found.len() > config.max_results;
+    found.truncate(config.max_results);
+    let response = Response {
+        success: true,
+        data: Some(QueryData::Many(
+            found.into_iter().map(DataResponse::from).collect(),
+        )),
+        error: None,
+        truncated,
+        query_time_us: Some(query_time_us),
+        request_id: None,
+    };
+    Json(
+        response
+            .render(params.format, ResponseVersion::V2)
+            .or_request_id(&request_id),
+    )
+}
+
+#[handler]
+async fn admin_handler(
+    index: Data<&IndexHandle>,
+    config: Data<&QueryConfig>,
+    Data(request_id): Data<&RequestId>,
+    Query(params): Query<AdminParams>,
+) -> Json<Response<Vec<AdminMatch>>> {
+    let error = |error: String| {
+        Json(Response {
+            success: false,
+            data: None,
+            error: Some(error),
+            truncated: false,
+            query_time_us: None,
+            request_id: Some(request_id.0.clone()),
+        })
+    };
+    for (name, value, limit) in [
+        ("latitude", params.latitude, 90.0),
+        ("longitude", params.longitude, 180.0),
+    ] {
+        if !value.is_finite() || !(-limit..=limit).contains(&value) {
+            return error(format!("{} {} out of range", name, value));
+        }
+    }
+    let geo_index = index.load();
+    if !geo_index.has_admin_boundaries() {
+        return error(
+            "Administrative boundaries are not indexed; start with --admin-boundaries".to_string(),
+        );
+    }
+
+    let found = with_timeout(config.0, move || {
+        timed(|| geo_index.locate_admin(params.latitude, params.longitude))
+    })
+    .await;
+    match found {
+        Ok((found, query_time_us)) => Json(Response {
+            success: !found.is_empty(),
+            error: found.is_empty().then(|| "No boundary found".to_string()),
+            data: (!found.is_empty()).then_some(found),
+            truncated: false,
+            query_time_us: Some(query_time_us),
+            request_id: Some(request_id.0.clone()),
+        }),
+        Err(response) => error(response.error.unwrap_or_default()),
+    }
+}
+
+#[handler]
+fn osm_handler(
+    index: Data<&IndexHandle>,
+    Data(request_id): Data<&RequestId>,
+    Path((osm_type, osm_id)): Path<(String, i64)>,
+) -> Json<Response<QueryData>> {
+    let kind = match serde_json::from_value::<OsmType>(serde_json::Value::String(osm_type.clone()))
+    {
+        Ok(kind) => kind,
+        Err(_) => {
+            return Json(Response {
+                success: false,
+                data: None,
+                error: Some(format!("unknown OSM type {}", osm_type)),
+                truncated: false,
+                query_time_us: None,
+                request_id: Some(request_id.0.clone()),
+            })
+        }
+    };
+    let (found, query_time_us) = timed(|| index.load().find_by_osm_id(kind, osm_id));
+    Json(match found {
+        Some(found) => Response {
+            success: true,
+            data: Some(QueryData::Single(Box::new(found.into()))),
+            error: None,
+            truncated: false,
+            query_time_us: Some(query_time_us),
+            request_id: Some(request_id.0.clone()),
+        },
+        None => Response {
+            success: false,
+            data: None,
+            error: Some(format!("{}/{} is not in the index", osm_type, osm_id)),
+            truncated: false,
+            query_time_us: Some(query_time_us),
+            request_id: Some(request_id.0.clone()),
+        },
+    })
+}
+
+#[handler]
+fn openapi_handler() -> Json<serde_json::Value> {
+    Json(openapi::spec())
+}
+
+#[handler]
+fn docs_handler() -> poem::web::Html<&'static str> {
+    poem::web::Html(openapi::DOCS_HTML)
+}
+
+#[handler]
+fn stats_handler(index: Data<&IndexHandle>) -> Json<StatsResponse> {
+    let geo = index.load();
+    Json(StatsResponse {
+        stats: geo.stats().clone(),
+        memory_bytes: geo.heap_bytes(),
+    })
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    #[serde(flatten)]
+    stats: geo::IndexStats,
+    /// Rough estimate of the heap the index occupies
+    memory_bytes: usize,
+}
+
+#[handler]
+fn metrics_handler(metrics: Data<&Arc<Metrics>>) -> impl IntoResponse {
+    metrics
+        .render()
+        .with_content_type("text/plain; version=0.0.4")
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct IndexInfo {
+    features: usize,
+    build_duration_ms: u128,
+    /// Whether the index was read from `--cache` rather than built
+    from_cache: bool,
+    /// When the live index was loaded, in seconds since the epoch
+    loaded_at_unix: u64,
+    /// The files it was built from, as they were when it was loaded
+    pbf: Vec<PbfInfo>,
+}
+
+impl IndexInfo {
+    fn new(args: &Args, geo: &GeoIndex, build_duration: std::time::Duration) -> IndexInfo {
+        IndexInfo {
+            features: geo.len(),
+            build_duration_ms: build_duration.as_millis(),
+            from_cache: geo.is_cached(),
+            loaded_at_unix: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            pbf: args.pbf.iter().map(|pbf| PbfInfo::new(pbf)).collect(),
+        }
+    }
+}
+
+/// One `--pbf` file. URLs are kept as given, without size or time.
+#[derive(Clone, Debug, Serialize)]
+struct PbfInfo {
+    /// Absolute, with symlinks resolved
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size_bytes: Option<u64>,
+    /// Modification time in seconds since the epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified_unix: Option<u64>,
+}
+
+impl PbfInfo {
+    fn new(pbf: &str) -> PbfInfo {
+        let (path, metadata) = if fetch::is_url(pbf) {
+            (pbf.to_string(), None)
+        } else {
+            let path = std::fs::canonicalize(pbf)
+                .map_or_else(|_| pbf.to_string(), |path| path.display().to_string());
+            (path, std::fs::metadata(pbf).ok())
+        };
+        PbfInfo {
+            path,
+            size_bytes: metadata.as_ref().map(std::fs::Metadata::len),
+            modified_unix: metadata
+                .and_then(|metadata| metadata.modified().ok())
+                .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|since| since.as_secs()),
+        }
+    }
+}
+
+/// Populated once the index is loaded; `None` while it is still building.
+#[derive(Clone, Default)]
+struct Health(Arc<RwLock<Option<IndexInfo>>>);
+
+impl Health {
+    fn get(&self) -> Option<IndexInfo> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn is_ready(&self) -> bool {
+        self.0.read().unwrap().is_some()
+    }
+
+    fn set(&self, info: IndexInfo) {
+        *self.0.write().unwrap() = Some(info);
+    }
+}
+
+/// The live index, swapped wholesale on reload. Requests work on a clone of
+/// the inner `Arc`, so in-flight ones finish against the index they started with.
+#[derive(Clone)]
+struct IndexHandle(Arc<RwLock<Arc<GeoIndex>>>);
+
+impl IndexHandle {
+    fn new(geo: GeoIndex) -> IndexHandle {
+        IndexHandle(Arc::new(RwLock::new(Arc::new(geo))))
+    }
+
+    fn load(&self) -> Arc<GeoIndex> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn store(&self, geo: GeoIndex) {
+        *self.0.write().unwrap() = Arc::new(geo);
+    }
+}
+
+#[handler]
+fn health_handler(health: Data<&Health>) -> poem::Response {
+    match health.get() {
+        Some(info) => Json(serde_json::json!({
+            "status": "ready",
+            "version": env!("CARGO_PKG_VERSION"),
+            "index": info,
+        }))
+        .into_response(),
+        None => Json(serde_json::json!({
+            "status": "loading",
+            "version": env!("CARGO_PKG_VERSION"),
+        }))
+        .with_status(StatusCode::SERVICE_UNAVAILABLE)
+        .into_response(),
+    }
+}
+
+/// Liveness: answering at all shows the runtime isn't stuck.
+#[handler]
+fn livez_handler() -> &'static str {
+    "ok"
+}
+
+/// The health of every dataset's index.
+#[derive(Clone)]
+struct Readiness(Vec<Health>);
+
+/// Readiness: 503 until every index is loaded, so no traffic is routed here
+/// before it can be answered.
+#[handler]
+fn readyz_handler(Data(readiness): Data<&Readiness>) -> poem::Response {
+    if readiness.0.iter().all(Health::is_ready) {
+        "ok".into_response()
+    } else {
+        "loading"
+            .with_status(StatusCode::SERVICE_UNAVAILABLE)
+            .into_response()
+    }
+}
+
+/// Answers one WebSocket message: an array or `{"points": [...]}` is a batch,
+/// anything else a single query.
+fn handle_request(
+    geo_index: &GeoIndex,
+    config: &QueryConfig,
+    metrics: &Metrics,
+    request: serde_json::Value,
+) -> serde_json::Result<serde_json::Value> {
+    match request {
+        serde_json::Value::Array(points) if !is_pair(&points) => {
+            serde_json::to_value(query_batch(geo_index, config, metrics, points))
+        }
+        serde_json::Value::Object(ref fields) if fields.contains_key("points") => {
+            match serde_json::from_value::<BatchQuery>(request) {
+                Ok(batch) => {
+                    serde_json::to_value(query_batch(geo_index, config, metrics, batch.points))
+                }
+                Err(e) => serde_json::to_value(invalid_query(e)),
+            }
+        }
+        request => match parse_query(request, config.coord_order) {
+            Ok(params) => serde_json::to_value(
+                query(geo_index, config, metrics, &params)
+                    .render(params.format, params.v.unwrap_or_default()),
+            ),
+            Err(e) => serde_json::to_value(invalid_query(e)),
+        },
+    }
+}
+
+/// Gives a WebSocket answer the message's id unless the query brought its
+/// own. Batches and GeoJSON have no envelope to put it in.
+fn tag_request_id(response: &mut serde_json::Value, id: &str) {
+    if let serde_json::Value::Object(fields) = response {
+        if fields.contains_key("success") && !fields.contains_key("request_id") {
+            fields.insert("request_id".to_string(), id.into());
+        }
+    }
+}
+
+/// [`handle_request`] bounded by the query timeout.
+async fn answer(
+    geo_index: Arc<GeoIndex>,
+    config: &QueryConfig,
+    metrics: &Arc<Metrics>,
+    request: serde_json::Value,
+) -> serde_json::Result<serde_json::Value> {
+    let query_config = config.clone();
+    let metrics = metrics.clone();
+    with_timeout(config, move || {
+        handle_request(&geo_index, &query_config, &metrics, request)
+    })
+    .await
+    .unwrap_or_else(serde_json::to_value)
+}
+
+#[handler]
+async fn ws_handler(
+    index: Data<&IndexHandle>,
+    config: Data<&QueryConfig>,
+    metrics: Data<&Arc<Metrics>>,
+    ws_config: Data<&WsConfig>,
+    api_key: Data<&ApiKey>,
+    req: &poem::Request,
+    ws: WebSocket,
+) -> impl poem::IntoResponse {
+    // Clone the Arc to avoid lifetime issues
+    let index = index.0.clone();
+    let config = config.0.clone();
+    let metrics = metrics.0.clone();
+    let ws_config = ws_config.0.clone();
+    let api_key = api_key.0.clone();
+    // Clients that can't set headers on the handshake (browsers) send the key
+    // as `{"api_key": "..."}` in their first message instead.
+    let mut authenticated = api_key.accepts_request(req);
+    let ip = ratelimit::client_ip(req);
+
+    ws.on_upgrade(move |socket| async move {
+        let (mut sink, mut stream) = socket.split();
+        // Held for the lifetime of the connection.
+        let Ok(_permit) = ws_config.connections.clone().try_acquire_owned() else {
+            metrics.ws_rejected();
+            close(
+                &mut sink,
+                &mut stream,
+                CloseCode::Policy,
+                "too many connections",
+            )
+            .await;
+            return;
+        };
+        let mut shutdown = ws_config.shutdown.clone();
+        let _open = ws_config.open.clone();
+        let _connection = metrics.ws_connected();
+        let mut last_seen = tokio::time::Instant::now();
+        let mut ping_sent: Option<tokio::time::Instant> = None;
+
+        loop {
+            // Ping once the connection has been idle for the interval, then
+            // give the client `pong_timeout` to show any sign of life.
+            let deadline = match (ping_sent, ws_config.ping_interval) {
+                (Some(sent), _) => Some(sent + ws_config.pong_timeout),
+                (None, Some(interval)) => Some(last_seen + interval),
+                (None, None) => None,
+            };
+            let heartbeat = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let msg = tokio::select! {
+                msg = stream.next() => match msg {
+                    Some(Ok(msg)) => msg,
+                    // Not yet handled: a frame that fails to read, including
+                    // a text frame that isn't UTF-8, ends the stream poem
+                    // hands us, so the connection is closed here instead of
+                    // kept open. The client at least learns why.
+                    Some(Err(e)) => {
+                        let (code, reason) = match unreadable_frame(&e) {
+                            Some((reply, code, reason)) => {
+                                let _ = sink.send(reply).await;
+                                (code, reason)
+                            }
+                            None => (CloseCode::Protocol, "invalid frame"),
+                        };
+                        close(&mut sink, &mut stream, code, reason).await;
+                        break;
+                    }
+                    None => break,
+                },
+                // The borrow `wait_for` returns isn't `Send`, so it's dropped
+                // right away.
+                _ = async { drop(shutdown.wait_for(|&down| down).await) } => {
+                    close(&mut sink, &mut stream, CloseCode::Away, "server shutting down").await;
+                    break;
+                }
+                _ = heartbeat => {
+                    if ping_sent.is_some() {
+                        close(&mut sink, &mut stream, CloseCode::Away, "ping timeout").await;
+                        break;
+                    }
+                    if sink.send(Message::Ping(Vec::new())).await.is_err() {
+                        break;
+                    }
+                    ping_sent = Some(tokio::time::Instant::now());
+                    continue;
+                }
+            };
+            last_seen = tokio::time::Instant::now();
+            ping_sent = None;
+
+            // Text frames speak JSON, binary frames MessagePack. Oversized
+            // messages (`None`) aren't parsed at all.
+            let size = match &msg {
+                Message::Text(text) => text.len(),
+                Message::Binary(bytes) => bytes.len(),
+                _ => 0,
+            };
+            let (request, binary) = match msg {
+                Message::Text(_) | Message::Binary(_) if size > ws_config.max_message_bytes => {
+                    (None, matches!(msg, Message::Binary(_)))
+                }
+                Message::Text(text) => (
+                    Some(serde_json::from_str(&text).map_err(|e| e.to_string())),
+                    false,
+                ),
+                Message::Binary(bytes) => (
+                    Some(msgpack::from_slice(&bytes).map_err(|e| e.to_string())),
+                    true,
+                ),
+                Message::Ping(payload) => {
+                    if sink.send(Message::Pong(payload)).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                // The client started the closing handshake. tungstenite has
+                // queued the close frame echoing its code; flushing sends it.
+                Message::Close(_) => {
+                    let _ = sink.close().await;
+                    break;
+                }
+                Message::Pong(_) => continue,
+            };
+
+            let mut close_with = None;
+            let request_id = requestid::next();
+            let mut response = match request {
+                _ if !config.rate_limit.allow(ip) => serde_json::to_value(Response::<QueryData> {
+                    success: false,
+                    data: None,
+                    error: Some("Rate limit exceeded".to_string()),
+                    truncated: false,
+                    query_time_us: None,
+                    request_id: None,
+                }),
+                None => serde_json::to_value(Response::<QueryData> {
+                    success: false,
+                    data: None,
+                    error: Some(format!(
+                        "Message of {} bytes exceeds the limit of {} bytes",
+                        size, ws_config.max_message_bytes
+                    )),
+                    truncated: false,
+                    query_time_us: None,
+                    request_id: None,
+                }),
+                Some(Ok(request)) if !authenticated => {
+                    let presented = request.get("api_key").and_then(|key| key.as_str());
+                    authenticated = api_key.accepts(presented);
+                    if !authenticated {
+                        close_with = Some((CloseCode::Policy, "unauthorized"));
+                    }
+                    serde_json::to_value(Response::<QueryData> {
+                        success: authenticated,
+                        data: None,
+                        error: (!authenticated).then(|| "Unauthorized".to_string()),
+                        truncated: false,
+                        query_time_us: None,
+                        request_id: None,
+                    })
+                }
+                Some(Ok(request)) => {
+                    answer(index.load(), &config, &metrics, request)
+                        .instrument(tracing::info_span!("req", request_id = %request_id))
+                        .await
+                }
+                Some(Err(e)) => serde_json::to_value(invalid_query(e)),
+            };
+            if let Ok(response) = &mut response {
+                tag_request_id(response, &request_id);
+            }
+            let reply = match response {
+                Ok(response) if binary => Message::Binary(msgpack::to_vec(&response)),
+                Ok(response) => match serde_json::to_string(&response) {
+                    Ok(response) => Message::Text(response),
+                    Err(_) => continue,
+                },
+                Err(_) => continue,
+            };
+
+            if sink.send(reply).await.is_err() {
+                break;
+            }
+            if let Some((code, reason)) = close_with {
+                close(&mut sink, &mut stream, code, reason).await;
+                break;
+            }
+        }
+    })
+}
+
+/// The error answer to a frame the WebSocket layer couldn't read and the
+/// code to close with after it, or `None` if the connection itself failed.
+/// poem hands on only tungstenite's message for anything but I/O errors, so
+/// a text frame that isn't UTF-8 is recognized by that.
+fn unreadable_frame(e: &std::io::Error) -> Option<(Message, CloseCode, &'static str)> {
+    if e.kind() != std::io::ErrorKind::Other {
+        return None;
+    }
+    let (code, reason) = if e.to_string().contains("UTF-8") {
+        (CloseCode::Invalid, "text frame is not valid UTF-8")
+    } else {
+        (CloseCode::Protocol, "invalid frame")
+    };
+    let reply = serde_json::to_string(&Response::<QueryData> {
+        success: false,
+        data: None,
+        error: Some(format!("Cannot read frame: {}", e)),
+        truncated: false,
+        query_time_us: None,
+        request_id: Some(requestid::next()),
+    })
+    .ok()?;
+    Some((Message::Text(reply), code, reason))
+}
+
+/// Answers the queries of the UDP and TCP line interfaces. They have no
+/// handshake to carry the API key, so with `--api-key` every query must
+/// carry it as `api_key`; readiness and the rate limit are checked per
+/// query too.
+#[derive(Clone)]
+struct PlainQueries {
+    reloader: Reloader,
+    config: QueryConfig,
+    metrics: Arc<Metrics>,
+    api_key: ApiKey,
+}
+
+impl PlainQueries {
+    /// The answer to `request`, or to the error decoding it, tagged with a
+    /// new request id unless the query brought its own.
+    async fn answer(
+        &self,
+        peer: std::net::IpAddr,
+        request: Result<serde_json::Value, String>,
+    ) -> serde_json::Result<serde_json::Value> {
+        let request_id = requestid::next();
+        let response = match request {
+            _ if !self.reloader.health.is_ready() => {
+                error_value("The index is still loading".to_string())
+            }
+            _ if !self.config.rate_limit.allow(Some(peer)) => {
+                error_value("Rate limit exceeded".to_string())
+            }
+            Ok(request)
+                if !self
+                    .api_key
+                    .accepts(request.get("api_key").and_then(|key| key.as_str())) =>
+            {
+                error_value("Unauthorized".to_string())
+            }
+            Ok(request) => {
+                answer(
+                    self.reloader.index.load(),
+                    &self.config,
+                    &self.metrics,
+                    request,
+                )
+                .instrument(tracing::info_span!("req", request_id = %request_id))
+                .await
+            }
+            Err(e) => serde_json::to_value(invalid_query(e)),
+        };
+        response.map(|mut response| {
+            tag_request_id(&mut response, &request_id);
+            response
+        })
+    }
+}
+
+fn error_value(error: String) -> serde_json::Result<serde_json::Value> {
+    serde_json::to_value(Response::<QueryData> {
+        success: false,
+        data: None,
+        error: Some(error),
+        truncated: false,
+        query_time_us: None,
+        request_id: None,
+    })
+}
+
+/// Largest payload a UDP datagram can carry over IPv4.
+const MAX_DATAGRAM_BYTES: usize = 65_507;
+
+/// Answers each datagram on `socket` with one datagram back to its sender.
+/// Both are MessagePack, shaped like WebSocket binary frames.
+async fn serve_udp(socket: tokio::net::UdpSocket, queries: PlainQueries) {
+    let socket = Arc::new(socket);
+    let mut buf = vec![0; MAX_DATAGRAM_BYTES];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            // E.g. an ICMP error for an earlier reply; the socket still works.
+            Err(e) => {
+                tracing::debug!("UDP receive failed: {}", e);
+                continue;
+            }
+        };
+        let request = msgpack::from_slice(&buf[..len]).map_err(|e| e.to_string());
+        let socket = socket.clone();
+        let queries = queries.clone();
+        tokio::spawn(async move {
+            let Ok(response) = queries.answer(peer.ip(), request).await else {
+                return;
+            };
+            let mut reply = msgpack::to_vec(&response);
+            if reply.len() > MAX_DATAGRAM_BYTES {
+                let request_id = response.get("request_id").cloned();
+                let error = format!("Answer of {} bytes doesn't fit in a datagram", reply.len());
+                let Ok(mut response) = error_value(error) else {
+                    return;
+                };
+                if let Some(id) = request_id {
+                    response["request_id"] = id;
+                }
+                reply = msgpack::to_vec(&response);
+            }
+            if let Err(e) = socket.send_to(&reply, peer).await {
+                tracing::debug!("cannot answer {} over UDP: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Serves newline-delimited JSON queries on each connection to `listener`,
+/// answering each line with one line in order, like `--stdin` does.
+async fn serve_tcp_lines(listener: tokio::net::TcpListener, queries: PlainQueries) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::debug!("TCP line accept failed: {}", e);
+                continue;
+            }
+        };
+        let queries = queries.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer_lines(stream, peer.ip(), &queries).await {
+                tracing::debug!("TCP line connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Lines longer than `--max-message-bytes` are answered with an error and
+/// end the connection, as the rest of them can't be told from the next
+/// query.
+async fn answer_lines(
+    stream: tokio::net::TcpStream,
+    peer: std::net::IpAddr,
+    queries: &PlainQueries,
+) -> std::io::Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+    let max_bytes = queries.reloader.args.max_message_bytes;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = tokio::io::BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        // Reading one byte past the limit tells a line that is too long
+        // from one that just fits.
+        let read = (&mut reader)
+            .take(max_bytes as u64 + 1)
+            .read_until(b'\n', &mut line)
+            .await?;
+        if read == 0 {
+            return Ok(());
+        }
+        let too_long = line.len() > max_bytes && line.last() != Some(&b'\n');
+        let request = if too_long {
+            Err(format!("Line exceeds the limit of {} bytes", max_bytes))
+        } else if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        } else {
+            serde_json::from_slice(&line).map_err(|e| e.to_string())
+        };
+        let response = queries.answer(peer, request).await?;
+        let mut reply = serde_json::to_vec(&response)?;
+        reply.push(b'\n');
+        writer.write_all(&reply).await?;
+        if too_long {
+            return Ok(());
+        }
+    }
+}
+
+/// How long a closing connection waits for the client to answer its close
+/// frame.
+const CLOSE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Starts the closing handshake and reads on until the client answers with
+/// its own close frame or hangs up, for at most [`CLOSE_TIMEOUT`], so both
+/// sides know the connection ended on purpose.
+async fn close(
+    sink: &mut SplitSink<WebSocketStream, Message>,
+    stream: &mut SplitStream<WebSocketStream>,
+    code: CloseCode,
+    reason: &str,
+) {
+    if sink
+        .send(Message::Close(Some((code, reason.to_string()))))
+        .await
+        .is_err()
+    {
+        return;
+    }
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+        while let Some(Ok(msg)) = stream.next().await {
+            if let Message::Close(_) = msg {
+                break;
+            }
+        }
+    })
+    .await;
+}
+
+fn positive_degrees(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(degrees) if degrees.is_finite() && degrees > 0.0 => Ok(degrees),
+        _ => Err("expected a positive number of degrees".to_string()),
+    }
+}
+
+fn parse_bbox(value: &str) -> Result<Bbox, String> {
+    let numbers = value
+        .split(',')
+        .map(|part| part.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    let [min_lat, min_lon, max_lat, max_lon] = numbers[..] else {
+        return Err("expected min_lat,min_lon,max_lat,max_lon".to_string());
+    };
+    for (name, value, limit) in [
+        ("min_lat", min_lat, 90.0),
+        ("max_lat", max_lat, 90.0),
+        ("min_lon", min_lon, 180.0),
+        ("max_lon", max_lon, 180.0),
+    ] {
+        if !value.is_finite() || !(-limit..=limit).contains(&value) {
+            return Err(format!("{} {} out of range", name, value));
+        }
+    }
+    if min_lat > max_lat || min_lon > max_lon {
+        return Err("the minimums must not exceed the maximums".to_string());
+    }
+    Ok(Bbox {
+        min_lat,
+        min_lon,
+        max_lat,
+        max_lon,
+    })
+}
+
+fn coverage_or_all(coverage: Option<Bbox>) -> String {
+    coverage.map_or_else(|| "everything".to_string(), |bbox| bbox.to_string())
+}
+
+/// `--grid-cell-degrees` if it applies, i.e. for `--index grid`.
+fn grid_cell_degrees(args: &Args) -> Option<f64> {
+    args.grid_cell_degrees
+        .filter(|_| args.index == spatial::Kind::Grid)
+}
+
+fn degrees_or_auto(degrees: Option<f64>) -> String {
+    degrees.map_or_else(
+        || "automatic size".to_string(),
+        |degrees| format!("{}°", degrees),
+    )
+}
+
+/// Loads the index from `--cache` when it is still valid, otherwise builds it
+/// from the pbf files (refreshing the cache if one is configured). Fails if
+/// a pbf can't be read or downloaded, or the cache can't be written.
+fn load_index(args: &Args) -> Result<GeoIndex, String> {
+    let build = |pbfs: &[String]| -> Result<GeoIndex, String> {
+        // A reload may find a file gone that was there at startup.
+        pbfs.iter().try_for_each(|pbf| check_pbf(pbf))?;
+        // Downloaded files are removed again once the index is built.
+        let local = pbfs
+            .iter()
+            .map(|pbf| fetch::localize(pbf))
+            .collect::<Result<Vec<_>, _>>()?;
+        let paths: Vec<String> = local.iter().map(|pbf| pbf.path().to_string()).collect();
+        if args.grid_cell_degrees.is_some() && grid_cell_degrees(args).is_none() {
+            tracing::warn!("--grid-cell-degrees only applies to --index grid => ignored");
+        }
+        let mut geo = GeoIndex::new(
+            args.index_tags.clone(),
+            args.store_tags.clone(),
+            args.index,
+            grid_cell_degrees(args),
+            args.admin_boundaries,
+            args.osc_dir.is_some(),
+        );
+        geo.set_coverage(args.bbox);
+        geo.build(&paths, args.max_memory_mb);
+        geo.set_sources(pbfs);
+        geo.set_metric(args.distance);
+        Ok(geo)
+    };
+    match &args.cache {
+        Some(path) => {
+            let cached = match std::fs::File::open(path) {
+                Ok(_) if args.force_rebuild => {
+                    tracing::info!("--force-rebuild => rebuild");
+                    None
+                }
+                Ok(file) => {
+                    let start = std::time::Instant::now();
+                    tracing::info!("load index from file");
+                    match cache::load(file, &args.pbf) {
+                        Ok(geo) if geo.index_tags() != args.index_tags => {
+                            tracing::warn!(
+                                "cache {} indexes tags {:?} but {:?} were requested => rebuild",
+                                path,
+                                geo.index_tags(),
+                                args.index_tags
+                            );
+                            None
+                        }
+                        Ok(geo) if geo.stored_tags() != args.store_tags => {
+                            tracing::warn!(
+                                "cache {} stores tags {:?} but {:?} were requested => rebuild",
+                                path,
+                                geo.stored_tags(),
+                                args.store_tags
+                            );
+                            None
+                        }
+                        Ok(geo) if geo.kind() != args.index => {
+                            tracing::warn!(
+                                "cache {} uses the {:?} index but {:?} was requested => rebuild",
+                                path,
+                                geo.kind(),
+                                args.index
+                            );
+                            None
+                        }
+                        Ok(geo) if geo.grid_cell_degrees() != grid_cell_degrees(args) => {
+                            tracing::warn!(
+                                "cache {} has grid cells of {} but {} was requested => rebuild",
+                                path,
+                                degrees_or_auto(geo.grid_cell_degrees()),
+                                degrees_or_auto(grid_cell_degrees(args))
+                            );
+                            None
+                        }
+                        Ok(geo) if geo.coverage() != args.bbox => {
+                            tracing::warn!(
+                                "cache {} covers {} but {} was requested => rebuild",
+                                path,
+                                coverage_or_all(geo.coverage()),
+                                coverage_or_all(args.bbox)
+                            );
+                            None
+                        }
+                        Ok(geo) if geo.has_admin_boundaries() != args.admin_boundaries => {
+                            tracing::warn!(
+                                "cache {} was built {} --admin-boundaries => rebuild",
+                                path,
+                                if geo.has_admin_boundaries() {
+                                    "with"
+                                } else {
+                                    "without"
+                                }
+                            );
+                            None
+                        }
+                        Ok(geo) if geo.is_updatable() != args.osc_dir.is_some() => {
+                            tracing::warn!(
+                                "cache {} was built {} --osc-dir => rebuild",
+                                path,
+                                if geo.is_updatable() {
+                                    "with"
+                                } else {
+                                    "without"
+                                }
+                            );
+                            None
+                        }
+                        Ok(geo) => {
+                            tracing::info!(
+                                "Loaded index in {}ms, taking about {:.1} MB",
+                                start.elapsed().as_millis(),
+                                memory::megabytes(geo.heap_bytes())
+                            );
+                            Some(geo)
+                        }
+                        Err(e) => {
+                            tracing::warn!("cannot use cache {}: {} => rebuild", path, e);
+                            None
+                        }
+                    }
+                }
+                Err(_e) => {
+                    tracing::info!("cannot load index => rebuild");
+                    None
+                }
+            };
+            if let Some(mut geo) = cached {
+                geo.set_metric(args.distance);
+                return Ok(geo);
+            }
+            let geo = build(&args.pbf)?;
+            cache::save(path, &args.pbf, &geo, args.cache_compression)
+                .map_err(|e| format!("cannot write cache '{}': {}", path, e))?;
+            Ok(geo)
+        }
+        None => build(&args.pbf),
+    }
+}
+
+/// The files of one index: the unnamed `--pbf` entries, or all those sharing
+/// a `name=`. `args` is the full configuration with just these files, and
+/// for a named dataset `--cache` with the name added, so each has its own.
+struct Dataset {
+    name: Option<String>,
+    args: Args,
+}
+
+/// Paths the datasets' routes are mounted next to, which a name would shadow.
+const RESERVED_NAMES: [&str; 12] = [
+    "query", "snap", "bbox", "admin", "osm", "health", "reload", "stats", "livez", "readyz",
+    "metrics", "docs",
+];
+
+/// Splits `name=path`; a name is made of letters, digits, `-` and `_`, so
+/// plain paths and URLs (whose `=` only follows a `:` or `/`) pass as
+/// they are.
+fn dataset_name(pbf: &str) -> (Option<&str>, &str) {
+    match pbf.split_once('=') {
+        Some((name, path))
+            if !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+        {
+            (Some(name), path)
+        }
+        _ => (None, pbf),
+    }
+}
+
+/// `--cache index.bin` becomes `index.<name>.bin`.
+fn dataset_cache(cache: &str, name: &str) -> String {
+    let path = std::path::Path::new(cache);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, name, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, name),
+    };
+    path.with_file_name(file).to_string_lossy().into_owned()
+}
+
+/// Groups `--pbf` into datasets, the unnamed one first, then the named ones
+/// in the order they first appear.
+fn datasets(args: &Args) -> Result<Vec<Dataset>, String> {
+    let mut groups: Vec<(Option<String>, Vec<String>)> = Vec::new();
+    for pbf in &args.pbf {
+        let (name, path) = dataset_name(pbf);
+        if let Some(name) = name.filter(|name| RESERVED_NAMES.contains(name)) {
+            return Err(format!(
+                "dataset name {} would shadow the /{} route",
+                name, name
+            ));
+        }
+        let name = name.map(str::to_string);
+        match groups.iter_mut().find(|(group, _)| *group == name) {
+            Some((_, paths)) => paths.push(path.to_string()),
+            None => groups.push((name, vec![path.to_string()])),
+        }
+    }
+    groups.sort_by_key(|(name, _)| name.is_some());
+    Ok(groups
+        .into_iter()
+        .map(|(name, pbf)| Dataset {
+            args: Args {
+                pbf,
+                cache: match &name {
+                    Some(name) => args
+                        .cache
+                        .as_deref()
+                        .map(|cache| dataset_cache(cache, name)),
+                    None => args.cache.clone(),
+                },
+                ..args.clone()
+            },
+            name,
+        })
+        .collect())
+}
+
+/// Fails if `pbf` is a local file that can't be read, before the parser
+/// gets to it. URLs are checked when they are downloaded.
+fn check_pbf(pbf: &str) -> Result<(), String> {
+    if fetch::is_url(pbf) {
+        return Ok(());
+    }
+    match std::fs::File::open(pbf).and_then(|file| file.metadata()) {
+        Ok(metadata) if metadata.is_dir() => Err(format!("pbf file '{}' is a directory", pbf)),
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Err(format!("pbf file '{}' not found", pbf))
+        }
+        Err(e) => Err(format!("cannot read pbf file '{}': {}", pbf, e)),
+    }
+}
+
+/// Fails if the index could never be written to `cache`. A cache that
+/// exists but is read-only is fine as long as it stays valid.
+fn check_cache(cache: &str) -> Result<(), String> {
+    let path = std::path::Path::new(cache);
+    if path.is_dir() {
+        return Err(format!("cache '{}' is a directory", cache));
+    }
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(std::path::Path::new("."));
+    if !dir.is_dir() {
+        return Err(format!(
+            "cache directory '{}' not found",
+            dir.to_string_lossy()
+        ));
+    }
+    Ok(())
+}
+
+/// [`datasets`], with their files checked, at startup.
+fn datasets_or_exit(args: &Args) -> Vec<Dataset> {
+    let checked = datasets(args).and_then(|datasets| {
+        for dataset in &datasets {
+            dataset.args.pbf.iter().try_for_each(|pbf| check_pbf(pbf))?;
+            dataset.args.cache.as_deref().map_or(Ok(()), check_cache)?;
+        }
+        Ok(datasets)
+    });
+    checked.unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        std::process::exit(2);
+    })
+}
+
+/// [`load_index`] at startup, where there is no index to fall back to.
+fn load_index_or_exit(args: &Args) -> GeoIndex {
+    load_index(args).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    })
+}
+
+/// Names of the change files applied to the live index. Held while applying
+/// more, so the watcher and a reload never interleave.
+#[derive(Clone, Default)]
+struct AppliedDiffs(Arc<tokio::sync::Mutex<BTreeSet<String>>>);
+
+/// Names of the change files in `dir` that aren't in `applied` yet, in
+/// name order.
+fn pending_osc_files(dir: &str, applied: &BTreeSet<String>) -> Vec<String> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            tracing::warn!("cannot read --osc-dir {}: {}", dir, e);
+            return Vec::new();
+        }
+    };
+    let mut pending: Vec<String> = entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.ends_with(".osc") || name.ends_with(".osc.gz"))
+        .filter(|name| !applied.contains(name))
+        .collect();
+    pending.sort();
+    pending
+}
+
+/// Applies the change files in `dir` that aren't in `applied` yet, in name
+/// order, and records them. Returns whether anything was applied.
+fn apply_osc_dir(geo: &mut GeoIndex, dir: &str, applied: &mut BTreeSet<String>) -> bool {
+    let pending = pending_osc_files(dir, applied);
+    apply_osc_files(geo, dir, pending, applied)
+}
+
+/// Applies the `pending` change files of `dir` in order and records them in
+/// `applied`. A file that fails (e.g. one still being written) and those
+/// after it are left for the next attempt.
+fn apply_osc_files(
+    geo: &mut GeoIndex,
+    dir: &str,
+    pending: Vec<String>,
+    applied: &mut BTreeSet<String>,
+) -> bool {
+    let mut changed = false;
+    for name in pending {
+        let path = std::path::Path::new(dir).join(&name);
+        match geo.apply_osc(&path.to_string_lossy()) {
+            Ok(()) => {
+                applied.insert(name);
+                changed = true;
+            }
+            Err(e) => {
+                tracing::warn!("cannot apply {}: {} => retry later", path.display(), e);
+                break;
+            }
+        }
+    }
+    changed
+}
+
+/// Polls `--osc-dir` and swaps in a copy of the index with the new change
+/// files applied; queries keep using the current index meanwhile. The index
+/// is only copied once there is a new file.
+async fn watch_osc_dir(
+    dir: String,
+    interval: std::time::Duration,
+    index: IndexHandle,
+    health: Health,
+    applied: AppliedDiffs,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let mut applied = applied.0.lock().await;
+        let pending = pending_osc_files(&dir, &applied);
+        if pending.is_empty() {
+            continue;
+        }
+        let current = index.load();
+        let dir = dir.clone();
+        let mut names = applied.clone();
+        let updated = tokio::task::spawn_blocking(move || {
+            let mut geo = (*current).clone();
+            apply_osc_files(&mut geo, &dir, pending, &mut names).then_some((geo, names))
+        })
+        .await;
+        match updated {
+            Ok(Some((geo, names))) => {
+                if let Some(info) = health.get() {
+                    health.set(IndexInfo {
+                        features: geo.len(),
+                        ..info
+                    });
+                }
+                index.store(geo);
+                *applied = names;
+            }
+            Ok(None) => {}
+            Err(e) => tracing::warn!("applying change files failed, keeping current index: {}", e),
+        }
+    }
+}
+
+/// What `--watch` compares between polls: each local pbf's modification
+/// time and size, or `None` while it is missing. URLs aren't watched.
+fn pbf_stamps(pbfs: &[String]) -> Vec<Option<(Option<std::time::SystemTime>, u64)>> {
+    pbfs.iter()
+        .filter(|pbf| !fetch::is_url(pbf))
+        .map(|pbf| {
+            let metadata = std::fs::metadata(pbf).ok()?;
+            Some((metadata.modified().ok(), metadata.len()))
+        })
+        .collect()
+}
+
+/// Reloads the index when one of its pbf files has changed, waiting until
+/// the files look the same on two polls in a row so one still being written
+/// or copied isn't read half-way.
+async fn watch_pbf(reloader: Reloader, interval: std::time::Duration) {
+    let mut current = pbf_stamps(&reloader.args.pbf);
+    loop {
+        tokio::time::sleep(interval).await;
+        let mut stamps = pbf_stamps(&reloader.args.pbf);
+        if stamps == current {
+            continue;
+        }
+        loop {
+            tokio::time::sleep(interval).await;
+            let settled = pbf_stamps(&reloader.args.pbf);
+            if settled == stamps {
+                break;
+            }
+            stamps = settled;
+        }
+        if stamps.contains(&None) {
+            tracing::warn!("a watched pbf file is missing, keeping current index");
+            current = stamps;
+            continue;
+        }
+        tracing::info!("pbf file changed => reload index");
+        let running = reloader.running.clone().lock_owned().await;
+        let _ = reloader.reload(running).await;
+        current = stamps;
+    }
+}
+
+/// Builds or loads the index at startup, on the blocking pool so the server
+/// keeps answering probes meanwhile, then starts watching `--osc-dir` and,
+/// with `--watch`, the pbf files.
+/// Holds `running` throughout, so an early reload waits for it. Exits the
+/// process if the index can't be loaded.
+async fn load_initial_index(reloader: Reloader, _running: tokio::sync::OwnedMutexGuard<()>) {
+    let start = std::time::Instant::now();
+    let args = reloader.args.clone();
+    let loaded = tokio::task::spawn_blocking(move || {
+        let mut geo = load_index_or_exit(&args);
+        let mut names = BTreeSet::new();
+        if let Some(dir) = &args.osc_dir {
+            apply_osc_dir(&mut geo, dir, &mut names);
+        }
+        (geo, names)
+    })
+    .await;
+    let (geo, names) = loaded.unwrap_or_else(|e| {
+        eprintln!("error: loading the index failed: {}", e);
+        std::process::exit(1);
+    });
+    *reloader.applied.0.lock().await = names;
+    reloader
+        .health
+        .set(IndexInfo::new(&reloader.args, &geo, start.elapsed()));
+    reloader.index.store(geo);
+
+    if let Some(dir) = reloader.args.osc_dir.clone() {
+        tokio::spawn(watch_osc_dir(
+            dir,
+            std::time::Duration::from_secs(reloader.args.osc_poll_secs.max(1)),
+            reloader.index.clone(),
+            reloader.health.clone(),
+            reloader.applied.clone(),
+        ));
+    }
+    if reloader.args.watch {
+        let interval = std::time::Duration::from_secs(reloader.args.watch_poll_secs.max(1));
+        tokio::spawn(watch_pbf(reloader, interval));
+    }
+}
+
+/// Everything a reload needs, shared by SIGHUP and `POST /reload`.
+#[derive(Clone)]
+struct Reloader {
+    args: Args,
+    index: IndexHandle,
+    health: Health,
+    applied: AppliedDiffs,
+    /// Held while a reload runs, so two never overlap.
+    running: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Reloader {
+    /// Rebuilds the index and swaps it in once it is ready; the old one keeps
+    /// serving until then, and stays if the rebuild fails. Takes the
+    /// `running` guard so the caller decides whether to wait for it.
+    async fn reload(
+        &self,
+        _running: tokio::sync::OwnedMutexGuard<()>,
+    ) -> Result<IndexInfo, String> {
+        let start = std::time::Instant::now();
+        let reload_args = self.args.clone();
+        // Change files are applied again on top of the fresh pbf.
+        let mut applied = self.applied.0.lock().await;
+        let reloaded = tokio::task::spawn_blocking(move || {
+            let mut geo = load_index(&reload_args)?;
+            let mut names = BTreeSet::new();
+            if let Some(dir) = &reload_args.osc_dir {
+                apply_osc_dir(&mut geo, dir, &mut names);
+            }
+            Ok::<_, String>((geo, names))
+        })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|reloaded| reloaded)
+        .and_then(|(geo, names)| {
+            // Unreadable pbf blocks are skipped while building, so a broken
+            // file shows up as an empty index rather than an error.
+            if geo.len() == 0 && self.index.load().len() > 0 {
+                return Err("reloaded index is empty".to_string());
+            }
+            *applied = names;
+            Ok(geo)
+        });
+        let geo = match reloaded {
+            Ok(geo) => geo,
+            Err(e) => {
+                tracing::warn!("reload failed, keeping current index: {}", e);
+                return Err(e);
+            }
+        };
+        tracing::info!(
+            "Reloaded index in {}ms: {} features (was {})",
+            start.elapsed().as_millis(),
+            geo.len(),
+            self.index.load().len()
+        );
+        let info = IndexInfo::new(&self.args, &geo, start.elapsed());
+        self.health.set(info.clone());
+        self.index.store(geo);
+        Ok(info)
+    }
+}
+
+/// Logs the query cache's hit ratio over each `interval` in which it was
+/// consulted, for judging whether `--query-cache-size` pays for its memory.
+async fn log_query_cache(metrics: Arc<Metrics>, interval: std::time::Duration) {
+    let mut last = metrics.query_cache_counts();
+    loop {
+        tokio::time::sleep(interval).await;
+        let (hits, misses) = metrics.query_cache_counts();
+        let (new_hits, new_misses) = (hits - last.0, misses - last.1);
+        last = (hits, misses);
+        if new_hits + new_misses == 0 {
+            continue;
+        }
+        tracing::info!(
+            "query cache: {} hits, {} misses in the last {}s ({:.1}% hit ratio)",
+            new_hits,
+            new_misses,
+            interval.as_secs(),
+            100.0 * new_hits as f64 / (new_hits + new_misses) as f64
+        );
+    }
+}
+
+/// Reloads every dataset's index on each SIGHUP, one after another and
+/// each after any reload of it already running.
+#[cfg(unix)]
+async fn reload_on_sighup(reloaders: Vec<Reloader>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::warn!("cannot listen for SIGHUP, reloading disabled: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        tracing::info!("SIGHUP received => reload index");
+        for reloader in &reloaders {
+            let running = reloader.running.clone().lock_owned().await;
+            let _ = reloader.reload(running).await;
+        }
+    }
+}
+
+/// Logs uptime, lookups answered, open WebSocket connections and each
+/// dataset's feature count and memory estimate on each SIGUSR1, for a look
+/// at a running server without an HTTP call.
+#[cfg(unix)]
+async fn log_stats_on_sigusr1(
+    datasets: Vec<(Option<String>, Reloader)>,
+    metrics: Arc<Metrics>,
+    started: std::time::Instant,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut user_defined1 = match signal(SignalKind::user_defined1()) {
+        Ok(user_defined1) => user_defined1,
+        Err(e) => {
+            tracing::warn!("cannot listen for SIGUSR1, stats dump disabled: {}", e);
+            return;
+        }
+    };
+    while user_defined1.recv().await.is_some() {
+        tracing::info!(
+            "stats: up {}s, {} lookups answered, {} WebSocket connections open",
+            started.elapsed().as_secs(),
+            metrics.queries_total(),
+            metrics.ws_connections()
+        );
+        for (name, reloader) in &datasets {
+            let index = match name {
+                Some(name) => format!("index {}", name),
+                None => "index".to_string(),
+            };
+            if !reloader.health.is_ready() {
+                tracing::info!("stats: {} still loading", index);
+                continue;
+            }
+            let geo = reloader.index.load();
+            tracing::info!(
+                "stats: {}: {} features, taking about {:.1} MB",
+                index,
+                geo.len(),
+                memory::megabytes(geo.heap_bytes())
+            );
+        }
+    }
+}
+
+/// `POST /reload`: the HTTP counterpart of SIGHUP, answering with the new
+/// index once it is swapped in. Refused unless `--api-key` is set, and with
+/// 409 while another reload is running.
+#[handler]
+async fn reload_handler(
+    reloader: Data<&Reloader>,
+    api_key: Data<&ApiKey>,
+    Data(request_id): Data<&RequestId>,
+) -> poem::Response {
+    let refuse = |status: StatusCode, error: &str| {
+        Json(Response::<IndexInfo> {
+            success: false,
+            data: None,
+            error: Some(error.to_string()),
+            truncated: false,
+            query_time_us: None,
+            request_id: Some(request_id.0.clone()),
+        })
+        .with_status(status)
+        .into_response()
+    };
+    if !api_key.is_enabled() {
+        return refuse(
+            StatusCode::FORBIDDEN,
+            "Reloading over HTTP requires --api-key",
+        );
+    }
+    let Ok(running) = reloader.running.clone().try_lock_owned() else {
+        return refuse(StatusCode::CONFLICT, "A reload is already in progress");
+    };
+    // Spawned so a client hanging up doesn't cancel the reload halfway.
+    let reloader = reloader.0.clone();
+    let reloaded = tokio::spawn(async move { reloader.reload(running).await })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|reloaded| reloaded);
+    match reloaded {
+        Ok(info) => Json(Response {
+            success: true,
+            data: Some(info),
+            error: None,
+            truncated: false,
+            query_time_us: None,
+            request_id: Some(request_id.0.clone()),
+        })
+        .into_response(),
+        Err(e) => refuse(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("Reload failed, keeping current index: {}", e),
+        ),
+    }
+}
+
+/// Binds `host`, which is an IPv4 or IPv6 address (brackets optional) or a
+/// hostname. IPv6 sockets are made dual-stack, so `::` also accepts IPv4
+/// connections regardless of the system's `bindv6only` default.
+fn listen(host: &str, port: u16) -> std::io::Result<std::net::TcpListener> {
+    let ip = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'));
+    let Ok(ip) = ip.unwrap_or(host).parse::<std::net::IpAddr>() else {
+        let listener = std::net::TcpListener::bind((host, port))?;
+        listener.set_nonblocking(true)?;
+        return Ok(listener);
+    };
+    let addr = std::net::SocketAddr::new(ip, port);
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Binds a Unix domain socket at `path`. A socket file left behind by a
+/// server that didn't shut down cleanly is replaced; one that still accepts
+/// connections is not.
+#[cfg(unix)]
+async fn listen_unix(path: &str) -> std::io::Result<BoxAcceptor> {
+    use poem::listener::{Listener, UnixListener};
+    use std::os::unix::{fs::FileTypeExt, net::UnixStream};
+
+    let stale = std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket())
+        && UnixStream::connect(path).is_err();
+    if stale {
+        std::fs::remove_file(path)?;
+    }
+    Ok(UnixListener::bind(path.to_string())
+        .into_acceptor()
+        .await?
+        .boxed())
+}
+
+#[cfg(not(unix))]
+async fn listen_unix(_path: &str) -> std::io::Result<BoxAcceptor> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    ))
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for SIGINT");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    tracing::info!("shutdown signal received, draining connections");
+}
+
+/// Loads the index for the command-line modes, which log warnings to stderr
+/// only and keep stdout for responses.
+fn load_offline(args: &Args) -> (GeoIndex, QueryConfig) {
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(tracing::Level::WARN);
+    match args.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.event_format(logging::Json).init(),
+    }
+
+    let config = QueryConfig {
+        max_results: args.max_results,
+        max_snap_points: args.max_snap_points,
+        max_match_distance_m: args.max_match_distance_m,
+        timeout: std::time::Duration::from_millis(args.query_timeout_ms),
+        rate_limit: RateLimit::default(),
+        batch_parallelism: args.batch_parallelism,
+        coord_order: args.coord_order,
+        query_cache: QueryCache::new(args.query_cache_size),
+    };
+    let mut datasets = datasets_or_exit(args);
+    if datasets.len() > 1 {
+        eprintln!("error: this command uses a single index, but --pbf names several datasets");
+        std::process::exit(2);
+    }
+    (load_index_or_exit(&datasets.remove(0).args), config)
+}
+
+/// The `query` subcommand: prints the JSON response and exits with status 1
+/// if the lookup didn't succeed.
+fn run_query(args: &Args, latitude: f64, longitude: f64) -> Result<(), std::io::Error> {
+    let (geo, config) = load_offline(args);
+    let params = QueryParams::at(latitude, longitude);
+    let response = query(&geo, &config, &Metrics::default(), &params);
+    let success = response.success;
+    println!("{}", serde_json::to_string(&response)?);
+    if !success {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// The `export` subcommand: dumps the index, loaded like `query` does.
+fn run_export(args: &Args, format: export::Format) -> Result<(), std::io::Error> {
+    let (geo, _) = load_offline(args);
+    let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+    match export::write(&geo, format, &mut stdout) {
+        // The reader went away (e.g. `| head`), which is not an error.
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
+
+/// The `bench` subcommand: runs `queries` nearest lookups through the same
+/// [`query`] path the server uses, one after the other, and reports the
+/// throughput and latency percentiles.
+fn run_bench(args: &Args, queries: usize, seed: u64) -> Result<(), std::io::Error> {
+    let (geo, config) = load_offline(args);
+    let Some(bbox) = geo.stats().bbox else {
+        eprintln!("error: the index is empty");
+        std::process::exit(1);
+    };
+    // SplitMix64; good enough to scatter points and keeps runs repeatable.
+    let mut state = seed;
+    let mut uniform = || {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        (z ^ (z >> 31)) as f64 / u64::MAX as f64
+    };
+    let metrics = Metrics::default();
+    let mut latencies = Vec::with_capacity(queries);
+    let mut found = 0;
+    let start = std::time::Instant::now();
+    for _ in 0..queries {
+        let params = QueryParams::at(
+            bbox.min_lat + uniform() * (bbox.max_lat - bbox.min_lat),
+            bbox.min_lon + uniform() * (bbox.max_lon - bbox.min_lon),
+        );
+        let (response, elapsed) = timed(|| query(&geo, &config, &metrics, &params));
+        found += usize::from(response.success);
+        latencies.push(elapsed);
+    }
+    let total = start.elapsed();
+    latencies.sort_unstable();
+    let percentile = |p: usize| {
+        latencies
+            .get((latencies.len() * p / 100).min(latencies.len().saturating_sub(1)))
+            .copied()
+            .unwrap_or(0)
+    };
+    println!(
+        "{} queries in {}ms ({:.0} queries/s), {} found; latency p50 {}us, p95 {}us, p99 {}us",
+        queries,
+        total.as_millis(),
+        queries as f64 / total.as_secs_f64(),
+        found,
+        percentile(50),
+        percentile(95),
+        percentile(99)
+    );
+    Ok(())
+}
+
+/// The `build` subcommand: always rebuilds, so the cache reflects the pbf
+/// files at hand, then reports what was written; one cache per dataset.
+fn run_build(args: &Args) -> Result<(), std::io::Error> {
+    if args.cache.is_none() {
+        eprintln!("error: build needs --cache to write the index to");
+        std::process::exit(2);
+    }
+    logging::init(args.log_format, args.log_level);
+    for dataset in datasets_or_exit(args) {
+        let start = std::time::Instant::now();
+        let geo = load_index_or_exit(&Args {
+            force_rebuild: true,
+            ..dataset.args.clone()
+        });
+        println!(
+            "Built index of {} features into {} in {}ms",
+            geo.len(),
+            dataset.args.cache.unwrap_or_default(),
+            start.elapsed().as_millis()
+        );
+    }
+    Ok(())
+}
+
+/// `--stdin`: answers each line of stdin like a WebSocket text message and
+/// writes one response line to stdout. Blank lines are skipped.
+fn run_pipe(args: &Args) -> Result<(), std::io::Error> {
+    use std::io::{BufRead, Write};
+
+    let (geo, config) = load_offline(args);
+    let metrics = Metrics::default();
+    let mut stdout = std::io::stdout().lock();
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => handle_request(&geo, &config, &metrics, request)?,
+            Err(e) => serde_json::to_value(invalid_query(e))?,
+        };
+        match writeln!(stdout, "{}", response) {
+            // The reader went away (e.g. `| head`), which is not an error.
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => return Ok(()),
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+/// The routes answered from one dataset's index, mounted at `/` for the
+/// unnamed `--pbf` files and at `/name/` for each named dataset. Until the
+/// index is loaded, everything but `/health` is answered with a 503.
+fn dataset_routes(
+    reloader: &Reloader,
+    api_key: &ApiKey,
+    config: QueryConfig,
+    gzip: Gzip,
+) -> impl Endpoint {
+    let rate_limit = config.rate_limit.clone();
+    let http_cache = {
+        let index = reloader.index.clone();
+        let (max_match_distance_m, max_results) = (config.max_match_distance_m, config.max_results);
+        HttpCache::new(
+            reloader.args.http_max_age_secs,
+            api_key.is_enabled(),
+            move || {
+                // Everything besides the query string that decides the answer.
+                let geo = index.load();
+                let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                (
+                    geo.build_id(),
+                    geo.metric(),
+                    max_match_distance_m.map(f64::to_bits),
+                    max_results,
+                )
+                    .hash(&mut hasher);
+                hasher.finish()
+            },
+        )
+    };
+    let loading = reloader.health.clone();
+    Route::new()
+        .at("/", get(ws_handler))
+        .at(
+            "/query",
+            get(query_handler)
+                .post(query_post_handler)
+                .with(http_cache)
+                .with(api_key.clone())
+                .with(rate_limit.clone())
+                .with(gzip),
+        )
+        .at(
+            "/query/batch",
+            post(batch_handler)
+                .with(api_key.clone())
+                .with(rate_limit.clone())
+                .with(gzip),
+        )
+        .at(
+            "/snap",
+            post(snap_handler)
+                .with(api_key.clone())
+                .with(rate_limit.clone())
+                .with(gzip),
+        )
+        .at(
+            "/bbox",
+            get(bbox_handler)
+                .with(api_key.clone())
+                .with(rate_limit.clone())
+                .with(gzip),
+        )
+        .at(
+            "/admin",
+            get(admin_handler)
+                .with(api_key.clone())
+                .with(rate_limit.clone())
+                .with(gzip),
+        )
+        .at(
+            "/osm/:type/:id",
+            get(osm_handler)
+                .with(api_key.clone())
+                .with(rate_limit.clone())
+                .with(gzip),
+        )
+        .at("/health", get(health_handler))
+        .at("/reload", post(reload_handler).with(api_key.clone()))
+        .at(
+            "/stats",
+            get(stats_handler).with(api_key.clone()).with(gzip),
+        )
+        .data(reloader.index.clone())
+        .data(reloader.health.clone())
+        .data(reloader.clone())
+        .data(config)
+        .around(move |ep, req| {
+            let ready = loading.is_ready() || req.uri().path() == "/health";
+            async move {
+                if !ready {
+                    return Ok(Json(Response::<()> {
+                        success: false,
+                        data: None,
+                        error: Some("The index is still loading".to_string()),
+                        truncated: false,
+                        query_time_us: None,
+                        request_id: req.data::<RequestId>().map(|id| id.0.clone()),
+                    })
+                    .with_status(StatusCode::SERVICE_UNAVAILABLE)
+                    .into_response());
+                }
+                ep.call(req).await.map(IntoResponse::into_response)
+            }
+        })
+}
+
+#[tokio::main]
+async fn main() -> Result<(), std::io::Error> {
+    let args: Args = config::parse();
+    match args.command {
+        Some(Command::Query { lat, lon }) => return run_query(&args, lat, lon),
+        Some(Command::Build) => return run_build(&args),
+        Some(Command::Bench { queries, seed }) => return run_bench(&args, queries, seed),
+        Some(Command::Export { format }) => return run_export(&args, format),
+        None => {}
+    }
+    if args.stdin {
+        return run_pipe(&args);
+    }
+    logging::init(args.log_format, args.log_level);
+
+    let datasets = datasets_or_exit(&args);
+    let acceptor = match &args.unix_socket {
+        Some(path) => listen_unix(path).await.map_err(|e| (path.clone(), e)),
+        None => listen(&args.host, args.port)
+            .and_then(TcpAcceptor::from_std)
+            .map(AcceptorExt::boxed)
+            .map_err(|e| (format!("{}:{}", args.host, args.port), e)),
+    };
+    let acceptor = match acceptor {
+        Ok(acceptor) => acceptor,
+        Err((addr, e)) => {
+            eprintln!("error: cannot bind {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut udp_socket = match args.udp_port {
+        Some(port) => {
+            let host = args.host.trim_start_matches('[').trim_end_matches(']');
+            match tokio::net::UdpSocket::bind((host, port)).await {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    eprintln!("error: cannot bind UDP {}:{}: {}", args.host, port, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let mut tcp_line_listener = match args.tcp_line_port {
+        Some(port) => match listen(&args.host, port).and_then(tokio::net::TcpListener::from_std) {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                eprintln!("error: cannot bind {}:{}: {}", args.host, port, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let api_key = ApiKey::new(args.api_key.clone());
+    let rate_limit = RateLimit::new(args.rate_limit_per_sec);
+    let gzip = Gzip::new(args.compression_min_bytes);
+    let (shutting_down, shutdown) = tokio::sync::watch::channel(false);
+    let (open, mut all_closed) = tokio::sync::mpsc::channel::<()>(1);
+    let started = std::time::Instant::now();
+    let metrics = Arc::new(Metrics::default());
+    if args.query_cache_size > 0 && args.query_cache_log_secs > 0 {
+        tokio::spawn(log_query_cache(
+            metrics.clone(),
+            std::time::Duration::from_secs(args.query_cache_log_secs),
+        ));
+    }
+    let mut app = Route::new();
+    let mut reloaders = Vec::new();
+    let mut named = Vec::new();
+    for dataset in datasets {
+        let reloader = Reloader {
+            // Stands in until `load_initial_index` swaps in the real one;
+            // lookups are refused meanwhile.
+            index: IndexHandle::new(GeoIndex::new(
+                Vec::new(),
+                Vec::new(),
+                args.index,
+                None,
+                false,
+                false,
+            )),
+            args: dataset.args,
+            health: Health::default(),
+            applied: AppliedDiffs::default(),
+            running: Arc::default(),
+        };
+        let config = QueryConfig {
+            max_results: args.max_results,
+            max_snap_points: args.max_snap_points,
+            max_match_distance_m: args.max_match_distance_m,
+            timeout: std::time::Duration::from_millis(args.query_timeout_ms),
+            rate_limit: rate_limit.clone(),
+            batch_parallelism: args.batch_parallelism,
+            coord_order: args.coord_order,
+            query_cache: QueryCache::new(args.query_cache_size),
+        };
+        if dataset.name.is_none() {
+            let queries = PlainQueries {
+                reloader: reloader.clone(),
+                config: config.clone(),
+                metrics: metrics.clone(),
+                api_key: api_key.clone(),
+            };
+            if let Some(socket) = udp_socket.take() {
+                tokio::spawn(serve_udp(socket, queries.clone()));
+            }
+            if let Some(listener) = tcp_line_listener.take() {
+                tokio::spawn(serve_tcp_lines(listener, queries));
+            }
+        }
+        let routes = dataset_routes(&reloader, &api_key, config, gzip);
+        app = app.nest(
+            format!("/{}", dataset.name.as_deref().unwrap_or_default()),
+            routes,
+        );
+        named.push((dataset.name, reloader.clone()));
+        reloaders.push(reloader);
+    }
+    if udp_socket.is_some() || tcp_line_listener.is_some() {
+        tracing::warn!(
+            "--udp-port and --tcp-line-port serve the unnamed dataset, but every --pbf is named => disabled"
+        );
+    }
+
+    // Bound before loading, so liveness probes pass during a long build.
+    // The reload guards are taken right away, so an early reload waits for
+    // the index it would replace.
+    let mut pending = Vec::new();
+    for reloader in &reloaders {
+        pending.push((
+            reloader.clone(),
+            reloader.running.clone().lock_owned().await,
+        ));
+    }
+    tokio::spawn(async move {
+        // One after another, so builds don't need memory for all at once.
+        for (reloader, running) in pending {
+            load_initial_index(reloader, running).await;
+        }
+    });
+    let readiness = Readiness(reloaders.iter().map(|r| r.health.clone()).collect());
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(reloaders));
+    #[cfg(unix)]
+    tokio::spawn(log_stats_on_sigusr1(named, metrics.clone(), started));
+
+    let app = app
+        .at("/livez", get(livez_handler))
+        .at("/readyz", get(readyz_handler))
+        .at("/metrics", get(metrics_handler).with(api_key.clone()))
+        .at("/openapi.json", get(openapi_handler))
+        .at("/docs", get(docs_handler))
+        .data(api_key)
+        .data(readiness)
+        .data(WsConfig {
+            ping_interval: (args.ws_ping_interval_secs > 0)
+                .then(|| std::time::Duration::from_secs(args.ws_ping_interval_secs)),
+            pong_timeout: std::time::Duration::from_secs(args.ws_pong_timeout_secs),
+            max_message_bytes: args.max_message_bytes,
+            connections: Arc::new(tokio::sync::Semaphore::new(
+                args.max_connections
+                    .unwrap_or(tokio::sync::Semaphore::MAX_PERMITS),
+            )),
+            shutdown,
+            open,
+        })
+        .data(metrics)
+        .with_if(
+            !args.cors_allow_origin.is_empty(),
+            Cors::new()
+                .allow_origins(args.cors_allow_origin.iter().map(String::as_str))
+                .allow_methods([Method::GET, Method::POST]),
+        )
+        .with(Tracing)
+        .with(AssignRequestId);
+    let result = Server::new_with_acceptor(acceptor)
+        .name("Fast-pbf-server")
+        .run_with_graceful_shutdown(
+            app,
+            async move {
+                shutdown_signal().await;
+                let _ = shutting_down.send(true);
+            },
+            Some(std::time::Duration::from_secs(args.shutdown_timeout_secs)),
+        )
+        .await;
+    // Upgraded WebSocket connections outlive the server; give them the time
+    // to finish their closing handshakes.
+    let _ = tokio::time::timeout(
+        std::time::Duration::from_secs(args.shutdown_timeout_secs),
+        all_closed.recv(),
+    )
+    .await;
+    if let Some(path) = &args.unix_socket {
+        if let Err(e) = std::fs::remove_file(path) {
+            tracing::warn!("cannot remove socket {}: {}", path, e);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    /// Serves `ws_handler` over an empty index on a free local port.
+    fn serve() -> std::net::SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let (shutting_down, shutdown) = tokio::sync::watch::channel(false);
+        let (open, _all_closed) = tokio::sync::mpsc::channel(1);
+        let app = Route::new()
+            .at("/", get(ws_handler))
+            .data(IndexHandle::new(GeoIndex::new(
+                Vec::new(),
+                Vec::new(),
+                spatial::Kind::Rtree,
+                None,
+                false,
+                false,
+            )))
+            .data(QueryConfig {
+                max_results: 100,
+                max_snap_points: 100,
+                max_match_distance_m: None,
+                timeout: std::time::Duration::from_secs(5),
+                rate_limit: RateLimit::default(),
+                batch_parallelism: 1,
+                coord_order: CoordOrder::Latlon,
+                query_cache: QueryCache::new(0),
+            })
+            .data(Arc::new(Metrics::default()))
+            .data(WsConfig {
+                ping_interval: None,
+                pong_timeout: std::time::Duration::from_secs(5),
+                max_message_bytes: 1 << 16,
+                connections: Arc::new(tokio::sync::Semaphore::new(8)),
+                shutdown,
+                open,
+            })
+            .data(ApiKey::default());
+        let acceptor = TcpAcceptor::from_std(listener).unwrap();
+        tokio::spawn(async move {
+            // Connections close as if shutting down once the sender is gone.
+            let _shutting_down = shutting_down;
+            Server::new_with_acceptor(acceptor).run(app).await
+        });
+        addr
+    }
+
+    /// A WebSocket client speaking raw frames, so it can send what real
+    /// clients refuse to.
+    struct Client(TcpStream);
+
+    impl Client {
+        async fn connect(addr: std::net::SocketAddr) -> Client {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(
+                    b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                      Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                      Sec-WebSocket-Version: 13\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+            assert!(head.starts_with(b"HTTP/1.1 101"));
+            Client(stream)
+        }
+
+        async fn send(&mut self, opcode: u8, payload: &[u8]) {
+            let mask = [0x37, 0xfa, 0x21, 0x3d];
+            let mut frame = vec![0x80 | opcode];
+            match payload.len() {
+                len @ 0..=125 => frame.push(0x80 | len as u8),
+                len => {
+                    frame.push(0x80 | 126);
+                    frame.extend((len as u16).to_be_bytes());
+                }
+            }
+            frame.extend(mask);
+            frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+            self.0.write_all(&frame).await.unwrap();
+        }
+
+        /// The opcode and payload of the next frame, within a second.
+        async fn recv(&mut self) -> (u8, Vec<u8>) {
+            tokio::time::timeout(std::time::Duration::from_secs(1), async {
+                let opcode = self.0.read_u8().await.unwrap() & 0x0f;
+                let len = match self.0.read_u8().await.unwrap() & 0x7f {
+                    126 => self.0.read_u16().await.unwrap() as usize,
+                    127 => self.0.read_u64().await.unwrap() as usize,
+                    len => len as usize,
+                };
+                let mut payload = vec![0; len];
+                self.0.read_exact(&mut payload).await.unwrap();
+                (opcode, payload)
+            })
+            .await
+            .expect("no answer")
+        }
+
+        /// The next frame, decoded, which must be an error answer.
+        async fn recv_error(&mut self) -> String {
+            let response = match self.recv().await {
+                (0x1, text) => serde_json::from_slice::<serde_json::Value>(&text).unwrap(),
+                (0x2, bytes) => msgpack::from_slice(&bytes).unwrap(),
+                (opcode, _) => panic!("expected an answer, got opcode {}", opcode),
+            };
+            assert_eq!(response["success"], false, "{}", response);
+            response["error"].as_str().unwrap().to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_undecodable_binary_frames_with_errors() {
+        let mut client = Client::connect(serve()).await;
+
+        // 0xc1 is the one byte MessagePack never uses.
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let random: Vec<u8> = std::iter::once(0xc1)
+            .chain((0..63).map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            }))
+            .collect();
+        client.send(0x2, &random).await;
+        assert!(client
+            .recv_error()
+            .await
+            .starts_with("Invalid query format"));
+
+        let query = msgpack::to_vec(&serde_json::json!({"lat": 48.8566, "lon": 2.3522}));
+        client.send(0x2, &query[..query.len() / 2]).await;
+        assert!(client
+            .recv_error()
+            .await
+            .starts_with("Invalid query format"));
+
+        // Both left the connection usable.
+        client.send(0x9, b"still there").await;
+        assert_eq!(client.recv().await, (0xa, b"still there".to_vec()));
+    }
+
+    // Invalid UTF-8 still closes the connection; see the README.
+    #[tokio::test]
+    async fn closes_the_connection_after_invalid_utf8_text() {
+        let mut client = Client::connect(serve()).await;
+        client.send(0x1, b"{\"lat\": \xff\xfe}").await;
+        assert!(client.recv_error().await.starts_with("Cannot read frame"));
+        let (opcode, payload) = client.recv().await;
+        assert_eq!(opcode, 0x8);
+        assert_eq!(
+            u16::from_be_bytes([payload[0], payload[1]]),
+            u16::from(CloseCode::Invalid)
+        );
+    }
+
+    #[test]
+    fn leaves_connection_failures_to_the_caller() {
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert!(unreadable_frame(&reset).is_none());
+        let other = std::io::Error::other("Invalid frame header");
+        let (reply, code, _) = unreadable_frame(&other).unwrap();
+        assert_eq!(code, CloseCode::Protocol);
+        assert!(matches!(reply, Message::Text(text) if text.contains("Cannot read frame")));
+    }
 }
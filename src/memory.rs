@@ -0,0 +1,40 @@
+//! Rough heap accounting for the loaded index, counting what the
+//! containers hold rather than asking the allocator. Allocator overhead
+//! and memory freed after building but not returned to the system are not
+//! included, so the process uses somewhat more.
+
+use std::{collections::BTreeMap, mem::size_of};
+
+use rstar::{RTree, RTreeNode, RTreeObject};
+
+/// Bytes allocated for the elements of `items`, not counting anything they
+/// point to.
+pub fn vec<T>(items: &Vec<T>) -> usize {
+    items.capacity() * size_of::<T>()
+}
+
+/// Entries per B-tree node in the standard library's `BTreeMap`.
+const BTREE_NODE_ENTRIES: usize = 11;
+
+/// Bytes held by a map of tags: its nodes, which even for a single entry
+/// have room for [`BTREE_NODE_ENTRIES`], and the strings.
+pub fn tags(tags: &BTreeMap<String, String>) -> usize {
+    let nodes = tags.len().div_ceil(BTREE_NODE_ENTRIES);
+    let strings: usize = tags
+        .iter()
+        .map(|(key, value)| key.capacity() + value.capacity())
+        .sum();
+    nodes * (BTREE_NODE_ENTRIES * size_of::<(String, String)>() + size_of::<usize>()) + strings
+}
+
+/// An R-tree stores each object in a leaf node, and with rstar's default
+/// fan-out about one parent node per three objects.
+pub fn rtree<T: RTreeObject>(tree: &RTree<T>) -> usize {
+    let nodes = tree.size() + tree.size() / 3;
+    nodes * size_of::<RTreeNode<T>>()
+}
+
+/// Megabytes, for log lines.
+pub fn megabytes(bytes: usize) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
@@ -0,0 +1,178 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Upper bounds of the query latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.00001, 0.000025, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.1,
+];
+
+/// Process-wide counters rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    queries_total: AtomicU64,
+    queries_empty: AtomicU64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_sum_ns: AtomicU64,
+    ws_connections: AtomicI64,
+    ws_rejected: AtomicU64,
+    query_cache_hits: AtomicU64,
+    query_cache_misses: AtomicU64,
+}
+
+/// Keeps the WebSocket connection gauge accurate however the connection ends.
+pub struct ConnectionGuard<'a>(&'a Metrics);
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.ws_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn observe_query(&self, elapsed: Duration, found: bool) {
+        self.queries_total.fetch_add(1, Ordering::Relaxed);
+        if !found {
+            self.queries_empty.fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency_sum_ns
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|le| seconds <= *le) {
+            self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn observe_query_cache(&self, hit: bool) {
+        let counter = if hit {
+            &self.query_cache_hits
+        } else {
+            &self.query_cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Query cache hits and misses so far.
+    pub fn query_cache_counts(&self) -> (u64, u64) {
+        (
+            self.query_cache_hits.load(Ordering::Relaxed),
+            self.query_cache_misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Lookups answered so far.
+    pub fn queries_total(&self) -> u64 {
+        self.queries_total.load(Ordering::Relaxed)
+    }
+
+    /// WebSocket connections open right now.
+    pub fn ws_connections(&self) -> i64 {
+        self.ws_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn ws_connected(&self) -> ConnectionGuard<'_> {
+        self.ws_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self)
+    }
+
+    pub fn ws_rejected(&self) {
+        self.ws_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let total = self.queries_total.load(Ordering::Relaxed);
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "# HELP fast_pbf_queries_total Total number of lookups."
+        );
+        let _ = writeln!(out, "# TYPE fast_pbf_queries_total counter");
+        let _ = writeln!(out, "fast_pbf_queries_total {}", total);
+
+        let _ = writeln!(
+            out,
+            "# HELP fast_pbf_queries_empty_total Lookups that found no feature."
+        );
+        let _ = writeln!(out, "# TYPE fast_pbf_queries_empty_total counter");
+        let _ = writeln!(
+            out,
+            "fast_pbf_queries_empty_total {}",
+            self.queries_empty.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP fast_pbf_query_duration_seconds Lookup latency."
+        );
+        let _ = writeln!(out, "# TYPE fast_pbf_query_duration_seconds histogram");
+        let mut cumulative = 0;
+        for (le, bucket) in LATENCY_BUCKETS.iter().zip(&self.latency_buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "fast_pbf_query_duration_seconds_bucket{{le=\"{}\"}} {}",
+                le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "fast_pbf_query_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            total
+        );
+        let _ = writeln!(
+            out,
+            "fast_pbf_query_duration_seconds_sum {}",
+            self.latency_sum_ns.load(Ordering::Relaxed) as f64 / 1e9
+        );
+        let _ = writeln!(out, "fast_pbf_query_duration_seconds_count {}", total);
+
+        let _ = writeln!(
+            out,
+            "# HELP fast_pbf_websocket_connections Open WebSocket connections."
+        );
+        let _ = writeln!(out, "# TYPE fast_pbf_websocket_connections gauge");
+        let _ = writeln!(
+            out,
+            "fast_pbf_websocket_connections {}",
+            self.ws_connections.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP fast_pbf_websocket_rejected_total WebSocket connections refused by --max-connections."
+        );
+        let _ = writeln!(out, "# TYPE fast_pbf_websocket_rejected_total counter");
+        let _ = writeln!(
+            out,
+            "fast_pbf_websocket_rejected_total {}",
+            self.ws_rejected.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP fast_pbf_query_cache_hits_total Lookups answered from --query-cache-size."
+        );
+        let _ = writeln!(out, "# TYPE fast_pbf_query_cache_hits_total counter");
+        let _ = writeln!(
+            out,
+            "fast_pbf_query_cache_hits_total {}",
+            self.query_cache_hits.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP fast_pbf_query_cache_misses_total Lookups the query cache had no result for."
+        );
+        let _ = writeln!(out, "# TYPE fast_pbf_query_cache_misses_total counter");
+        let _ = writeln!(
+            out,
+            "fast_pbf_query_cache_misses_total {}",
+            self.query_cache_misses.load(Ordering::Relaxed)
+        );
+
+        out
+    }
+}
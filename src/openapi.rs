@@ -0,0 +1,419 @@
+use serde_json::{json, Value};
+
+/// Page rendering `/openapi.json` with Swagger UI; the assets come from a CDN.
+pub const DOCS_HTML: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <title>fast-pbf-server API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>SwaggerUIBundle({ url: "openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>
+"##;
+
+/// Hand-maintained OpenAPI 3 description of the HTTP endpoints. Keep it in
+/// step with the request and response types in `main.rs`.
+pub fn spec() -> Value {
+    let format = json!({
+        "name": "format",
+        "in": "query",
+        "schema": { "type": "string", "enum": ["json", "geojson"], "default": "json" },
+        "description": "`geojson` returns a FeatureCollection instead of the envelope on success",
+    });
+    let dms_coordinate = |name: &str, limit: f64, description: &str| {
+        json!({
+            "name": name,
+            "in": "query",
+            "required": true,
+            "schema": { "oneOf": [
+                { "type": "number", "format": "double", "minimum": -limit, "maximum": limit },
+                { "type": "string", "example": "40°44'54.36\"N" },
+            ] },
+            "description": format!("{}, in decimal degrees or as degrees, minutes and seconds", description),
+        })
+    };
+    let coordinate = |name: &str, limit: f64, description: &str| {
+        json!({
+            "name": name,
+            "in": "query",
+            "required": true,
+            "schema": { "type": "number", "format": "double", "minimum": -limit, "maximum": limit },
+            "description": description,
+        })
+    };
+    let stats_bbox = |description: &str| {
+        json!({
+            "type": "object",
+            "nullable": true,
+            "description": description,
+            "properties": {
+                "min_lat": { "type": "number" },
+                "min_lon": { "type": "number" },
+                "max_lat": { "type": "number" },
+                "max_lon": { "type": "number" },
+            },
+        })
+    };
+    let index_info = json!({ "type": "object", "properties": {
+        "features": { "type": "integer" },
+        "build_duration_ms": { "type": "integer" },
+        "from_cache": { "type": "boolean", "description": "Read from `--cache` rather than built" },
+        "loaded_at_unix": { "type": "integer" },
+        "pbf": { "type": "array", "items": { "type": "object", "properties": {
+            "path": { "type": "string", "description": "Absolute path, or the URL as given" },
+            "size_bytes": { "type": "integer" },
+            "modified_unix": { "type": "integer" },
+        } } },
+    } });
+    let languages = json!({ "oneOf": [
+        { "type": "string", "description": "Language codes separated by commas" },
+        { "type": "array", "items": { "type": "string" } },
+    ], "description": "Preferred languages for `wikipedia` in order" });
+    let lookup_response = |data: &str| {
+        json!({
+            "200": {
+                "description": format!("Always 200; check `success`. On success `data` is {}.", data),
+                "content": { "application/json": { "schema": { "oneOf": [
+                    { "$ref": "#/components/schemas/Envelope" },
+                    { "$ref": "#/components/schemas/FeatureCollection" },
+                ] } } },
+            },
+            "401": { "description": "`--api-key` is set and the request didn't carry it" },
+            "429": { "description": "`--rate-limit-per-sec` exceeded" },
+        })
+    };
+
+    let mut cacheable_lookup_response =
+        lookup_response("a DataResponse, or an array of them with `k`, `radius_m` or `categorize`");
+    cacheable_lookup_response["304"] = json!({
+        "description": "The index hasn't changed since the response with the `If-None-Match` ETag",
+    });
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "fast-pbf-server",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Reverse geocoding against an in-memory OpenStreetMap index. The WebSocket API at `/` accepts the same queries as JSON or MessagePack messages. Datasets given as `--pbf name=path` serve the lookup paths, `/health`, `/reload` and the WebSocket API under `/name` as well.",
+        },
+        "security": [{}, { "bearer": [] }],
+        "paths": {
+            "/query": { "get": {
+                "summary": "Nearest features to a point",
+                "parameters": [
+                    dms_coordinate("latitude", 90.0, "Latitude of the query point"),
+                    dms_coordinate("longitude", 180.0, "Longitude of the query point"),
+                    { "name": "k", "in": "query", "schema": { "type": "integer", "minimum": 1 },
+                      "description": "Return up to `k` nearest features as an array, at most `--max-results`" },
+                    { "name": "radius_m", "in": "query", "schema": { "type": "number", "format": "double", "minimum": 0 },
+                      "description": "Return every feature within this many meters; takes precedence over `k`" },
+                    { "name": "max_match_distance_m", "in": "query", "schema": { "type": "number", "format": "double", "minimum": 0 },
+                      "description": "Drop nearest matches farther than this many meters, overriding `--max-match-distance-m`" },
+                    { "name": "exact", "in": "query", "schema": { "type": "boolean", "default": false },
+                      "description": "Only match features at the point itself, within `tolerance_m`; cannot be combined with `radius_m`" },
+                    { "name": "categorize", "in": "query", "schema": { "type": "string" }, "example": "amenity",
+                      "description": "Return the nearest feature for each value of this tag as an array, up to `--max-results`; cannot be combined with `k` or `radius_m`" },
+                    { "name": "tolerance_m", "in": "query", "schema": { "type": "number", "format": "double", "minimum": 0, "default": 1 },
+                      "description": "How far from the point an `exact` match may be, in meters" },
+                    { "name": "filter", "in": "query", "schema": { "type": "string" }, "example": "tourism=museum",
+                      "description": "Only match features carrying these tag values, as `key=value` pairs separated by commas; the keys must be in `--index-tags` or `--store-tags`" },
+                    { "name": "lang", "in": "query", "schema": { "type": "string" }, "example": "de,en",
+                      "description": "Preferred languages for `wikipedia` in order, separated by commas, falling back to the plain `wikipedia` tag" },
+                    { "name": "include_tags", "in": "query", "schema": { "type": "boolean", "default": false },
+                      "description": "Also return the feature's tags kept with `--store-tags`, in `all_tags`" },
+                    { "name": "units", "in": "query", "schema": { "type": "string", "enum": ["m", "km", "mi"], "default": "m" },
+                      "description": "Unit of `distance` in the response" },
+                    { "name": "request_id", "in": "query", "schema": { "type": "string" },
+                      "description": "Echoed back as the response's `request_id` instead of the server's id" },
+                    { "name": "v", "in": "query", "schema": { "type": "integer", "enum": [1, 2], "default": 1 },
+                      "description": "Response schema version: 1 answers each match with just `wikipedia`, 2 with the full DataResponse" },
+                    format.clone(),
+                    { "name": "If-None-Match", "in": "header", "schema": { "type": "string" },
+                      "description": "ETag of an earlier response to the same query" },
+                ],
+                "responses": cacheable_lookup_response,
+            }, "post": {
+                "summary": "Nearest features to a point, with the parameters as a JSON body",
+                "requestBody": { "required": true, "content": { "application/json": { "schema": {
+                    "$ref": "#/components/schemas/QueryParams",
+                } } } },
+                "responses": lookup_response("a DataResponse, or an array of them with `k`, `radius_m` or `categorize`"),
+            } },
+            "/query/batch": { "post": {
+                "summary": "Several nearest-feature queries in one request",
+                "requestBody": { "required": true, "content": { "application/json": { "schema": {
+                    "type": "array", "items": { "$ref": "#/components/schemas/QueryParams" },
+                } } } },
+                "responses": {
+                    "200": {
+                        "description": "One response per query, in order; a bad entry gets an error envelope in its slot. A body that isn't an array gets a single error envelope.",
+                        "content": { "application/json": { "schema": { "oneOf": [
+                            { "type": "array", "items": { "oneOf": [
+                                { "$ref": "#/components/schemas/Envelope" },
+                                { "$ref": "#/components/schemas/FeatureCollection" },
+                            ] } },
+                            { "$ref": "#/components/schemas/Envelope" },
+                        ] } } },
+                    },
+                    "401": { "description": "`--api-key` is set and the request didn't carry it" },
+                    "429": { "description": "`--rate-limit-per-sec` exceeded" },
+                },
+            } },
+            "/snap": { "post": {
+                "summary": "Match each point of a trace to a feature, smoothing over jumps between neighbours",
+                "requestBody": { "required": true, "content": { "application/json": { "schema": {
+                    "type": "object",
+                    "required": ["points"],
+                    "properties": {
+                        "points": { "type": "array", "items": {},
+                                    "description": "The trace in order, each point a coordinate pair in `--coord-order` or an object with `latitude` and `longitude`; at most `--max-snap-points`" },
+                        "candidates": { "type": "integer", "minimum": 1, "maximum": 20, "default": 5,
+                                        "description": "How many of the nearest features each point chooses among" },
+                        "smoothing": { "type": "number", "format": "double", "minimum": 0, "default": 0,
+                                       "description": "Meters of cost per meter of jump between consecutive matches; 0 matches every point on its own" },
+                        "max_match_distance_m": { "type": "number", "format": "double", "minimum": 0 },
+                        "filter": { "type": "object", "additionalProperties": { "type": "string" } },
+                        "lang": languages,
+                        "units": { "type": "string", "enum": ["m", "km", "mi"], "default": "m" },
+                        "request_id": { "type": "string" },
+                    },
+                } } } },
+                "responses": lookup_response("an array with a DataResponse or null for each point, in order"),
+            } },
+            "/bbox": { "get": {
+                "summary": "Features intersecting a bounding box",
+                "parameters": [
+                    coordinate("min_lat", 90.0, "Southern edge"),
+                    coordinate("min_lon", 180.0, "Western edge; greater than max_lon to cross the antimeridian"),
+                    coordinate("max_lat", 90.0, "Northern edge"),
+                    coordinate("max_lon", 180.0, "Eastern edge"),
+                    format,
+                ],
+                "responses": lookup_response("an array of DataResponse, closest to the box center first"),
+            } },
+            "/admin": { "get": {
+                "summary": "Administrative boundaries containing a point",
+                "description": "Requires the server to run with `--admin-boundaries`.",
+                "parameters": [
+                    coordinate("latitude", 90.0, "Latitude of the query point"),
+                    coordinate("longitude", 180.0, "Longitude of the query point"),
+                ],
+                "responses": {
+                    "200": {
+                        "description": "Always 200; check `success`. On success `data` lists the boundaries by ascending `admin_level`.",
+                        "content": { "application/json": { "schema": {
+                            "type": "object",
+                            "required": ["success"],
+                            "properties": {
+                                "success": { "type": "boolean" },
+                                "data": { "type": "array", "items": { "$ref": "#/components/schemas/AdminMatch" } },
+                                "error": { "type": "string" },
+                                "query_time_us": { "type": "integer" },
+                            },
+                        } } },
+                    },
+                    "401": { "description": "`--api-key` is set and the request didn't carry it" },
+                    "429": { "description": "`--rate-limit-per-sec` exceeded" },
+                },
+            } },
+            "/osm/{type}/{id}": { "get": {
+                "summary": "An indexed feature by its OSM identity",
+                "parameters": [
+                    { "name": "type", "in": "path", "required": true,
+                      "schema": { "type": "string", "enum": ["node", "way", "relation"] } },
+                    { "name": "id", "in": "path", "required": true,
+                      "schema": { "type": "integer", "format": "int64" } },
+                ],
+                "responses": lookup_response("a DataResponse located at the feature's first node, with `distance_m` 0"),
+            } },
+            "/health": { "get": {
+                "summary": "Readiness of the index",
+                "security": [],
+                "responses": {
+                    "200": { "description": "Index loaded", "content": { "application/json": { "schema": {
+                        "type": "object",
+                        "properties": {
+                            "status": { "type": "string", "enum": ["ready"] },
+                            "version": { "type": "string", "description": "The server's crate version" },
+                            "index": index_info,
+                        },
+                    } } } },
+                    "503": { "description": "Index still loading" },
+                },
+            } },
+            "/livez": { "get": {
+                "summary": "Liveness probe; answers `ok` whenever the server is up",
+                "security": [],
+                "responses": {
+                    "200": { "description": "Server is up", "content": { "text/plain": { "schema": { "type": "string" } } } },
+                },
+            } },
+            "/readyz": { "get": {
+                "summary": "Readiness probe; `ok` once the index is loaded",
+                "security": [],
+                "responses": {
+                    "200": { "description": "Index loaded", "content": { "text/plain": { "schema": { "type": "string" } } } },
+                    "503": { "description": "Index still loading" },
+                },
+            } },
+            "/reload": { "post": {
+                "summary": "Rebuild the index from the configured pbf files, like SIGHUP",
+                "security": [{ "bearer": [] }],
+                "responses": {
+                    "200": { "description": "The new index has been swapped in", "content": { "application/json": { "schema": {
+                        "type": "object",
+                        "properties": {
+                            "success": { "type": "boolean" },
+                            "data": index_info,
+                        },
+                    } } } },
+                    "401": { "description": "Missing or wrong API key" },
+                    "403": { "description": "The server runs without `--api-key`" },
+                    "409": { "description": "Another reload is in progress" },
+                    "500": { "description": "The rebuild failed; the current index is kept" },
+                },
+            } },
+            "/stats": { "get": {
+                "summary": "What the index contains",
+                "responses": { "200": {
+                    "description": "Counts and extent, computed when the index was built or last updated",
+                    "content": { "application/json": { "schema": {
+                        "type": "object",
+                        "properties": {
+                            "features": { "type": "integer" },
+                            "bbox": stats_bbox("Extent of the indexed geometry"),
+                            "tag_counts": { "type": "object", "additionalProperties": { "type": "integer" } },
+                            "built_at_unix": { "type": "integer" },
+                            "updated_at_unix": { "type": "integer", "nullable": true },
+                            "sources": { "type": "array", "items": { "type": "string" } },
+                            "coverage": stats_bbox("The `--bbox` the index is limited to"),
+                            "memory_bytes": { "type": "integer", "description": "Rough estimate of the heap the index occupies" },
+                        },
+                    } } },
+                } },
+            } },
+            "/metrics": { "get": {
+                "summary": "Prometheus metrics",
+                "responses": { "200": {
+                    "description": "Prometheus text exposition format",
+                    "content": { "text/plain": { "schema": { "type": "string" } } },
+                } },
+            } },
+        },
+        "components": {
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer", "description": "Required only when the server runs with `--api-key`" },
+            },
+            "schemas": {
+                "Envelope": {
+                    "type": "object",
+                    "required": ["success"],
+                    "properties": {
+                        "success": { "type": "boolean" },
+                        "data": { "oneOf": [
+                            { "$ref": "#/components/schemas/DataResponse" },
+                            { "type": "array", "items": { "$ref": "#/components/schemas/DataResponse" } },
+                        ] },
+                        "error": { "type": "string" },
+                        "truncated": { "type": "boolean", "description": "Present and true when a result cap was hit" },
+                        "query_time_us": { "type": "integer", "description": "Microseconds the index lookup took" },
+                        "request_id": { "type": "string",
+                                        "description": "The query's `request_id`, or on `/query` and WebSocket answers the id the server assigned, also sent as `X-Request-Id`" },
+                    },
+                },
+                "DataResponse": {
+                    "type": "object",
+                    "required": ["tags", "distance_m", "distance", "distance_unit", "matched_latitude", "matched_longitude", "osm_type", "osm_id"],
+                    "properties": {
+                        "category": { "type": "string", "description": "For `categorize` queries, the tag value this is the nearest feature for" },
+                        "wikipedia": { "type": "string" },
+                        "wikipedia_lang": { "type": "string", "description": "The language `wikipedia` is in, where known, for queries with `lang`" },
+                        "wikidata": { "type": "string" },
+                        "tags": { "type": "object", "additionalProperties": { "type": "string" } },
+                        "all_tags": { "type": "object", "additionalProperties": { "type": "string" },
+                                      "description": "`tags` together with the `--store-tags` ones; only with `include_tags`" },
+                        "name": { "type": "string" },
+                        "wikipedia_langs": { "type": "object", "additionalProperties": { "type": "string" },
+                                             "description": "Article titles by language code, from `wikipedia:<lang>` tags" },
+                        "distance_m": { "type": "number", "format": "double" },
+                        "distance": { "type": "number", "format": "double",
+                                      "description": "`distance_m` converted to `distance_unit`" },
+                        "distance_unit": { "type": "string", "enum": ["m", "km", "mi"] },
+                        "matched_latitude": { "type": "number", "format": "double" },
+                        "matched_longitude": { "type": "number", "format": "double" },
+                        "osm_type": { "type": "string", "enum": ["node", "way", "relation"] },
+                        "osm_id": { "type": "integer", "format": "int64" },
+                    },
+                },
+                "QueryParams": {
+                    "type": "object",
+                    "required": ["latitude", "longitude"],
+                    "description": "The `/query` parameters, also accepted as WebSocket messages",
+                    "properties": {
+                        "latitude": { "oneOf": [
+                            { "type": "number", "format": "double", "minimum": -90, "maximum": 90 },
+                            { "type": "string", "description": "Degrees, minutes and seconds, e.g. `40°44'54.36\"N`" },
+                        ] },
+                        "longitude": { "oneOf": [
+                            { "type": "number", "format": "double", "minimum": -180, "maximum": 180 },
+                            { "type": "string", "description": "Degrees, minutes and seconds, e.g. `73°59'8.36\"W`" },
+                        ] },
+                        "k": { "type": "integer", "minimum": 1 },
+                        "radius_m": { "type": "number", "format": "double", "minimum": 0 },
+                        "max_match_distance_m": { "type": "number", "format": "double", "minimum": 0 },
+                        "exact": { "type": "boolean", "default": false },
+                        "categorize": { "type": "string", "description": "Tag whose every value gets its nearest feature" },
+                        "tolerance_m": { "type": "number", "format": "double", "minimum": 0, "default": 1 },
+                        "filter": {
+                            "oneOf": [
+                                { "type": "object", "additionalProperties": { "type": "string" } },
+                                { "type": "string" },
+                            ],
+                            "description": "Tag values a match must carry, e.g. `{\"tourism\": \"museum\"}` or `tourism=museum`",
+                        },
+                        "lang": languages,
+                        "include_tags": { "type": "boolean", "default": false },
+                        "units": { "type": "string", "enum": ["m", "km", "mi"], "default": "m" },
+                        "format": { "type": "string", "enum": ["json", "geojson"], "default": "json" },
+                        "v": { "type": "integer", "enum": [1, 2], "default": 1, "description": "Response schema version" },
+                        "request_id": { "type": "string", "description": "Echoed back in the response" },
+                    },
+                },
+                "AdminMatch": {
+                    "type": "object",
+                    "required": ["osm_id", "tags"],
+                    "properties": {
+                        "osm_id": { "type": "integer", "format": "int64", "description": "Id of the boundary relation" },
+                        "name": { "type": "string" },
+                        "admin_level": { "type": "integer" },
+                        "tags": { "type": "object", "additionalProperties": { "type": "string" } },
+                    },
+                },
+                "FeatureCollection": {
+                    "type": "object",
+                    "required": ["type", "features"],
+                    "properties": {
+                        "type": { "type": "string", "enum": ["FeatureCollection"] },
+                        "features": { "type": "array", "items": {
+                            "type": "object",
+                            "properties": {
+                                "type": { "type": "string", "enum": ["Feature"] },
+                                "geometry": { "type": "object", "properties": {
+                                    "type": { "type": "string", "enum": ["Point"] },
+                                    "coordinates": { "type": "array", "items": { "type": "number" }, "minItems": 2, "maxItems": 2,
+                                                     "description": "[longitude, latitude]" },
+                                } },
+                                "properties": { "$ref": "#/components/schemas/DataResponse" },
+                            },
+                        } },
+                        "truncated": { "type": "boolean" },
+                    },
+                },
+            },
+        },
+    })
+}
@@ -0,0 +1,222 @@
+//! Just enough of an XML reader for OsmChange (`.osc`) files: elements,
+//! attributes and entities; text content, namespaces and DTDs are ignored.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Create,
+    Modify,
+    Delete,
+}
+
+#[derive(Debug)]
+pub enum Element {
+    Node {
+        id: i64,
+        /// Missing on deletions.
+        location: Option<[f64; 2]>,
+        tags: Vec<(String, String)>,
+    },
+    Way {
+        id: i64,
+        nodes: Vec<i64>,
+        tags: Vec<(String, String)>,
+    },
+    /// Relations aren't indexed; they are only recognized.
+    Relation,
+}
+
+#[derive(Debug)]
+pub struct Change {
+    pub action: Action,
+    pub element: Element,
+}
+
+struct Tag<'a> {
+    name: &'a str,
+    attributes: Vec<(&'a str, String)>,
+    closing: bool,
+    self_closing: bool,
+}
+
+impl Tag<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn parse<T: std::str::FromStr>(&self, key: &str) -> Result<T, String> {
+        let value = self
+            .get(key)
+            .ok_or_else(|| format!("<{}> without {}", self.name, key))?;
+        value
+            .parse()
+            .map_err(|_| format!("<{}> has invalid {} {:?}", self.name, key, value))
+    }
+}
+
+/// Reads every change in document order.
+pub fn parse(text: &str) -> Result<Vec<Change>, String> {
+    let mut changes = Vec::new();
+    let mut action = None;
+    let mut open: Option<Change> = None;
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start..];
+        // Comments, processing instructions and declarations carry no data.
+        let markup = [("<!--", "-->"), ("<?", "?>"), ("<!", ">")]
+            .into_iter()
+            .find(|(prefix, _)| rest.starts_with(prefix));
+        if let Some((_, end)) = markup {
+            let len = rest.find(end).ok_or("unterminated markup")? + end.len();
+            rest = &rest[len..];
+            continue;
+        }
+        let end = tag_end(rest).ok_or("unterminated tag")?;
+        let tag = tag(&rest[1..end])?;
+        rest = &rest[end + 1..];
+
+        match (tag.name, tag.closing) {
+            ("create", false) => action = Some(Action::Create),
+            ("modify", false) => action = Some(Action::Modify),
+            ("delete", false) => action = Some(Action::Delete),
+            ("create" | "modify" | "delete", true) => action = None,
+            ("node" | "way" | "relation", false) => {
+                let action = action
+                    .ok_or_else(|| format!("<{}> outside of create, modify or delete", tag.name))?;
+                let id = tag.parse("id")?;
+                let element = match tag.name {
+                    "node" => Element::Node {
+                        id,
+                        location: match (tag.get("lat"), tag.get("lon")) {
+                            (Some(_), Some(_)) => Some([tag.parse("lat")?, tag.parse("lon")?]),
+                            _ => None,
+                        },
+                        tags: Vec::new(),
+                    },
+                    "way" => Element::Way {
+                        id,
+                        nodes: Vec::new(),
+                        tags: Vec::new(),
+                    },
+                    _ => Element::Relation,
+                };
+                let change = Change { action, element };
+                if tag.self_closing {
+                    changes.push(change);
+                } else {
+                    open = Some(change);
+                }
+            }
+            ("node" | "way" | "relation", true) => changes.extend(open.take()),
+            ("nd", false) => {
+                if let Some(Change {
+                    element: Element::Way { nodes, .. },
+                    ..
+                }) = &mut open
+                {
+                    nodes.push(tag.parse("ref")?);
+                }
+            }
+            ("tag", false) => {
+                if let Some(Change {
+                    element: Element::Node { tags, .. } | Element::Way { tags, .. },
+                    ..
+                }) = &mut open
+                {
+                    tags.push((tag.parse("k")?, tag.parse("v")?));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(changes)
+}
+
+/// Position of the `>` closing the tag `rest` starts with; attribute values
+/// may contain a bare `>`.
+fn tag_end(rest: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in rest.char_indices() {
+        match (quote, c) {
+            (None, '>') => return Some(i),
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), c) if c == open => quote = None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parses the inside of `<...>`.
+fn tag(inner: &str) -> Result<Tag<'_>, String> {
+    let (closing, inner) = match inner.strip_prefix('/') {
+        Some(inner) => (true, inner),
+        None => (false, inner),
+    };
+    let (self_closing, inner) = match inner.strip_suffix('/') {
+        Some(inner) => (true, inner),
+        None => (false, inner),
+    };
+    let inner = inner.trim();
+    let name_end = inner.find(char::is_whitespace).unwrap_or(inner.len());
+    let (name, mut rest) = inner.split_at(name_end);
+
+    let mut attributes = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        let eq = rest
+            .find('=')
+            .ok_or_else(|| format!("attribute without value in <{}>", name))?;
+        let key = rest[..eq].trim();
+        rest = rest[eq + 1..].trim_start();
+        let quote = rest
+            .chars()
+            .next()
+            .filter(|c| *c == '"' || *c == '\'')
+            .ok_or_else(|| format!("unquoted attribute {} in <{}>", key, name))?;
+        let len = rest[1..]
+            .find(quote)
+            .ok_or_else(|| format!("unterminated attribute {} in <{}>", key, name))?;
+        attributes.push((key, unescape(&rest[1..1 + len])?));
+        rest = &rest[len + 2..];
+    }
+    Ok(Tag {
+        name,
+        attributes,
+        closing,
+        self_closing,
+    })
+}
+
+fn unescape(value: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp + 1..];
+        let semi = rest.find(';').ok_or("unterminated entity")?;
+        let entity = &rest[..semi];
+        let c = match entity {
+            "amp" => '&',
+            "lt" => '<',
+            "gt" => '>',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(|code| char::from_u32(code.ok()?))
+                .ok_or_else(|| format!("unknown entity &{};", entity))?,
+        };
+        out.push(c);
+        rest = &rest[semi + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
@@ -0,0 +1,39 @@
+use std::io::{self, BufRead, BufReader, Read};
+
+use flate2::bufread::MultiGzDecoder;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const BZIP2_MAGIC: [u8; 3] = *b"BZh";
+
+/// A pbf as stored on disk, decompressed on the fly if the whole file was
+/// gzipped (`.osm.pbf.gz`). Detected by magic bytes, not the extension.
+pub enum Input<R> {
+    Plain(BufReader<R>),
+    Gzip(MultiGzDecoder<BufReader<R>>),
+}
+
+impl<R: Read> Input<R> {
+    pub fn new(inner: R) -> io::Result<Input<R>> {
+        let mut reader = BufReader::new(inner);
+        let head = reader.fill_buf()?;
+        if head.starts_with(&GZIP_MAGIC) {
+            Ok(Input::Gzip(MultiGzDecoder::new(reader)))
+        } else if head.starts_with(&BZIP2_MAGIC) {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "bzip2-compressed pbf files are not supported, decompress with bunzip2 first",
+            ))
+        } else {
+            Ok(Input::Plain(reader))
+        }
+    }
+}
+
+impl<R: Read> Read for Input<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Input::Plain(reader) => reader.read(buf),
+            Input::Gzip(reader) => reader.read(buf),
+        }
+    }
+}
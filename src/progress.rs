@@ -0,0 +1,156 @@
+//! Reporting on [`GeoIndex::build`](crate::geo::GeoIndex::build) as it runs,
+//! kept apart from the build so each front-end can follow it its own way.
+
+use std::time::Duration;
+
+use crate::{geo::OsmType, memory};
+
+/// What a build reports, in the order it happens: for each pbf file, its
+/// opening, its blocks and the features they yield, its end; then the end
+/// of the whole build.
+pub enum BuildEvent<'a> {
+    /// `path` was opened, which took `elapsed`.
+    FileOpened { path: &'a str, elapsed: Duration },
+    /// Another block of the current file was processed. The counts are for
+    /// the file so far, except `features`, which counts the whole index.
+    BlockProcessed {
+        blocks: usize,
+        nodes: usize,
+        ways: usize,
+        relations: usize,
+        features: usize,
+        read_bytes: u64,
+        total_bytes: u64,
+        elapsed: Duration,
+    },
+    /// A feature was added to the index.
+    FeatureIndexed { osm_type: OsmType, osm_id: i64 },
+    /// Everything in `path` was read. `duplicates` were already indexed from
+    /// an earlier file and `outside` lay outside `--bbox`. The locations of
+    /// `located` of the `nodes` were held in memory, all of them unless the
+    /// file was read in several passes under `--max-memory-mb`.
+    FileFinished {
+        path: &'a str,
+        nodes: usize,
+        located: usize,
+        ways: usize,
+        relations: usize,
+        lines: usize,
+        duplicates: usize,
+        outside: usize,
+        elapsed: Duration,
+    },
+    /// The index was built from `files` pbf files.
+    Finished {
+        features: usize,
+        files: usize,
+        heap_bytes: usize,
+        elapsed: Duration,
+    },
+}
+
+/// Follows a build. Closures taking a [`BuildEvent`] are observers too.
+pub trait BuildObserver {
+    fn observe(&mut self, event: &BuildEvent);
+}
+
+impl<F: FnMut(&BuildEvent)> BuildObserver for F {
+    fn observe(&mut self, event: &BuildEvent) {
+        self(event)
+    }
+}
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The server's own observer: logs each file and the result, and the
+/// progress through a file with an ETA at most once every
+/// [`PROGRESS_INTERVAL`].
+#[derive(Default)]
+pub struct LogProgress {
+    /// When into the current file progress was last logged.
+    last_report: Duration,
+}
+
+impl BuildObserver for LogProgress {
+    fn observe(&mut self, event: &BuildEvent) {
+        match *event {
+            BuildEvent::FileOpened { path, elapsed } => {
+                self.last_report = Duration::ZERO;
+                tracing::info!("Loaded pbf {} in {}ms", path, elapsed.as_millis());
+            }
+            BuildEvent::BlockProcessed {
+                blocks,
+                nodes,
+                ways,
+                relations,
+                features,
+                read_bytes,
+                total_bytes,
+                elapsed,
+            } => {
+                if elapsed < self.last_report + PROGRESS_INTERVAL {
+                    return;
+                }
+                self.last_report = elapsed;
+                let status = format!(
+                    "{} blocks, {} nodes, {} ways, {} relations, {} features indexed",
+                    blocks, nodes, ways, relations, features
+                );
+                if total_bytes == 0 || read_bytes == 0 {
+                    tracing::info!("Building index: {} after {}s", status, elapsed.as_secs());
+                    return;
+                }
+                let fraction = (read_bytes as f64 / total_bytes as f64).min(1.0);
+                let eta = elapsed.as_secs_f64() * (1.0 - fraction) / fraction;
+                tracing::info!(
+                    "Building index: {}, {:.1}% of pbf read after {}s, ETA {:.0}s",
+                    status,
+                    fraction * 100.0,
+                    elapsed.as_secs(),
+                    eta
+                );
+            }
+            BuildEvent::FeatureIndexed { osm_type, osm_id } => {
+                tracing::trace!("Indexed {} {}", osm_type.as_str(), osm_id);
+            }
+            BuildEvent::FileFinished {
+                path,
+                nodes,
+                located,
+                ways,
+                relations,
+                lines,
+                duplicates,
+                outside,
+                elapsed,
+            } => {
+                tracing::info!(
+                    "Loaded {} nodes ({} located) {} ways {} relations {} lines from {} in {}ms, skipped {} already indexed and {} outside --bbox",
+                    nodes,
+                    located,
+                    ways,
+                    relations,
+                    lines,
+                    path,
+                    elapsed.as_millis(),
+                    duplicates,
+                    outside
+                );
+            }
+            BuildEvent::Finished {
+                features,
+                files,
+                heap_bytes,
+                elapsed,
+            } => {
+                tracing::info!(
+                    "Indexed {} features from {} pbf files in {}ms, taking about {:.1} MB",
+                    features,
+                    files,
+                    elapsed.as_millis(),
+                    memory::megabytes(heap_bytes)
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use crate::geo::TagFilter;
+
+/// Coordinates are rounded to 1e-7 degrees for the key, the precision OSM
+/// stores them with (about a centimeter).
+const SCALE: f64 = 1e7;
+
+/// Everything about a query that determines its result.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Key {
+    lat: i64,
+    lon: i64,
+    k: Option<usize>,
+    radius_m: Option<u64>,
+    max_distance_m: u64,
+    filter: TagFilter,
+    categorize: Option<String>,
+}
+
+impl Key {
+    pub fn new(
+        lat: f64,
+        lon: f64,
+        k: Option<usize>,
+        radius_m: Option<f64>,
+        max_distance_m: f64,
+        filter: &TagFilter,
+        categorize: Option<&str>,
+    ) -> Key {
+        Key {
+            lat: (lat * SCALE).round() as i64,
+            lon: (lon * SCALE).round() as i64,
+            k,
+            radius_m: radius_m.map(f64::to_bits),
+            max_distance_m: max_distance_m.to_bits(),
+            filter: filter.clone(),
+            categorize: categorize.map(str::to_string),
+        }
+    }
+}
+
+struct Lru<V> {
+    capacity: usize,
+    /// Revision of the index the entries were looked up in.
+    revision: u64,
+    tick: u64,
+    /// Each value with the tick it was last used at.
+    entries: HashMap<Key, (V, u64)>,
+    /// Keys by the tick they were last used at, oldest first.
+    order: BTreeMap<u64, Key>,
+}
+
+/// The `--query-cache-size` most recently used lookup results; `None`
+/// means disabled. Entries only ever answer for the index revision they
+/// were looked up in, so a reloaded or updated index starts out empty.
+#[derive(Clone)]
+pub struct QueryCache<V>(Option<Arc<Mutex<Lru<V>>>>);
+
+impl<V> Default for QueryCache<V> {
+    fn default() -> Self {
+        QueryCache(None)
+    }
+}
+
+impl<V> std::fmt::Debug for QueryCache<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let capacity = self.0.as_ref().map(|lru| lru.lock().unwrap().capacity);
+        f.debug_tuple("QueryCache").field(&capacity).finish()
+    }
+}
+
+impl<V: Clone> QueryCache<V> {
+    pub fn new(capacity: usize) -> Self {
+        QueryCache((capacity > 0).then(|| {
+            Arc::new(Mutex::new(Lru {
+                capacity,
+                revision: 0,
+                tick: 0,
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+            }))
+        }))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn get(&self, revision: u64, key: &Key) -> Option<V> {
+        let mut lru = self.0.as_ref()?.lock().unwrap();
+        let lru = &mut *lru;
+        if lru.revision != revision {
+            return None;
+        }
+        let (value, used) = lru.entries.get_mut(key)?;
+        lru.tick += 1;
+        lru.order.remove(used);
+        lru.order.insert(lru.tick, key.clone());
+        *used = lru.tick;
+        Some(value.clone())
+    }
+
+    pub fn insert(&self, revision: u64, key: Key, value: V) {
+        let Some(lru) = &self.0 else {
+            return;
+        };
+        let mut lru = lru.lock().unwrap();
+        let lru = &mut *lru;
+        match revision.cmp(&lru.revision) {
+            // Looked up in an index that has been replaced since.
+            std::cmp::Ordering::Less => return,
+            std::cmp::Ordering::Greater => {
+                lru.entries.clear();
+                lru.order.clear();
+                lru.revision = revision;
+            }
+            std::cmp::Ordering::Equal => {}
+        }
+        lru.tick += 1;
+        if let Some((_, used)) = lru.entries.insert(key.clone(), (value, lru.tick)) {
+            lru.order.remove(&used);
+        }
+        lru.order.insert(lru.tick, key);
+        while lru.entries.len() > lru.capacity {
+            let Some((_, oldest)) = lru.order.pop_first() else {
+                break;
+            };
+            lru.entries.remove(&oldest);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use poem::{
+    http::StatusCode, web::Json, Endpoint, IntoResponse, Middleware, Request, Response, Result,
+};
+
+/// Buckets are swept for idle clients once the table grows past this.
+const SWEEP_THRESHOLD: usize = 10_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct Limiter {
+    per_sec: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+/// Token bucket per client IP holding up to one second's worth of requests;
+/// `None` means unlimited.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimit(Option<Arc<Limiter>>);
+
+impl RateLimit {
+    pub fn new(per_sec: Option<f64>) -> Self {
+        RateLimit(per_sec.map(|per_sec| {
+            Arc::new(Limiter {
+                per_sec,
+                buckets: Mutex::new(HashMap::new()),
+            })
+        }))
+    }
+
+    /// Takes a token for `ip`, returning false if its bucket is empty.
+    /// Requests without a known IP are never limited.
+    pub fn allow(&self, ip: Option<IpAddr>) -> bool {
+        let (Some(limiter), Some(ip)) = (&self.0, ip) else {
+            return true;
+        };
+        let burst = limiter.per_sec.max(1.0);
+        let now = Instant::now();
+        let mut buckets = limiter.buckets.lock().unwrap();
+
+        if buckets.len() >= SWEEP_THRESHOLD {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * limiter.per_sec
+                    < burst
+            });
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * limiter.per_sec;
+        bucket.tokens = (bucket.tokens + refill).min(burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+pub fn client_ip(req: &Request) -> Option<IpAddr> {
+    req.remote_addr().as_socket_addr().map(|addr| addr.ip())
+}
+
+impl<E: Endpoint> Middleware<E> for RateLimit {
+    type Output = RateLimitEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        RateLimitEndpoint {
+            inner: ep,
+            limit: self.clone(),
+        }
+    }
+}
+
+/// Answers 429 once the client has used up its bucket.
+pub struct RateLimitEndpoint<E> {
+    inner: E,
+    limit: RateLimit,
+}
+
+#[poem::async_trait]
+impl<E: Endpoint> Endpoint for RateLimitEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        if !self.limit.allow(client_ip(&req)) {
+            return Ok(Json(
+                serde_json::json!({ "success": false, "error": "Rate limit exceeded" }),
+            )
+            .with_status(StatusCode::TOO_MANY_REQUESTS)
+            .into_response());
+        }
+        self.inner.call(req).await.map(IntoResponse::into_response)
+    }
+}
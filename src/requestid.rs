@@ -0,0 +1,78 @@
+use std::{
+    hash::{BuildHasher, Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+
+use poem::{http::HeaderValue, Endpoint, IntoResponse, Middleware, Request, Response, Result};
+use tracing::Instrument;
+
+pub const HEADER: &str = "x-request-id";
+
+/// Identifies one HTTP request in logs and in its response.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+/// A fresh id: a prefix random per process, so ids of restarted or parallel
+/// servers don't collide, then a counter.
+pub fn next() -> String {
+    static PREFIX: OnceLock<u32> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(1);
+    let prefix = PREFIX.get_or_init(|| {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        std::time::SystemTime::now().hash(&mut hasher);
+        std::process::id().hash(&mut hasher);
+        hasher.finish() as u32
+    });
+    format!("{:08x}-{}", prefix, COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Ids clients send in `X-Request-Id` are kept if they are short and
+/// printable, so they can't flood or garble the logs.
+fn is_acceptable(id: &str) -> bool {
+    (1..=128).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Gives each request an id, the client's `X-Request-Id` if it sent a usable
+/// one and [`next`] otherwise. Handlers find it as a [`RequestId`] in the
+/// request's data, everything logged while answering carries it in a
+/// `request_id` span field, and the response returns it in `X-Request-Id`.
+pub struct AssignRequestId;
+
+impl<E: Endpoint> Middleware<E> for AssignRequestId {
+    type Output = AssignRequestIdEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        AssignRequestIdEndpoint { inner: ep }
+    }
+}
+
+pub struct AssignRequestIdEndpoint<E> {
+    inner: E,
+}
+
+#[poem::async_trait]
+impl<E: Endpoint> Endpoint for AssignRequestIdEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, mut req: Request) -> Result<Self::Output> {
+        let id = req
+            .headers()
+            .get(HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| is_acceptable(id))
+            .map_or_else(next, str::to_string);
+        req.extensions_mut().insert(RequestId(id.clone()));
+        let span = tracing::info_span!("req", request_id = %id);
+        let mut resp = match self.inner.call(req).instrument(span).await {
+            Ok(resp) => resp.into_response(),
+            Err(e) => e.into_response(),
+        };
+        if let Ok(value) = HeaderValue::from_str(&id) {
+            resp.headers_mut().insert(HEADER, value);
+        }
+        Ok(resp)
+    }
+}
@@ -0,0 +1,420 @@
+//! A spatial backend over S2 cells: the sphere is projected onto the six
+//! faces of a cube, each face split into a quadtree of cells numbered along
+//! a Hilbert curve, so cells close in id are close on the ground.
+
+use std::collections::{BinaryHeap, HashSet};
+
+use rstar::{Envelope, PointDistance, RTreeObject, AABB};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    memory,
+    spatial::{Candidate, Segment, SpatialIndex},
+};
+
+/// Finest level the index picks; cells there are about 2m across.
+const MAX_LEVEL: u8 = 24;
+/// Levels of the id, as in S2.
+const ID_LEVELS: u8 = 30;
+/// A segment is sampled at most about this many times; longer ones are
+/// stored in coarser cells.
+const SAMPLES_PER_SEGMENT: f64 = 16.0;
+/// Slack for rounding when points land on cell boundaries, in degrees.
+const EPSILON: f64 = 1e-9;
+
+/// Hilbert curve position of each `(i << 1) | j` quadrant, per orientation.
+const IJ_TO_POS: [[u64; 4]; 4] = [[0, 1, 3, 2], [0, 3, 1, 2], [2, 3, 1, 0], [2, 1, 3, 0]];
+/// How the curve's orientation changes entering each position.
+const POS_TO_ORIENTATION: [usize; 4] = [1, 0, 0, 3];
+
+fn to_xyz([lat, lon]: [f64; 2]) -> [f64; 3] {
+    let (lat, lon) = (lat.to_radians(), lon.to_radians());
+    [lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin()]
+}
+
+fn to_latlon([x, y, z]: [f64; 3]) -> [f64; 2] {
+    [z.atan2(x.hypot(y)).to_degrees(), y.atan2(x).to_degrees()]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+/// S2's quadratic transform, which evens out cell areas across a face.
+fn st_to_uv(s: f64) -> f64 {
+    if s >= 0.5 {
+        (4.0 * s * s - 1.0) / 3.0
+    } else {
+        (1.0 - 4.0 * (1.0 - s) * (1.0 - s)) / 3.0
+    }
+}
+
+fn uv_to_st(u: f64) -> f64 {
+    if u >= 0.0 {
+        0.5 * (1.0 + 3.0 * u).sqrt()
+    } else {
+        1.0 - 0.5 * (1.0 - 3.0 * u).sqrt()
+    }
+}
+
+fn face_uv_to_xyz(face: u8, u: f64, v: f64) -> [f64; 3] {
+    match face {
+        0 => [1.0, u, v],
+        1 => [-u, 1.0, v],
+        2 => [-u, -v, 1.0],
+        3 => [-1.0, -v, -u],
+        4 => [v, -1.0, -u],
+        _ => [v, u, -1.0],
+    }
+}
+
+fn xyz_to_face_uv([x, y, z]: [f64; 3]) -> (u8, f64, f64) {
+    let axis = if x.abs() >= y.abs() && x.abs() >= z.abs() {
+        0
+    } else if y.abs() >= z.abs() {
+        1
+    } else {
+        2
+    };
+    let negative = [x, y, z][axis] < 0.0;
+    match (axis, negative) {
+        (0, false) => (0, y / x, z / x),
+        (1, false) => (1, -x / y, z / y),
+        (2, false) => (2, -x / z, -y / z),
+        (0, true) => (3, z / x, y / x),
+        (1, true) => (4, z / y, -x / y),
+        _ => (5, -y / z, -x / z),
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Cell {
+    face: u8,
+    level: u8,
+    i: u32,
+    j: u32,
+}
+
+impl Cell {
+    fn faces() -> impl Iterator<Item = Cell> {
+        (0..6).map(|face| Cell {
+            face,
+            level: 0,
+            i: 0,
+            j: 0,
+        })
+    }
+
+    fn containing(point: [f64; 2], level: u8) -> Cell {
+        let (face, u, v) = xyz_to_face_uv(to_xyz(point));
+        let size = 1u64 << level;
+        let index = |u: f64| ((uv_to_st(u) * size as f64) as u64).min(size - 1) as u32;
+        Cell {
+            face,
+            level,
+            i: index(u),
+            j: index(v),
+        }
+    }
+
+    /// The S2 cell id: three bits of face, two bits per level of Hilbert
+    /// curve position, and a trailing set bit marking the level.
+    fn id(self) -> u64 {
+        let mut orientation = (self.face & 1) as usize;
+        let mut pos = 0;
+        for bit in (0..self.level).rev() {
+            let ij = (((self.i >> bit) & 1) << 1 | ((self.j >> bit) & 1)) as usize;
+            let quadrant = IJ_TO_POS[orientation][ij];
+            pos = pos << 2 | quadrant;
+            orientation ^= POS_TO_ORIENTATION[quadrant as usize];
+        }
+        let shift = 2 * (ID_LEVELS - self.level) as u64;
+        (self.face as u64) << 61 | pos << (shift + 1) | 1 << shift
+    }
+
+    /// Ids of this cell and all of its descendants.
+    fn range(self) -> std::ops::RangeInclusive<u64> {
+        let id = self.id();
+        let lsb = id & id.wrapping_neg();
+        id - (lsb - 1)..=id + (lsb - 1)
+    }
+
+    fn children(self) -> [Cell; 4] {
+        [(0, 0), (0, 1), (1, 0), (1, 1)].map(|(di, dj)| Cell {
+            face: self.face,
+            level: self.level + 1,
+            i: 2 * self.i + di,
+            j: 2 * self.j + dj,
+        })
+    }
+
+    /// A `[lat, lon]` box around the cell. Its edges are great circle arcs,
+    /// so a latitude extreme may lie between two corners.
+    fn bounds(self) -> AABB<[f64; 2]> {
+        let size = (1u64 << self.level) as f64;
+        let st = |k: u32| k as f64 / size;
+        let (s0, s1, t0, t1) = (st(self.i), st(self.i + 1), st(self.j), st(self.j + 1));
+        let corners = [(s0, t0), (s1, t0), (s1, t1), (s0, t1)]
+            .map(|(s, t)| face_uv_to_xyz(self.face, st_to_uv(s), st_to_uv(t)));
+
+        let (mut lat_lo, mut lat_hi) = (f64::INFINITY, f64::NEG_INFINITY);
+        let (mut lon_lo, mut lon_hi) = (f64::INFINITY, f64::NEG_INFINITY);
+        for (k, &a) in corners.iter().enumerate() {
+            let [lat, lon] = to_latlon(a);
+            (lat_lo, lat_hi) = (lat_lo.min(lat), lat_hi.max(lat));
+            (lon_lo, lon_hi) = (lon_lo.min(lon), lon_hi.max(lon));
+            let b = corners[(k + 1) % 4];
+            let normal = cross(a, b);
+            let norm = dot(normal, normal).sqrt();
+            if norm < 1e-15 {
+                continue;
+            }
+            let normal = normal.map(|c| c / norm);
+            // The highest point of the edge's great circle, and its lowest.
+            let top = [
+                -normal[2] * normal[0],
+                -normal[2] * normal[1],
+                1.0 - normal[2] * normal[2],
+            ];
+            let length = dot(top, top).sqrt();
+            if length < 1e-15 {
+                continue;
+            }
+            for extreme in [top.map(|c| c / length), top.map(|c| -c / length)] {
+                let on_edge =
+                    dot(cross(a, extreme), normal) >= 0.0 && dot(cross(extreme, b), normal) >= 0.0;
+                if on_edge {
+                    let lat = to_latlon(extreme)[0];
+                    (lat_lo, lat_hi) = (lat_lo.min(lat), lat_hi.max(lat));
+                }
+            }
+        }
+
+        // Cells around a pole span every longitude, as do cells crossing
+        // the antimeridian or touching it, whose points may be given as
+        // either 180 or -180.
+        let holds = |s: f64, t: f64| (s0..=s1).contains(&s) && (t0..=t1).contains(&t);
+        let north = self.face == 2 && holds(0.5, 0.5);
+        let south = self.face == 5 && holds(0.5, 0.5);
+        if north {
+            lat_hi = 90.0;
+        }
+        if south {
+            lat_lo = -90.0;
+        }
+        if north || south || lon_hi - lon_lo > 180.0 || lon_hi.max(-lon_lo) >= 180.0 - EPSILON {
+            (lon_lo, lon_hi) = (-180.0, 180.0);
+        }
+        AABB::from_corners(
+            [lat_lo - EPSILON, lon_lo - EPSILON],
+            [lat_hi + EPSILON, lon_hi + EPSILON],
+        )
+    }
+}
+
+/// How far apart, in degrees, the samples of a segment stored at `level`
+/// are; every point of the segment is within half that of a sample.
+fn spacing(level: u8) -> f64 {
+    45.0 / (1u64 << level) as f64
+}
+
+/// Segments filed under the S2 cells their sample points fall in.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct S2Index {
+    segments: Vec<Segment>,
+    /// Level of the finest cells, picked from the data's density.
+    level: u8,
+    /// `(cell id, segment)`, sorted by id. Long segments are filed under
+    /// coarser cells than short ones.
+    entries: Vec<(u64, u32)>,
+}
+
+impl S2Index {
+    /// Entries of the cell itself rather than its descendants.
+    fn entries_at(&self, cell: Cell) -> impl Iterator<Item = u32> + '_ {
+        let id = cell.id();
+        let start = self.entries.partition_point(|&(entry, _)| entry < id);
+        self.entries[start..]
+            .iter()
+            .take_while(move |&&(entry, _)| entry == id)
+            .map(|&(_, segment)| segment)
+    }
+
+    /// Whether anything is filed under the cell or below it.
+    fn is_occupied(&self, cell: Cell) -> bool {
+        let range = cell.range();
+        let start = self.entries.partition_point(|&(id, _)| id < *range.start());
+        self.entries
+            .get(start)
+            .is_some_and(|&(id, _)| id <= *range.end())
+    }
+
+    /// Lower bound on the squared distance from `point` to any segment
+    /// filed under the cell or below it.
+    fn bound_2(cell: Cell, point: [f64; 2]) -> f64 {
+        let distance = cell.bounds().distance_2(&point).sqrt() - spacing(cell.level) / 2.0;
+        distance.max(0.0).powi(2)
+    }
+}
+
+impl SpatialIndex for S2Index {
+    fn build(segments: Vec<Segment>) -> Self {
+        let bounds = segments
+            .iter()
+            .map(|segment| segment.envelope())
+            .reduce(|a, b| a.merged(&b))
+            .unwrap_or_else(|| AABB::from_point([0.0, 0.0]));
+        let (lower, upper) = (bounds.lower(), bounds.upper());
+        let area = ((upper[0] - lower[0]) * (upper[1] - lower[1])).max(1e-12);
+        // Aim for a couple of segments per cell of the covered area, like
+        // the grid does.
+        let coverage = (area / (180.0 * 360.0)).min(1.0);
+        let cells = segments.len().max(1) as f64 / 2.0 / coverage / 6.0;
+        let level = (cells.log(4.0).round().max(0.0) as u8).min(MAX_LEVEL);
+
+        let mut entries = Vec::new();
+        for (k, segment) in segments.iter().enumerate() {
+            let [from, to] = [segment.geom().from, segment.geom().to];
+            let length = (to[0] - from[0]).hypot(to[1] - from[1]);
+            let segment_level = if length > 0.0 {
+                (45.0 * SAMPLES_PER_SEGMENT / length)
+                    .log2()
+                    .floor()
+                    .clamp(0.0, level as f64) as u8
+            } else {
+                level
+            };
+            let samples = (length / spacing(segment_level)).ceil().max(1.0) as usize;
+            let start = entries.len();
+            for sample in 0..=samples {
+                let t = sample as f64 / samples as f64;
+                let point = [0, 1].map(|axis| from[axis] + (to[axis] - from[axis]) * t);
+                entries.push((Cell::containing(point, segment_level).id(), k as u32));
+            }
+            entries[start..].sort_unstable();
+            let mut filed = entries.split_off(start);
+            filed.dedup();
+            entries.extend(filed);
+        }
+        entries.sort_unstable();
+        S2Index {
+            segments,
+            level,
+            entries,
+        }
+    }
+
+    fn nearest<'a>(&'a self, point: [f64; 2]) -> Box<dyn Iterator<Item = &'a Segment> + 'a> {
+        let heap = Cell::faces()
+            .filter(|&face| self.is_occupied(face))
+            .map(|face| Candidate {
+                distance_2: S2Index::bound_2(face, point),
+                item: S2Item::Cell(face),
+            })
+            .collect();
+        Box::new(S2Nearest {
+            index: self,
+            point,
+            heap,
+            seen: HashSet::new(),
+        })
+    }
+
+    fn in_envelope<'a>(
+        &'a self,
+        envelope: AABB<[f64; 2]>,
+    ) -> Box<dyn Iterator<Item = &'a Segment> + 'a> {
+        let mut seen = HashSet::new();
+        let mut found = Vec::new();
+        let mut stack: Vec<Cell> = Cell::faces().collect();
+        while let Some(cell) = stack.pop() {
+            if !self.is_occupied(cell) {
+                continue;
+            }
+            let bounds = cell.bounds();
+            let slack = spacing(cell.level) / 2.0;
+            let reach = AABB::from_corners(
+                [bounds.lower()[0] - slack, bounds.lower()[1] - slack],
+                [bounds.upper()[0] + slack, bounds.upper()[1] + slack],
+            );
+            if !reach.intersects(&envelope) {
+                continue;
+            }
+            found.extend(
+                self.entries_at(cell)
+                    .filter(|&segment| seen.insert(segment))
+                    .map(|segment| &self.segments[segment as usize])
+                    .filter(|segment| segment.envelope().intersects(&envelope)),
+            );
+            if cell.level < self.level {
+                stack.extend(cell.children());
+            }
+        }
+        Box::new(found.into_iter())
+    }
+
+    fn into_segments(self) -> Vec<Segment> {
+        self.segments
+    }
+
+    fn heap_bytes(&self) -> usize {
+        memory::vec(&self.segments) + memory::vec(&self.entries)
+    }
+}
+
+enum S2Item {
+    Cell(Cell),
+    Segment(u32),
+}
+
+/// Best-first descent from the six faces: cells are queued by
+/// [`S2Index::bound_2`], which never exceeds the distance of a segment filed
+/// under them.
+struct S2Nearest<'a> {
+    index: &'a S2Index,
+    point: [f64; 2],
+    heap: BinaryHeap<Candidate<S2Item>>,
+    /// Segments filed under several cells are only queued once.
+    seen: HashSet<u32>,
+}
+
+impl<'a> Iterator for S2Nearest<'a> {
+    type Item = &'a Segment;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(Candidate { item, .. }) = self.heap.pop() {
+            let cell = match item {
+                S2Item::Segment(i) => return Some(&self.index.segments[i as usize]),
+                S2Item::Cell(cell) => cell,
+            };
+            for i in self.index.entries_at(cell) {
+                if self.seen.insert(i) {
+                    let distance_2 = self.index.segments[i as usize].distance_2(&self.point);
+                    self.heap.push(Candidate {
+                        distance_2,
+                        item: S2Item::Segment(i),
+                    });
+                }
+            }
+            if cell.level < self.index.level {
+                for child in cell.children() {
+                    if self.index.is_occupied(child) {
+                        self.heap.push(Candidate {
+                            distance_2: S2Index::bound_2(child, self.point),
+                            item: S2Item::Cell(child),
+                        });
+                    }
+                }
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,129 @@
+//! Matching a trace of points, such as a GPS track, to indexed features,
+//! smoothed so a single stray point doesn't flip the match.
+
+use crate::geo::{GeoIndex, Match, Metric, TagFilter};
+
+/// The match for each of `points`, chosen among its `candidates` nearest
+/// features within `max_distance_m`. Each match costs its distance to the
+/// point, and each step between consecutive matches costs `smoothing` times
+/// the meters of [`jump`], so following one way along the trace is cheap
+/// and a detour to a neighbour is not. The cheapest sequence wins. With
+/// `smoothing` 0 every point gets its nearest feature. Points without
+/// candidates get `None` and split the trace into parts matched on their
+/// own.
+pub fn snap(
+    geo: &GeoIndex,
+    points: &[[f64; 2]],
+    candidates: usize,
+    max_distance_m: f64,
+    smoothing: f64,
+    filter: &TagFilter,
+) -> Vec<Option<Match>> {
+    let mut options: Vec<Vec<Match>> = points
+        .iter()
+        .map(|&[lat, lon]| {
+            let mut found = geo.find_k_nearest(lat, lon, candidates, filter);
+            found.retain(|found| found.distance_m <= max_distance_m);
+            found
+        })
+        .collect();
+    let mut snapped = Vec::with_capacity(points.len());
+    let mut start = 0;
+    while start < points.len() {
+        if options[start].is_empty() {
+            snapped.push(None);
+            start += 1;
+            continue;
+        }
+        let end = (start..points.len())
+            .find(|&i| options[i].is_empty())
+            .unwrap_or(points.len());
+        let path = cheapest(
+            &points[start..end],
+            &options[start..end],
+            geo.metric(),
+            smoothing,
+        );
+        for (i, choice) in (start..end).zip(path) {
+            snapped.push(Some(options[i].swap_remove(choice)));
+        }
+        start = end;
+    }
+    snapped
+}
+
+/// The index into each point's candidates of the cheapest sequence, found
+/// by dynamic programming over consecutive points (Viterbi). Every point
+/// must have a candidate.
+fn cheapest(
+    points: &[[f64; 2]],
+    options: &[Vec<Match>],
+    metric: Metric,
+    smoothing: f64,
+) -> Vec<usize> {
+    // The cost of the cheapest sequence ending in each candidate of the
+    // current point, and for each later point, which candidate of the one
+    // before that sequence came from.
+    let mut cost: Vec<f64> = options[0].iter().map(|found| found.distance_m).collect();
+    let mut from: Vec<Vec<usize>> = Vec::with_capacity(points.len().saturating_sub(1));
+    for i in 1..points.len() {
+        let moved = metric.distance_m(points[i - 1], points[i]);
+        let (next_cost, next_from) = options[i]
+            .iter()
+            .map(|here| {
+                let (before, step) = options[i - 1]
+                    .iter()
+                    .enumerate()
+                    .map(|(j, there)| {
+                        let jump = jump(metric, &options[i - 1], there, here, moved);
+                        (j, cost[j] + smoothing * jump)
+                    })
+                    .fold((0, f64::INFINITY), |best, step| {
+                        if step.1 < best.1 {
+                            step
+                        } else {
+                            best
+                        }
+                    });
+                (step + here.distance_m, before)
+            })
+            .unzip();
+        cost = next_cost;
+        from.push(next_from);
+    }
+    let mut choice = (0..cost.len())
+        .min_by(|&a, &b| cost[a].total_cmp(&cost[b]))
+        .unwrap_or(0);
+    let mut path = vec![choice];
+    for before in from.iter().rev() {
+        choice = before[choice];
+        path.push(choice);
+    }
+    path.reverse();
+    path
+}
+
+/// How implausible it is to go from the match `there` to `here` when the
+/// points moved `moved` meters: the difference between how far the matches
+/// and the points moved, plus, for a change of feature, how far apart the
+/// two features were at the point before. At that point, `previous` are the
+/// candidates and `there` the match. Turning onto a way at a junction then
+/// costs little, while hopping to a parallel one costs its distance. A
+/// feature that wasn't a candidate there counts as the whole jump away.
+fn jump(metric: Metric, previous: &[Match], there: &Match, here: &Match, moved: f64) -> f64 {
+    let same = |a: &Match, b: &Match| {
+        (a.feature.osm_type, a.feature.osm_id) == (b.feature.osm_type, b.feature.osm_id)
+    };
+    let jumped = metric.distance_m(there.location, here.location);
+    let switch = if same(there, here) {
+        0.0
+    } else {
+        previous
+            .iter()
+            .find(|candidate| same(candidate, here))
+            .map_or(jumped, |candidate| {
+                metric.distance_m(there.location, candidate.location)
+            })
+    };
+    (jumped - moved).abs() + switch
+}
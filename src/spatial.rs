@@ -0,0 +1,603 @@
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashSet},
+};
+
+use rstar::{
+    primitives::{GeomWithData, Line},
+    Envelope, PointDistance, RTree, RTreeObject, AABB,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{memory, s2::S2Index};
+
+/// A piece of a feature's geometry, carrying the feature's position in
+/// `GeoIndex::features`.
+pub type Segment = GeomWithData<Line<[f64; 2]>, usize>;
+
+/// Spatial structure over feature segments. Distances are planar squared
+/// degrees, like rstar's, so every backend ranks candidates identically.
+pub trait SpatialIndex {
+    fn build(segments: Vec<Segment>) -> Self
+    where
+        Self: Sized;
+
+    /// All segments, nearest to `point` first.
+    fn nearest<'a>(&'a self, point: [f64; 2]) -> Box<dyn Iterator<Item = &'a Segment> + 'a>;
+
+    /// Segments whose bounding box intersects `envelope`.
+    fn in_envelope<'a>(
+        &'a self,
+        envelope: AABB<[f64; 2]>,
+    ) -> Box<dyn Iterator<Item = &'a Segment> + 'a>;
+
+    fn into_segments(self) -> Vec<Segment>;
+
+    /// Rough heap footprint, see [`crate::memory`].
+    fn heap_bytes(&self) -> usize;
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Grid,
+    Kdtree,
+    Rtree,
+    S2,
+}
+
+/// The backend picked with `--index`, serialized with its variant so a cache
+/// remembers which one it was built with.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Backend {
+    Grid(Grid),
+    KdTree(KdTree),
+    RTree(RTree<Segment>),
+    S2(S2Index),
+}
+
+impl Backend {
+    /// `grid_cell_degrees` sets the cell size of a [`Kind::Grid`] index, which
+    /// otherwise follows the density of `segments`; other kinds ignore it.
+    pub fn new(kind: Kind, segments: Vec<Segment>, grid_cell_degrees: Option<f64>) -> Backend {
+        match kind {
+            Kind::Grid => Backend::Grid(Grid::with_cell_size(segments, grid_cell_degrees)),
+            Kind::Kdtree => Backend::KdTree(KdTree::build(segments)),
+            Kind::Rtree => Backend::RTree(SpatialIndex::build(segments)),
+            Kind::S2 => Backend::S2(S2Index::build(segments)),
+        }
+    }
+
+    pub fn kind(&self) -> Kind {
+        match self {
+            Backend::Grid(_) => Kind::Grid,
+            Backend::KdTree(_) => Kind::Kdtree,
+            Backend::RTree(_) => Kind::Rtree,
+            Backend::S2(_) => Kind::S2,
+        }
+    }
+
+    pub fn nearest<'a>(&'a self, point: [f64; 2]) -> Box<dyn Iterator<Item = &'a Segment> + 'a> {
+        match self {
+            Backend::Grid(index) => index.nearest(point),
+            Backend::KdTree(index) => index.nearest(point),
+            Backend::RTree(index) => index.nearest(point),
+            Backend::S2(index) => index.nearest(point),
+        }
+    }
+
+    pub fn in_envelope<'a>(
+        &'a self,
+        envelope: AABB<[f64; 2]>,
+    ) -> Box<dyn Iterator<Item = &'a Segment> + 'a> {
+        match self {
+            Backend::Grid(index) => index.in_envelope(envelope),
+            Backend::KdTree(index) => index.in_envelope(envelope),
+            Backend::RTree(index) => index.in_envelope(envelope),
+            Backend::S2(index) => index.in_envelope(envelope),
+        }
+    }
+
+    pub fn into_segments(self) -> Vec<Segment> {
+        match self {
+            Backend::Grid(index) => index.into_segments(),
+            Backend::KdTree(index) => index.into_segments(),
+            Backend::RTree(index) => index.into_segments(),
+            Backend::S2(index) => index.into_segments(),
+        }
+    }
+
+    pub fn heap_bytes(&self) -> usize {
+        match self {
+            Backend::Grid(index) => index.heap_bytes(),
+            Backend::KdTree(index) => index.heap_bytes(),
+            Backend::RTree(index) => index.heap_bytes(),
+            Backend::S2(index) => index.heap_bytes(),
+        }
+    }
+}
+
+impl SpatialIndex for RTree<Segment> {
+    fn build(segments: Vec<Segment>) -> Self {
+        RTree::bulk_load(segments)
+    }
+
+    fn nearest<'a>(&'a self, point: [f64; 2]) -> Box<dyn Iterator<Item = &'a Segment> + 'a> {
+        Box::new(
+            self.nearest_neighbor_iter_with_distance_2(&point)
+                .map(|(segment, _)| segment),
+        )
+    }
+
+    fn in_envelope<'a>(
+        &'a self,
+        envelope: AABB<[f64; 2]>,
+    ) -> Box<dyn Iterator<Item = &'a Segment> + 'a> {
+        Box::new(self.locate_in_envelope_intersecting(&envelope))
+    }
+
+    fn into_segments(mut self) -> Vec<Segment> {
+        self.drain().collect()
+    }
+
+    fn heap_bytes(&self) -> usize {
+        memory::rtree(self)
+    }
+}
+
+/// Orders heap entries by ascending distance.
+pub struct Candidate<T> {
+    pub distance_2: f64,
+    pub item: T,
+}
+
+impl<T> PartialEq for Candidate<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Candidate<T> {}
+
+impl<T> PartialOrd for Candidate<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Candidate<T> {
+    // Reversed so `BinaryHeap` pops the closest first.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.distance_2.total_cmp(&self.distance_2)
+    }
+}
+
+/// Uniform grid of cells, each listing the segments whose bounding box
+/// overlaps it. Cheap to build and good for evenly dense data.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Grid {
+    segments: Vec<Segment>,
+    origin: [f64; 2],
+    cell_size: f64,
+    dims: [usize; 2],
+    /// `entries[cell_start[c]..cell_start[c + 1]]` lists the segments of cell `c`.
+    cell_start: Vec<u32>,
+    entries: Vec<u32>,
+}
+
+/// Cells per axis are capped so a few huge segments can't blow up memory.
+const GRID_MAX_DIM: usize = 4096;
+
+impl Grid {
+    fn cell_of(&self, point: [f64; 2]) -> [i64; 2] {
+        [0, 1].map(|axis| ((point[axis] - self.origin[axis]) / self.cell_size).floor() as i64)
+    }
+
+    fn cell(&self, cell: [i64; 2]) -> Option<&[u32]> {
+        let [x, y] = cell;
+        if x < 0 || y < 0 || x as usize >= self.dims[0] || y as usize >= self.dims[1] {
+            return None;
+        }
+        Some(self.cell_entries(x as usize * self.dims[1] + y as usize))
+    }
+
+    fn cell_entries(&self, cell: usize) -> &[u32] {
+        &self.entries[self.cell_start[cell] as usize..self.cell_start[cell + 1] as usize]
+    }
+
+    /// Clamped cell ranges covering `envelope`.
+    fn cell_range(
+        &self,
+        envelope: &AABB<[f64; 2]>,
+    ) -> Option<[std::ops::RangeInclusive<usize>; 2]> {
+        let lower = self.cell_of(envelope.lower());
+        let upper = self.cell_of(envelope.upper());
+        let mut ranges = [0..=0, 0..=0];
+        for axis in 0..2 {
+            let max = self.dims[axis] as i64 - 1;
+            if upper[axis] < 0 || lower[axis] > max {
+                return None;
+            }
+            ranges[axis] = lower[axis].max(0) as usize..=upper[axis].min(max) as usize;
+        }
+        Some(ranges)
+    }
+
+    /// Cells are `cell_size` degrees on a side, or sized for a couple of
+    /// segments each if it is `None`. Either way they grow if the extent
+    /// would need more than [`GRID_MAX_DIM`] of them per axis.
+    pub fn with_cell_size(segments: Vec<Segment>, cell_size: Option<f64>) -> Grid {
+        let bounds = segments
+            .iter()
+            .map(|segment| segment.envelope())
+            .reduce(|a, b| a.merged(&b))
+            .unwrap_or_else(|| AABB::from_point([0.0, 0.0]));
+        let (lower, upper) = (bounds.lower(), bounds.upper());
+        let requested = cell_size;
+        let mut cell_size = cell_size.unwrap_or_else(|| {
+            let area = ((upper[0] - lower[0]) * (upper[1] - lower[1])).max(1e-12);
+            // Aim for a couple of segments per cell.
+            (2.0 * area / segments.len().max(1) as f64).sqrt().max(1e-6)
+        });
+        for axis in 0..2 {
+            cell_size = cell_size.max((upper[axis] - lower[axis]) / GRID_MAX_DIM as f64);
+        }
+        if let Some(requested) = requested.filter(|&requested| requested < cell_size) {
+            tracing::warn!(
+                "grid cells of {}° would be more than {} per axis => using {}°",
+                requested,
+                GRID_MAX_DIM,
+                cell_size
+            );
+        }
+        let dims = [0, 1].map(|axis| ((upper[axis] - lower[axis]) / cell_size) as usize + 1);
+
+        let mut grid = Grid {
+            segments: Vec::new(),
+            origin: lower,
+            cell_size,
+            dims,
+            cell_start: vec![0; dims[0] * dims[1] + 1],
+            entries: Vec::new(),
+        };
+        // Count the entries of each cell first, then fill them in place.
+        let cells_of = |grid: &Grid, segment: &Segment| {
+            let [xs, ys] = grid.cell_range(&segment.envelope())?;
+            Some(xs.flat_map(move |x| ys.clone().map(move |y| x * dims[1] + y)))
+        };
+        for segment in &segments {
+            for cell in cells_of(&grid, segment).into_iter().flatten() {
+                grid.cell_start[cell + 1] += 1;
+            }
+        }
+        for cell in 1..grid.cell_start.len() {
+            grid.cell_start[cell] += grid.cell_start[cell - 1];
+        }
+        let mut next = grid.cell_start.clone();
+        grid.entries = vec![0; *grid.cell_start.last().unwrap_or(&0) as usize];
+        for (i, segment) in segments.iter().enumerate() {
+            for cell in cells_of(&grid, segment)
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+            {
+                grid.entries[next[cell] as usize] = i as u32;
+                next[cell] += 1;
+            }
+        }
+        grid.segments = segments;
+        grid
+    }
+}
+
+impl SpatialIndex for Grid {
+    fn build(segments: Vec<Segment>) -> Self {
+        Grid::with_cell_size(segments, None)
+    }
+
+    fn nearest<'a>(&'a self, point: [f64; 2]) -> Box<dyn Iterator<Item = &'a Segment> + 'a> {
+        Box::new(GridNearest {
+            grid: self,
+            point,
+            center: self.cell_of(point),
+            ring: 0,
+            seen: HashSet::new(),
+            heap: BinaryHeap::new(),
+        })
+    }
+
+    fn in_envelope<'a>(
+        &'a self,
+        envelope: AABB<[f64; 2]>,
+    ) -> Box<dyn Iterator<Item = &'a Segment> + 'a> {
+        let Some([xs, ys]) = self.cell_range(&envelope) else {
+            return Box::new(std::iter::empty());
+        };
+        let mut seen = std::collections::HashSet::new();
+        let found: Vec<&Segment> = xs
+            .flat_map(|x| ys.clone().map(move |y| x * self.dims[1] + y))
+            .flat_map(|cell| self.cell_entries(cell))
+            .filter(|&&i| seen.insert(i))
+            .map(|&i| &self.segments[i as usize])
+            .filter(|segment| segment.envelope().intersects(&envelope))
+            .collect();
+        Box::new(found.into_iter())
+    }
+
+    fn into_segments(self) -> Vec<Segment> {
+        self.segments
+    }
+
+    fn heap_bytes(&self) -> usize {
+        memory::vec(&self.segments) + memory::vec(&self.cell_start) + memory::vec(&self.entries)
+    }
+}
+
+/// Visits the grid in growing square rings around the query cell. A
+/// candidate is yielded once it is closer than anything outside the rings
+/// visited so far could be.
+struct GridNearest<'a> {
+    grid: &'a Grid,
+    point: [f64; 2],
+    center: [i64; 2],
+    ring: i64,
+    /// Segments spanning several cells are met once per cell. A set sized
+    /// by the cells visited rather than a flag per segment, so a lookup
+    /// doesn't cost as much as the index is large.
+    seen: HashSet<u32>,
+    heap: BinaryHeap<Candidate<u32>>,
+}
+
+impl GridNearest<'_> {
+    /// Lower bound on the distance of segments not yet visited.
+    fn unvisited_bound_2(&self) -> f64 {
+        let grid = self.grid;
+        let mut bound = f64::INFINITY;
+        let mut covered = true;
+        for axis in 0..2 {
+            let low = self.center[axis] - self.ring + 1;
+            let high = self.center[axis] + self.ring;
+            // Once the visited square covers the whole grid nothing is left.
+            if low > 0 || high < grid.dims[axis] as i64 {
+                covered = false;
+            }
+            let low = grid.origin[axis] + low as f64 * grid.cell_size;
+            let high = grid.origin[axis] + high as f64 * grid.cell_size;
+            bound = bound.min((self.point[axis] - low).max(0.0));
+            bound = bound.min((high - self.point[axis]).max(0.0));
+        }
+        if covered {
+            f64::INFINITY
+        } else {
+            bound * bound
+        }
+    }
+
+    fn visit_cell(&mut self, cell: [i64; 2]) {
+        let Some(cell) = self.grid.cell(cell) else {
+            return;
+        };
+        for &i in cell {
+            if self.seen.insert(i) {
+                let distance_2 = self.grid.segments[i as usize].distance_2(&self.point);
+                self.heap.push(Candidate {
+                    distance_2,
+                    item: i,
+                });
+            }
+        }
+    }
+
+    fn visit_ring(&mut self) {
+        let [cx, cy] = self.center;
+        let r = self.ring;
+        // Only the part of the ring inside the grid is walked, so a query
+        // far outside it, like one shifted across the antimeridian, doesn't
+        // step through every empty cell on the way.
+        let [max_x, max_y] = [0, 1].map(|axis| self.grid.dims[axis] as i64 - 1);
+        if r == 0 {
+            self.visit_cell([cx, cy]);
+        } else {
+            for x in (cx - r).max(0)..=(cx + r).min(max_x) {
+                self.visit_cell([x, cy - r]);
+                self.visit_cell([x, cy + r]);
+            }
+            for y in (cy - r + 1).max(0)..=(cy + r - 1).min(max_y) {
+                self.visit_cell([cx - r, y]);
+                self.visit_cell([cx + r, y]);
+            }
+        }
+        self.ring += 1;
+    }
+
+    /// Skips the empty rings between a query far outside the grid and its edge.
+    fn skip_to_grid(&mut self) {
+        if self.ring > 0 {
+            return;
+        }
+        let mut gap = 0;
+        for axis in 0..2 {
+            let c = self.center[axis];
+            let max = self.grid.dims[axis] as i64 - 1;
+            gap = gap.max(-c).max(c - max);
+        }
+        self.ring = gap.max(0);
+    }
+}
+
+impl<'a> Iterator for GridNearest<'a> {
+    type Item = &'a Segment;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.skip_to_grid();
+        loop {
+            let bound = self.unvisited_bound_2();
+            if let Some(top) = self.heap.peek() {
+                if top.distance_2 <= bound {
+                    let i = self.heap.pop()?.item;
+                    return Some(&self.grid.segments[i as usize]);
+                }
+            } else if bound.is_infinite() {
+                return None;
+            }
+            self.visit_ring();
+        }
+    }
+}
+
+/// Segments sorted into a 2-d tree by the centers of their bounding boxes,
+/// each node keeping the bounding box of everything below it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KdTree {
+    segments: Vec<Segment>,
+    nodes: Vec<KdNode>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct KdNode {
+    envelope: AABB<[f64; 2]>,
+    /// Range of `segments` below this node.
+    start: u32,
+    end: u32,
+    /// Children, or `None` for a leaf.
+    children: Option<[u32; 2]>,
+}
+
+const KD_LEAF_SIZE: usize = 8;
+
+impl KdTree {
+    fn split(&mut self, start: usize, end: usize, axis: usize) -> u32 {
+        let envelope = self.segments[start..end]
+            .iter()
+            .map(|segment| segment.envelope())
+            .reduce(|a, b| a.merged(&b))
+            .unwrap_or_else(|| AABB::from_point([0.0, 0.0]));
+        let node = self.nodes.len() as u32;
+        self.nodes.push(KdNode {
+            envelope,
+            start: start as u32,
+            end: end as u32,
+            children: None,
+        });
+        if end - start > KD_LEAF_SIZE {
+            let mid = (start + end) / 2;
+            self.segments[start..end].select_nth_unstable_by(mid - start, |a, b| {
+                a.envelope().center()[axis].total_cmp(&b.envelope().center()[axis])
+            });
+            let left = self.split(start, mid, 1 - axis);
+            let right = self.split(mid, end, 1 - axis);
+            self.nodes[node as usize].children = Some([left, right]);
+        }
+        node
+    }
+}
+
+impl SpatialIndex for KdTree {
+    fn build(segments: Vec<Segment>) -> Self {
+        let mut tree = KdTree {
+            segments,
+            nodes: Vec::new(),
+        };
+        tree.split(0, tree.segments.len(), 0);
+        tree
+    }
+
+    fn nearest<'a>(&'a self, point: [f64; 2]) -> Box<dyn Iterator<Item = &'a Segment> + 'a> {
+        let mut heap = BinaryHeap::new();
+        if let Some(root) = self.nodes.first() {
+            heap.push(Candidate {
+                distance_2: root.envelope.distance_2(&point),
+                item: KdItem::Node(0),
+            });
+        }
+        Box::new(KdNearest {
+            tree: self,
+            point,
+            heap,
+        })
+    }
+
+    fn in_envelope<'a>(
+        &'a self,
+        envelope: AABB<[f64; 2]>,
+    ) -> Box<dyn Iterator<Item = &'a Segment> + 'a> {
+        let mut found = Vec::new();
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            let Some(node) = self.nodes.get(node as usize) else {
+                continue;
+            };
+            if !node.envelope.intersects(&envelope) {
+                continue;
+            }
+            match node.children {
+                Some(children) => stack.extend(children),
+                None => found.extend(
+                    self.segments[node.start as usize..node.end as usize]
+                        .iter()
+                        .filter(|segment| segment.envelope().intersects(&envelope)),
+                ),
+            }
+        }
+        Box::new(found.into_iter())
+    }
+
+    fn into_segments(self) -> Vec<Segment> {
+        self.segments
+    }
+
+    fn heap_bytes(&self) -> usize {
+        memory::vec(&self.segments) + memory::vec(&self.nodes)
+    }
+}
+
+enum KdItem {
+    Node(u32),
+    Segment(u32),
+}
+
+/// Best-first traversal: nodes are queued by the distance to their bounding
+/// box, which never exceeds that of any segment below them.
+struct KdNearest<'a> {
+    tree: &'a KdTree,
+    point: [f64; 2],
+    heap: BinaryHeap<Candidate<KdItem>>,
+}
+
+impl<'a> Iterator for KdNearest<'a> {
+    type Item = &'a Segment;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(Candidate { item, .. }) = self.heap.pop() {
+            match item {
+                KdItem::Segment(i) => return Some(&self.tree.segments[i as usize]),
+                KdItem::Node(node) => {
+                    let node = &self.tree.nodes[node as usize];
+                    match node.children {
+                        Some(children) => {
+                            for child in children {
+                                let distance_2 = self.tree.nodes[child as usize]
+                                    .envelope
+                                    .distance_2(&self.point);
+                                self.heap.push(Candidate {
+                                    distance_2,
+                                    item: KdItem::Node(child),
+                                });
+                            }
+                        }
+                        None => {
+                            for i in node.start..node.end {
+                                let distance_2 =
+                                    self.tree.segments[i as usize].distance_2(&self.point);
+                                self.heap.push(Candidate {
+                                    distance_2,
+                                    item: KdItem::Segment(i),
+                                });
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+}